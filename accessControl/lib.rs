@@ -0,0 +1,269 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+//! Role based access control and ownership shared by the dazhbog contracts.
+//!
+//! A contract embeds [`AccessControl`] in its storage and implements
+//! [`AccessControlled`] by forwarding to it, which emits [`RoleGranted`] /
+//! [`RoleRevoked`] when membership changes. Its owner is kept in an
+//! [`Ownership`], which is handed over in two steps.
+
+use ink::env::DefaultEnvironment;
+use ink::primitives::AccountId;
+use ink::storage::Mapping;
+
+pub type RoleType = u32;
+
+/// Grants and revokes every role and changes contract settings.
+pub const ADMIN: RoleType = ink::selector_id!("ADMIN");
+/// Pauses trading in an emergency.
+pub const GUARDIAN: RoleType = ink::selector_id!("GUARDIAN");
+/// Runs liquidations and fee settlement.
+pub const KEEPER: RoleType = ink::selector_id!("KEEPER");
+/// Pushes oracle prices.
+pub const FEEDER: RoleType = ink::selector_id!("FEEDER");
+/// Moves trader funds in the vault on behalf of positions.
+pub const MANAGER_CONTRACT: RoleType = ink::selector_id!("MANAGER_CONTRACT");
+
+pub type RoleResult = core::result::Result<(), RoleError>;
+
+#[derive(Debug, PartialEq, Eq)]
+#[ink::scale_derive(Encode, Decode, TypeInfo)]
+pub enum RoleError {
+    MissingRole,
+}
+
+/// Role messages every contract with an [`AccessControl`] exposes.
+#[ink::trait_definition]
+pub trait AccessControlled {
+    /// Gives `account` the `role`. Only an `ADMIN` can grant roles.
+    #[ink(message)]
+    fn grant_role(&mut self, role: RoleType, account: AccountId) -> RoleResult;
+
+    /// Takes `role` away from `account`. Only an `ADMIN` can revoke roles.
+    #[ink(message)]
+    fn revoke_role(&mut self, role: RoleType, account: AccountId) -> RoleResult;
+
+    /// Gives up a `role` held by the caller.
+    #[ink(message)]
+    fn renounce_role(&mut self, role: RoleType) -> RoleResult;
+
+    #[ink(message)]
+    fn has_role(&self, role: RoleType, account: AccountId) -> bool;
+}
+
+#[ink::event]
+pub struct RoleGranted {
+    #[ink(topic)]
+    pub role: RoleType,
+    #[ink(topic)]
+    pub account: AccountId,
+    pub sender: AccountId,
+}
+
+#[ink::event]
+pub struct RoleRevoked {
+    #[ink(topic)]
+    pub role: RoleType,
+    #[ink(topic)]
+    pub account: AccountId,
+    pub sender: AccountId,
+}
+
+#[ink::event]
+pub struct OwnershipTransferStarted {
+    #[ink(topic)]
+    pub previous_owner: Option<AccountId>,
+    #[ink(topic)]
+    pub new_owner: AccountId,
+}
+
+#[ink::event]
+pub struct OwnershipTransferred {
+    #[ink(topic)]
+    pub previous_owner: Option<AccountId>,
+    #[ink(topic)]
+    pub new_owner: Option<AccountId>,
+}
+
+#[ink::storage_item]
+#[derive(Debug, Default)]
+pub struct AccessControl {
+    members: Mapping<(RoleType, AccountId), ()>,
+}
+
+impl AccessControl {
+    /// Creates the role table with `admin` holding `ADMIN`.
+    pub fn new(admin: AccountId) -> Self {
+        let mut access_control = Self::default();
+        access_control.grant(ADMIN, admin);
+        access_control
+    }
+
+    pub fn has_role(&self, role: RoleType, account: AccountId) -> bool {
+        self.members.contains((role, account))
+    }
+
+    /// Adds `account` to `role`. Returns `false` if it already had the role.
+    pub fn grant(&mut self, role: RoleType, account: AccountId) -> bool {
+        if self.has_role(role, account) {
+            return false;
+        }
+
+        self.members.insert((role, account), &());
+        true
+    }
+
+    /// Removes `account` from `role`. Returns `false` if it did not have the role.
+    pub fn revoke(&mut self, role: RoleType, account: AccountId) -> bool {
+        if !self.has_role(role, account) {
+            return false;
+        }
+
+        self.members.remove((role, account));
+        true
+    }
+
+    /// Backs [`AccessControlled::grant_role`] for a message called by `caller`.
+    pub fn grant_role(
+        &mut self,
+        caller: AccountId,
+        role: RoleType,
+        account: AccountId,
+    ) -> RoleResult {
+        if !self.has_role(ADMIN, caller) {
+            return Err(RoleError::MissingRole);
+        }
+
+        if self.grant(role, account) {
+            ink::env::emit_event::<DefaultEnvironment, _>(RoleGranted {
+                role,
+                account,
+                sender: caller,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Backs [`AccessControlled::revoke_role`] for a message called by `caller`.
+    pub fn revoke_role(
+        &mut self,
+        caller: AccountId,
+        role: RoleType,
+        account: AccountId,
+    ) -> RoleResult {
+        if !self.has_role(ADMIN, caller) {
+            return Err(RoleError::MissingRole);
+        }
+
+        if self.revoke(role, account) {
+            ink::env::emit_event::<DefaultEnvironment, _>(RoleRevoked {
+                role,
+                account,
+                sender: caller,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Backs [`AccessControlled::renounce_role`] for a message called by `caller`.
+    pub fn renounce_role(&mut self, caller: AccountId, role: RoleType) -> RoleResult {
+        if !self.revoke(role, caller) {
+            return Err(RoleError::MissingRole);
+        }
+
+        ink::env::emit_event::<DefaultEnvironment, _>(RoleRevoked {
+            role,
+            account: caller,
+            sender: caller,
+        });
+
+        Ok(())
+    }
+}
+
+/// Owner of a contract. A new owner is only proposed by `start_transfer` and takes
+/// over once it calls `accept` itself, so a mistyped account can never receive
+/// ownership. A renounced contract has no owner.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[ink::scale_derive(Encode, Decode, TypeInfo)]
+#[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+pub struct Ownership {
+    owner: Option<AccountId>,
+    pending_owner: Option<AccountId>,
+}
+
+impl Ownership {
+    pub fn new(owner: AccountId) -> Self {
+        Self {
+            owner: Some(owner),
+            pending_owner: None,
+        }
+    }
+
+    pub fn owner(&self) -> Option<AccountId> {
+        self.owner
+    }
+
+    pub fn pending_owner(&self) -> Option<AccountId> {
+        self.pending_owner
+    }
+
+    pub fn is_owner(&self, account: AccountId) -> bool {
+        self.owner == Some(account)
+    }
+
+    /// Proposes `new_owner`, replacing any earlier proposal.
+    pub fn start_transfer(&mut self, new_owner: AccountId) {
+        self.pending_owner = Some(new_owner);
+    }
+
+    /// Makes `account` the owner if it is the pending owner. Returns `false` otherwise.
+    pub fn accept(&mut self, account: AccountId) -> bool {
+        if self.pending_owner != Some(account) {
+            return false;
+        }
+
+        self.owner = Some(account);
+        self.pending_owner = None;
+        true
+    }
+
+    /// Drops the owner and any pending transfer for good.
+    pub fn renounce(&mut self) {
+        self.owner = None;
+        self.pending_owner = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ownership_transfer_works() {
+        let alice = AccountId::from([0x1; 32]);
+        let bob = AccountId::from([0x2; 32]);
+        let charlie = AccountId::from([0x3; 32]);
+
+        let mut ownership = Ownership::new(alice);
+        assert!(ownership.is_owner(alice));
+
+        ownership.start_transfer(charlie);
+        ownership.start_transfer(bob);
+        assert_eq!(ownership.pending_owner(), Some(bob));
+        assert!(!ownership.accept(charlie));
+        assert!(ownership.is_owner(alice));
+
+        assert!(ownership.accept(bob));
+        assert_eq!(ownership.owner(), Some(bob));
+        assert_eq!(ownership.pending_owner(), None);
+        assert!(!ownership.accept(bob));
+
+        ownership.start_transfer(alice);
+        ownership.renounce();
+        assert_eq!(ownership.owner(), None);
+        assert!(!ownership.accept(alice));
+    }
+}
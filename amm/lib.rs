@@ -41,6 +41,7 @@ mod amm {
         WrongParameterValue,
         TooMuchSlippage,
         NotAdmin,
+        NotPendingOwner,
         NotEnoughLiquidityOf(AccountId),
         UnsupportedSwapPair(SwapPair),
     }
@@ -83,9 +84,26 @@ mod amm {
         amount_out: Balance,
     }
 
+    #[ink(event)]
+    pub struct OwnershipTransferStarted {
+        #[ink(topic)]
+        previous_owner: Option<AccountId>,
+        #[ink(topic)]
+        new_owner: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct OwnershipTransferred {
+        #[ink(topic)]
+        previous_owner: Option<AccountId>,
+        #[ink(topic)]
+        new_owner: Option<AccountId>,
+    }
+
     #[ink(storage)]
     pub struct AmmPool {
-        pub owner: AccountId,
+        pub owner: Option<AccountId>,
+        pub pending_owner: Option<AccountId>,
         pub swap_fee_percentage: u128,
         // a set of pairs that are availiable for swapping between
         pub swap_pairs: Mapping<SwapPair, ()>,
@@ -96,7 +114,8 @@ mod amm {
         pub fn new() -> Self {
             let caller = Self::env().caller();
             Self {
-                owner: caller,
+                owner: Some(caller),
+                pending_owner: None,
                 swap_fee_percentage: 0,
                 swap_pairs: Mapping::default(),
             }
@@ -275,6 +294,66 @@ mod amm {
             Ok(())
         }
 
+        /// Proposes `new_owner` as the next Admin
+        ///
+        /// Nothing changes until `new_owner` calls `accept_ownership`.
+        /// Can only be called by the contract's Admin.
+        #[ink(message)]
+        pub fn transfer_ownership(&mut self, new_owner: AccountId) -> Result<(), AmmError> {
+            self.check_owner(self.env().caller())?;
+
+            self.pending_owner = Some(new_owner);
+
+            self.env().emit_event(OwnershipTransferStarted {
+                previous_owner: self.owner,
+                new_owner,
+            });
+
+            Ok(())
+        }
+
+        /// Completes an ownership transfer started by `transfer_ownership`
+        ///
+        /// Can only be called by the pending owner.
+        #[ink(message)]
+        pub fn accept_ownership(&mut self) -> Result<(), AmmError> {
+            let caller = self.env().caller();
+
+            if self.pending_owner != Some(caller) {
+                return Err(AmmError::NotPendingOwner);
+            }
+
+            let previous_owner = self.owner;
+            self.owner = Some(caller);
+            self.pending_owner = None;
+
+            self.env().emit_event(OwnershipTransferred {
+                previous_owner,
+                new_owner: Some(caller),
+            });
+
+            Ok(())
+        }
+
+        /// Leaves the contract without an Admin, disabling every secured message for good
+        ///
+        /// Can only be called by the contract's Admin.
+        #[ink(message)]
+        pub fn renounce_ownership(&mut self) -> Result<(), AmmError> {
+            self.check_owner(self.env().caller())?;
+
+            let previous_owner = self.owner;
+            self.owner = None;
+            self.pending_owner = None;
+
+            self.env().emit_event(OwnershipTransferred {
+                previous_owner,
+                new_owner: None,
+            });
+
+            Ok(())
+        }
+
         /// Terminates the contract.
         ///
         /// Can only be called by the contract's Admin.
@@ -427,7 +506,7 @@ mod amm {
 
         /// Checks if the `account` is eligible to call the secured messages.
         fn check_owner(&self, account: AccountId) -> Result<(), AmmError> {
-            if account != self.owner {
+            if self.owner != Some(account) {
                 return Err(AmmError::NotAdmin);
             }
 
@@ -477,6 +556,31 @@ mod amm {
             assert_eq!(amount_in, expected_amount_in - dust);
         }
 
+        #[ink::test]
+        fn ownership_transfer_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut pool = AmmPool::new();
+            assert_eq!(pool.transfer_ownership(accounts.bob), Ok(()));
+            assert_eq!(pool.owner, Some(accounts.alice));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.charlie);
+            assert_eq!(pool.accept_ownership(), Err(AmmError::NotPendingOwner));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(pool.accept_ownership(), Ok(()));
+            assert_eq!(pool.owner, Some(accounts.bob));
+            assert_eq!(pool.pending_owner, None);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(pool.set_swap_fee_percentage(1), Err(AmmError::NotAdmin));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(pool.renounce_ownership(), Ok(()));
+            assert_eq!(pool.set_swap_fee_percentage(1), Err(AmmError::NotAdmin));
+        }
+
         proptest! {
             #[test]
             fn proptest_in_given_out(
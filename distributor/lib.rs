@@ -1,17 +1,235 @@
 #![cfg_attr(not(feature = "std"), no_std, no_main)]
 
+pub use self::distributor::{Distributor, DistributorRef};
+
+pub type Result<T> = core::result::Result<T, Error>;
+pub type EpochId = u32;
+
+#[derive(Debug, PartialEq, Eq)]
+#[ink::scale_derive(Encode, Decode, TypeInfo)]
+pub enum Error {
+    Overflow,
+    Underflow,
+    ZeroAmount,
+    InvalidParameter,
+    InsufficientBalance,
+    NothingToClaim,
+    VaultNotSet,
+    EpochNotEnded,
+    NotBeneficiary,
+    UnknownRewardToken,
+    NotOwner,
+    NotPendingOwner,
+    AlreadyMigrated,
+    MissingRole,
+    BeneficiariesLocked,
+}
+
 #[ink::contract]
 mod distributor {
     use super::*;
+    use access_control::{
+        AccessControl, AccessControlled, Ownership, OwnershipTransferStarted,
+        OwnershipTransferred, RoleGranted, RoleResult, RoleRevoked, RoleType, ADMIN,
+    };
     use ink::env::call::{build_call, ExecutionInput, Selector};
     use ink::env::DefaultEnvironment;
-    use ink::storage::Mapping;
+    use ink::prelude::vec::Vec;
+    use ink::storage::{Lazy, Mapping};
+
+    const STORAGE_VERSION: u32 = 1;
+    const BPS_DENOMINATOR: Balance = 10_000;
+    const DEFAULT_TEAM_SHARE_BPS: u32 = 2_000;
+    const ACC_PRECISION: Balance = 1_000_000_000_000;
+    const DEFAULT_EPOCH_DURATION: Timestamp = 7 * 24 * 60 * 60 * 1000;
+    const DEFAULT_VESTING_DURATION: Timestamp = 365 * 24 * 60 * 60 * 1000;
+    const TRANCHE_PERIOD: Timestamp = 24 * 60 * 60 * 1000;
+
+    /// How the LP part of collected fees reaches LPs.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub enum DistributionMode {
+        /// Fees are credited pro rata as soon as they are collected.
+        #[default]
+        Continuous,
+        /// Fees are pooled per epoch and split by the liquidity each LP kept
+        /// deposited for the whole epoch, so liquidity added during an epoch
+        /// only earns from the next one.
+        Epoch,
+    }
+
+    /// Linear vesting of the team's fee income. Every tranche of income vests on
+    /// its own: nothing before `cliff` has passed since the tranche started, after
+    /// that the vested part grows with the time since its start and reaches the
+    /// whole tranche after `duration`. No tranche starts before `start`.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct VestingSchedule {
+        pub start: Timestamp,
+        pub duration: Timestamp,
+        pub cliff: Timestamp,
+    }
+
+    /// Team income received within one `TRANCHE_PERIOD`. It starts vesting at the
+    /// end of that period, so income never vests for time before it arrived.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct Tranche {
+        pub start: Timestamp,
+        pub amount: Balance,
+    }
+
+    /// A closed epoch. `total_liquidity` only counts liquidity that stayed
+    /// deposited from the start of the epoch to its end.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct Epoch {
+        pub lp_fees: Balance,
+        pub total_liquidity: Balance,
+        pub end: Timestamp,
+    }
+
+    #[ink(event)]
+    pub struct Deposited {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        amount: Balance,
+    }
+
+    #[ink(event)]
+    pub struct Withdrawn {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        amount: Balance,
+    }
+
+    #[ink(event)]
+    pub struct FeesCollected {
+        #[ink(topic)]
+        token: AccountId,
+        amount: Balance,
+        team_amount: Balance,
+    }
+
+    #[ink(event)]
+    pub struct Claimed {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        #[ink(topic)]
+        token: AccountId,
+        amount: Balance,
+    }
+
+    #[ink(event)]
+    pub struct RewardTokenAdded {
+        #[ink(topic)]
+        token: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct EpochClosed {
+        #[ink(topic)]
+        epoch: EpochId,
+        lp_fees: Balance,
+        total_liquidity: Balance,
+    }
+
+    #[ink(event)]
+    pub struct EpochsClaimed {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        first: EpochId,
+        last: EpochId,
+        amount: Balance,
+    }
+
+    #[ink(event)]
+    pub struct Released {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        #[ink(topic)]
+        token: AccountId,
+        amount: Balance,
+    }
+
+    #[ink(event)]
+    pub struct VestingScheduleUpdated {
+        schedule: VestingSchedule,
+    }
+
+    #[ink(event)]
+    pub struct TeamShareUpdated {
+        team_share_bps: u32,
+    }
+
+    #[ink(event)]
+    pub struct Upgraded {
+        code_hash: Hash,
+    }
+
+    #[ink(event)]
+    pub struct Migrated {
+        version: u32,
+    }
 
     #[ink(storage)]
     pub struct Distributor {
         liquidity_providers: Mapping<AccountId, Balance>,
         team: AccountId,
         erc20: AccountId,
+        ownership: Ownership,
+        version: u32,
+        access_control: AccessControl,
+        vault: Lazy<AccountId>,
+        team_share_bps: Lazy<u32>,
+        total_liquidity: Lazy<Balance>,
+        /// Tokens fees are paid out in besides `erc20`, the token LPs deposit and
+        /// the vault pays fees in.
+        reward_tokens: Lazy<Vec<AccountId>>,
+        /// LP fees per unit of liquidity since deployment for each reward token,
+        /// scaled by `ACC_PRECISION`.
+        acc_reward_per_share: Mapping<AccountId, Balance>,
+        /// LP fees per reward token, scaled by `ACC_PRECISION`, that did not divide
+        /// evenly into `acc_reward_per_share`. They are added to the next
+        /// distribution.
+        reward_remainders: Mapping<AccountId, Balance>,
+        /// `liquidity * acc_reward_per_share` of an LP at its last settlement for
+        /// each reward token, rounded up, so it only earns from fees distributed
+        /// while it was deposited.
+        reward_debt: Mapping<(AccountId, AccountId), Balance>,
+        rewards: Mapping<(AccountId, AccountId), Balance>,
+        distribution_mode: Lazy<DistributionMode>,
+        epoch_duration: Lazy<Timestamp>,
+        current_epoch: Lazy<EpochId>,
+        epoch_start: Lazy<Timestamp>,
+        /// LP fees collected in the current epoch. Epochs only pool `erc20` fees,
+        /// other reward tokens are always distributed continuously.
+        epoch_fees: Lazy<Balance>,
+        epochs: Mapping<EpochId, Epoch>,
+        /// Liquidity of an LP after each epoch in which it changed, oldest first.
+        checkpoints: Mapping<AccountId, Vec<(EpochId, Balance)>>,
+        epoch_claims: Mapping<(AccountId, EpochId), ()>,
+        /// Lowest liquidity of an LP during an epoch in which it changed, counting
+        /// the liquidity it started the epoch with.
+        epoch_min_liquidity: Mapping<(AccountId, EpochId), Balance>,
+        /// Liquidity deposited since the start of the current epoch without a
+        /// break, summed over all LPs.
+        eligible_liquidity: Lazy<Balance>,
+        vesting: Lazy<VestingSchedule>,
+        /// All fees credited to the team so far per reward token, vested or not.
+        team_income: Mapping<AccountId, Balance>,
+        team_beneficiaries: Lazy<Vec<(AccountId, u32)>>,
+        team_released: Mapping<(AccountId, AccountId), Balance>,
+        /// Team income per reward token that is still vesting, oldest first.
+        team_tranches: Mapping<AccountId, Vec<Tranche>>,
+        /// Team income per reward token from tranches that have fully vested.
+        team_vested: Mapping<AccountId, Balance>,
+        /// Set by the first release, after which the beneficiaries are fixed.
+        team_release_started: Lazy<bool>,
     }
 
     impl Distributor {
@@ -20,128 +238,1579 @@ mod distributor {
             let liquidity_providers = Mapping::default();
             let team = team_address;
             let erc20 = erc20_contract_address;
-            Self { liquidity_providers, team, erc20 }
+            let owner = Self::env().caller();
+            let mut epoch_start = Lazy::new();
+            epoch_start.set(&Self::env().block_timestamp());
+            let mut vesting = Lazy::new();
+            vesting.set(&VestingSchedule {
+                start: Self::env().block_timestamp(),
+                duration: DEFAULT_VESTING_DURATION,
+                cliff: 0,
+            });
+            Self {
+                liquidity_providers,
+                team,
+                erc20,
+                ownership: Ownership::new(owner),
+                version: STORAGE_VERSION,
+                access_control: AccessControl::new(owner),
+                vault: Lazy::new(),
+                team_share_bps: Lazy::new(),
+                total_liquidity: Lazy::new(),
+                reward_tokens: Lazy::new(),
+                acc_reward_per_share: Mapping::default(),
+                reward_remainders: Mapping::default(),
+                reward_debt: Mapping::default(),
+                rewards: Mapping::default(),
+                distribution_mode: Lazy::new(),
+                epoch_duration: Lazy::new(),
+                current_epoch: Lazy::new(),
+                epoch_start,
+                epoch_fees: Lazy::new(),
+                epochs: Mapping::default(),
+                checkpoints: Mapping::default(),
+                epoch_claims: Mapping::default(),
+                epoch_min_liquidity: Mapping::default(),
+                eligible_liquidity: Lazy::new(),
+                vesting,
+                team_income: Mapping::default(),
+                team_beneficiaries: Lazy::new(),
+                team_released: Mapping::default(),
+                team_tranches: Mapping::default(),
+                team_vested: Mapping::default(),
+                team_release_started: Lazy::new(),
+            }
         }
 
+        /// Pulls the fees accrued in the vault and splits them: `team_share_bps`
+        /// goes to the team and the rest to LPs in proportion to their deposits.
+        /// Returns the amount collected.
         #[ink(message)]
-        pub fn withdraw_funds_from_vault(&mut self, vault: AccountId) {
-            let withdraw = build_call::<DefaultEnvironment>()
+        pub fn withdraw_funds_from_vault(&mut self) -> Result<Balance> {
+            let vault = self.vault.get().ok_or(Error::VaultNotSet)?;
+
+            let amount = build_call::<DefaultEnvironment>()
                 .call(vault)
                 .call_v1()
                 .gas_limit(0)
                 .exec_input(
                     ExecutionInput::new(Selector::new(ink::selector_bytes!("withdraw_distributor")))
                 )
+                .returns::<Result<Balance>>()
+                .invoke()
+                .unwrap_or_default();
+
+            if amount == 0 {
+                return Ok(0);
+            }
+
+            let team_amount = self.distribute(self.erc20, amount)?;
+
+            self.env().emit_event(FeesCollected {
+                token: self.erc20,
+                amount,
+                team_amount,
+            });
+
+            Ok(amount)
+        }
+
+        /// Distributes `amount` of reward `token` from the caller the same way as
+        /// fees pulled from the vault. The distributor has to be approved for
+        /// `amount` first.
+        #[ink(message)]
+        pub fn deposit_rewards(&mut self, token: AccountId, amount: Balance) -> Result<()> {
+            self.check_reward_token(token)?;
+
+            if amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            let caller = self.env().caller();
+            let team_amount = self.distribute(token, amount)?;
+
+            let deposit = build_call::<DefaultEnvironment>()
+                .call(token)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("transfer_from")))
+                        .push_arg(caller)
+                        .push_arg(self.env().account_id())
+                        .push_arg(amount),
+                )
+                .returns::<bool>()
+                .invoke();
+
+            self.env().emit_event(FeesCollected {
+                token,
+                amount,
+                team_amount,
+            });
+
+            Ok(())
+        }
+
+        /// Deposits `amount` of erc20 tokens as the caller's LP stake. The
+        /// distributor has to be approved for `amount` first.
+        #[ink(message)]
+        pub fn add_funds(&mut self, amount: Balance) -> Result<()> {
+            let caller = self.env().caller();
+            self.record_deposit(caller, amount)?;
+
+            let deposit = build_call::<DefaultEnvironment>()
+                .call(self.erc20)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("transfer_from")))
+                        .push_arg(caller)
+                        .push_arg(self.env().account_id())
+                        .push_arg(amount),
+                )
                 .returns::<bool>()
                 .invoke();
+
+            self.env().emit_event(Deposited {
+                from: Some(caller),
+                amount,
+            });
+
+            Ok(())
         }
 
+        /// Returns `amount` of the caller's LP stake. Rewards already credited stay
+        /// claimable.
         #[ink(message)]
-        pub fn add_funds(&mut self, amount: Balance) {
+        pub fn withdraw_funds(&mut self, amount: Balance) -> Result<()> {
             let caller = self.env().caller();
-            self.liquidity_providers.insert(caller, &amount);
+            self.record_withdrawal(caller, amount)?;
+
+            let withdraw = build_call::<DefaultEnvironment>()
+                .call(self.erc20)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("transfer")))
+                        .push_arg(caller)
+                        .push_arg(amount),
+                )
+                .returns::<bool>()
+                .invoke();
+
+            self.env().emit_event(Withdrawn {
+                from: Some(caller),
+                amount,
+            });
+
+            Ok(())
+        }
+
+        /// Pays out the fees credited to the caller as an LP in every reward token.
+        #[ink(message)]
+        pub fn claim(&mut self) -> Result<Vec<(AccountId, Balance)>> {
+            self.claim_tokens(self.get_reward_tokens())
+        }
+
+        /// Pays out the fees credited to the caller as an LP in the given reward
+        /// tokens only.
+        #[ink(message)]
+        pub fn claim_tokens(
+            &mut self,
+            tokens: Vec<AccountId>,
+        ) -> Result<Vec<(AccountId, Balance)>> {
+            let caller = self.env().caller();
+            let claimed = self.record_claim(caller, &tokens)?;
+
+            for (token, amount) in claimed.iter() {
+                self.transfer_token(*token, caller, *amount);
+
+                self.env().emit_event(Claimed {
+                    from: Some(caller),
+                    token: *token,
+                    amount: *amount,
+                });
+            }
+
+            Ok(claimed)
+        }
+
+        /// Fees credited to `account` and not claimed yet, per reward token.
+        #[ink(message)]
+        pub fn pending_rewards(&self, account: AccountId) -> Vec<(AccountId, Balance)> {
+            self.get_reward_tokens()
+                .into_iter()
+                .map(|token| (token, self.get_claimable(account, token)))
+                .collect()
+        }
+
+        /// Closes the current epoch once `epoch_duration` has passed since it
+        /// started, snapshotting its LP fees and the liquidity that stayed for the
+        /// whole epoch. Anyone can call this.
+        #[ink(message)]
+        pub fn close_epoch(&mut self) -> Result<EpochId> {
+            let epoch = self.record_epoch_close()?;
+            let closed = self.epochs.get(epoch).ok_or(Error::Underflow)?;
+
+            self.env().emit_event(EpochClosed {
+                epoch,
+                lp_fees: closed.lp_fees,
+                total_liquidity: closed.total_liquidity,
+            });
+
+            Ok(epoch)
         }
 
+        /// Pays out the caller's share of the closed epochs from `first` to `last`
+        /// inclusive. Epochs already claimed are skipped.
         #[ink(message)]
-        pub fn withdraw_funds(&self) -> bool {
+        pub fn claim_epochs(&mut self, first: EpochId, last: EpochId) -> Result<Balance> {
             let caller = self.env().caller();
+            let amount = self.record_epoch_claim(caller, first, last)?;
+
+            let claim = build_call::<DefaultEnvironment>()
+                .call(self.erc20)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("transfer")))
+                        .push_arg(caller)
+                        .push_arg(amount),
+                )
+                .returns::<bool>()
+                .invoke();
 
-            // if (caller == team) {
-            //     let get_total_balance = 
-            //     let withdraw_amount = 
-            // } 
-            //check if it's team
-            //if it's liquidity provider
-            //error if not team or provider
-            true
+            self.env().emit_event(EpochsClaimed {
+                from: Some(caller),
+                first,
+                last,
+                amount,
+            });
+
+            Ok(amount)
+        }
+
+        /// Closed epochs in which `account` earned fees it has not claimed yet.
+        #[ink(message)]
+        pub fn get_unclaimed_epochs(&self, account: AccountId) -> Vec<EpochId> {
+            (0..self.get_current_epoch())
+                .filter(|epoch| !self.epoch_claims.contains((account, *epoch)))
+                .filter(|epoch| self.epoch_share(account, *epoch).unwrap_or_default() > 0)
+                .collect()
+        }
+
+        /// Share of `account` in the fees of a closed epoch.
+        #[ink(message)]
+        pub fn get_epoch_share(&self, account: AccountId, epoch: EpochId) -> Result<Balance> {
+            self.epoch_share(account, epoch)
+        }
+
+        #[ink(message)]
+        pub fn get_epoch(&self, epoch: EpochId) -> Option<Epoch> {
+            self.epochs.get(epoch)
+        }
+
+        #[ink(message)]
+        pub fn get_current_epoch(&self) -> EpochId {
+            self.current_epoch.get().unwrap_or_default()
+        }
+
+        #[ink(message)]
+        pub fn set_distribution_mode(&mut self, mode: DistributionMode) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+            self.distribution_mode.set(&mode);
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_distribution_mode(&self) -> DistributionMode {
+            self.distribution_mode.get().unwrap_or_default()
+        }
+
+        #[ink(message)]
+        pub fn set_epoch_duration(&mut self, duration: Timestamp) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            if duration == 0 {
+                return Err(Error::InvalidParameter);
+            }
+
+            self.epoch_duration.set(&duration);
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_epoch_duration(&self) -> Timestamp {
+            self.epoch_duration.get().unwrap_or(DEFAULT_EPOCH_DURATION)
+        }
+
+        #[ink(message)]
+        pub fn get_liquidity(&self, account: AccountId) -> Balance {
+            self.liquidity_providers.get(account).unwrap_or_default()
+        }
+
+        #[ink(message)]
+        pub fn get_total_liquidity(&self) -> Balance {
+            self.total_liquidity.get().unwrap_or_default()
+        }
+
+        /// Fees in `token` credited to `account` and not claimed yet.
+        #[ink(message)]
+        pub fn get_claimable(&self, account: AccountId, token: AccountId) -> Balance {
+            let rewards = self.rewards.get((account, token)).unwrap_or_default();
+            rewards.saturating_add(self.pending(account, token).unwrap_or_default())
+        }
+
+        #[ink(message)]
+        pub fn get_acc_reward_per_share(&self, token: AccountId) -> Balance {
+            self.acc_reward_per_share.get(token).unwrap_or_default()
+        }
+
+        /// Adds `token` to the tokens fees can be distributed in.
+        #[ink(message)]
+        pub fn add_reward_token(&mut self, token: AccountId) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            if self.is_reward_token(token) {
+                return Err(Error::InvalidParameter);
+            }
+
+            let mut reward_tokens = self.reward_tokens.get().unwrap_or_default();
+            reward_tokens.push(token);
+            self.reward_tokens.set(&reward_tokens);
+
+            self.env().emit_event(RewardTokenAdded { token });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_reward_tokens(&self) -> Vec<AccountId> {
+            let mut tokens = ink::prelude::vec![self.erc20];
+            tokens.extend(self.reward_tokens.get().unwrap_or_default());
+            tokens
+        }
+
+        #[ink(message)]
+        pub fn set_team_share_bps(&mut self, bps: u32) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            if bps as Balance > BPS_DENOMINATOR {
+                return Err(Error::InvalidParameter);
+            }
+
+            self.team_share_bps.set(&bps);
+
+            self.env().emit_event(TeamShareUpdated { team_share_bps: bps });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_team_share_bps(&self) -> u32 {
+            self.team_share_bps.get().unwrap_or(DEFAULT_TEAM_SHARE_BPS)
+        }
+
+        /// Pays out the part of the vested team income owed to the caller, a team
+        /// beneficiary, in every reward token.
+        #[ink(message)]
+        pub fn release(&mut self) -> Result<Vec<(AccountId, Balance)>> {
+            let caller = self.env().caller();
+            let released = self.record_release(caller)?;
+
+            for (token, amount) in released.iter() {
+                self.transfer_token(*token, caller, *amount);
+
+                self.env().emit_event(Released {
+                    from: Some(caller),
+                    token: *token,
+                    amount: *amount,
+                });
+            }
+
+            Ok(released)
+        }
+
+        /// Team income in `token` vested by time `at`, across all beneficiaries.
+        /// Tranches that had fully vested when the last income arrived count as
+        /// vested for any `at`.
+        #[ink(message)]
+        pub fn vested_amount(&self, token: AccountId, at: Timestamp) -> Result<Balance> {
+            let schedule = self.get_vesting_schedule();
+            let mut vested = self.team_vested.get(token).unwrap_or_default();
+
+            for tranche in self.get_team_tranches(token) {
+                vested = vested
+                    .checked_add(Self::vested_from_tranche(&schedule, &tranche, at)?)
+                    .ok_or(Error::Overflow)?;
+            }
+
+            Ok(vested)
+        }
+
+        /// Vested team income in `token` that `account` can release now.
+        #[ink(message)]
+        pub fn get_releasable(&self, account: AccountId, token: AccountId) -> Result<Balance> {
+            let beneficiaries = self.get_team_beneficiaries();
+            let weight = beneficiaries
+                .iter()
+                .find(|(beneficiary, _)| *beneficiary == account)
+                .map(|(_, weight)| *weight)
+                .ok_or(Error::NotBeneficiary)?;
+            let total_weight: Balance = beneficiaries.iter().map(|(_, weight)| *weight as Balance).sum();
+
+            let owed = self
+                .vested_amount(token, self.env().block_timestamp())?
+                .checked_mul(weight as Balance)
+                .ok_or(Error::Overflow)?
+                .checked_div(total_weight)
+                .ok_or(Error::Underflow)?;
+
+            Ok(owed.saturating_sub(self.team_released.get((account, token)).unwrap_or_default()))
+        }
+
+        #[ink(message)]
+        pub fn get_team_income(&self, token: AccountId) -> Balance {
+            self.team_income.get(token).unwrap_or_default()
+        }
+
+        /// Team income in `token` that is still vesting, oldest first.
+        #[ink(message)]
+        pub fn get_team_tranches(&self, token: AccountId) -> Vec<Tranche> {
+            self.team_tranches.get(token).unwrap_or_default()
+        }
+
+        #[ink(message)]
+        pub fn set_vesting_schedule(&mut self, schedule: VestingSchedule) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            if schedule.duration == 0 || schedule.cliff > schedule.duration {
+                return Err(Error::InvalidParameter);
+            }
+
+            self.vesting.set(&schedule);
+
+            self.env().emit_event(VestingScheduleUpdated { schedule });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_vesting_schedule(&self) -> VestingSchedule {
+            self.vesting.get().unwrap_or(VestingSchedule {
+                start: 0,
+                duration: DEFAULT_VESTING_DURATION,
+                cliff: 0,
+            })
+        }
+
+        /// Replaces the team beneficiaries and their weights. Only possible before
+        /// the first release, since releases are settled against the weights.
+        #[ink(message)]
+        pub fn set_team_beneficiaries(&mut self, beneficiaries: Vec<(AccountId, u32)>) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            if self.team_release_started.get().unwrap_or_default() {
+                return Err(Error::BeneficiariesLocked);
+            }
+
+            if beneficiaries.is_empty() || beneficiaries.iter().any(|(_, weight)| *weight == 0) {
+                return Err(Error::InvalidParameter);
+            }
+
+            self.team_beneficiaries.set(&beneficiaries);
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_team_beneficiaries(&self) -> Vec<(AccountId, u32)> {
+            self.team_beneficiaries
+                .get()
+                .unwrap_or_else(|| ink::prelude::vec![(self.team, 1)])
+        }
+
+        #[ink(message)]
+        pub fn set_vault(&mut self, vault: AccountId) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+            self.vault.set(&vault);
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_vault(&self) -> Option<AccountId> {
+            self.vault.get()
+        }
+
+        /// Replaces the distributor code with `code_hash`, keeping storage.
+        #[ink(message)]
+        pub fn upgrade(&mut self, code_hash: Hash) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            self.env().set_code_hash(&code_hash).unwrap_or_else(|err| {
+                panic!("failed to set code hash {:?}: {:?}", code_hash, err)
+            });
+
+            self.env().emit_event(Upgraded { code_hash });
+
+            Ok(())
+        }
+
+        /// Migrates storage from an older code version after an `upgrade`.
+        #[ink(message)]
+        pub fn migrate(&mut self) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            if self.version >= STORAGE_VERSION {
+                return Err(Error::AlreadyMigrated);
+            }
+
+            self.version = STORAGE_VERSION;
+
+            self.env().emit_event(Migrated {
+                version: self.version,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_version(&self) -> u32 {
+            self.version
+        }
+
+        /// Proposes `new_owner` as the next owner. Nothing changes until
+        /// `new_owner` calls `accept_ownership`.
+        #[ink(message)]
+        pub fn transfer_ownership(&mut self, new_owner: AccountId) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            self.ownership.start_transfer(new_owner);
+
+            self.env().emit_event(OwnershipTransferStarted {
+                previous_owner: self.ownership.owner(),
+                new_owner,
+            });
+
+            Ok(())
+        }
+
+        /// Completes a transfer started by `transfer_ownership`. `ADMIN` moves from
+        /// the previous owner to the caller along with ownership.
+        #[ink(message)]
+        pub fn accept_ownership(&mut self) -> Result<()> {
+            let caller = self.env().caller();
+            let previous_owner = self.ownership.owner();
+
+            if !self.ownership.accept(caller) {
+                return Err(Error::NotPendingOwner);
+            }
+
+            if let Some(previous_owner) = previous_owner {
+                if self.access_control.revoke(ADMIN, previous_owner) {
+                    self.env().emit_event(RoleRevoked {
+                        role: ADMIN,
+                        account: previous_owner,
+                        sender: caller,
+                    });
+                }
+            }
+
+            if self.access_control.grant(ADMIN, caller) {
+                self.env().emit_event(RoleGranted {
+                    role: ADMIN,
+                    account: caller,
+                    sender: caller,
+                });
+            }
+
+            self.env().emit_event(OwnershipTransferred {
+                previous_owner,
+                new_owner: Some(caller),
+            });
+
+            Ok(())
+        }
+
+        /// Leaves the contract without an owner, which disables `upgrade` and
+        /// `migrate` for good. The owner's `ADMIN` goes with it, other admins
+        /// keep theirs.
+        #[ink(message)]
+        pub fn renounce_ownership(&mut self) -> Result<()> {
+            let caller = self.env().caller();
+            self.check_owner(caller)?;
+
+            let previous_owner = self.ownership.owner();
+            self.ownership.renounce();
+
+            if self.access_control.revoke(ADMIN, caller) {
+                self.env().emit_event(RoleRevoked {
+                    role: ADMIN,
+                    account: caller,
+                    sender: caller,
+                });
+            }
+
+            self.env().emit_event(OwnershipTransferred {
+                previous_owner,
+                new_owner: None,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn owner(&self) -> Option<AccountId> {
+            self.ownership.owner()
+        }
+
+        #[ink(message)]
+        pub fn pending_owner(&self) -> Option<AccountId> {
+            self.ownership.pending_owner()
+        }
+
+        fn check_owner(&self, account: AccountId) -> Result<()> {
+            if !self.ownership.is_owner(account) {
+                return Err(Error::NotOwner);
+            }
+            Ok(())
+        }
+
+        fn check_role(&self, role: RoleType, account: AccountId) -> Result<()> {
+            if !self.access_control.has_role(role, account) {
+                return Err(Error::MissingRole);
+            }
+            Ok(())
+        }
+
+        fn record_deposit(&mut self, account: AccountId, amount: Balance) -> Result<()> {
+            if amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            self.settle(account)?;
+
+            let liquidity = self
+                .liquidity_providers
+                .get(account)
+                .unwrap_or_default()
+                .checked_add(amount)
+                .ok_or(Error::Overflow)?;
+            self.set_liquidity(account, liquidity)?;
+            self.total_liquidity
+                .set(&self.get_total_liquidity().checked_add(amount).ok_or(Error::Overflow)?);
+
+            Ok(())
+        }
+
+        fn record_withdrawal(&mut self, account: AccountId, amount: Balance) -> Result<()> {
+            if amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            let liquidity = self.liquidity_providers.get(account).unwrap_or_default();
+
+            if liquidity < amount {
+                return Err(Error::InsufficientBalance);
+            }
+
+            self.settle(account)?;
+
+            self.set_liquidity(account, liquidity.checked_sub(amount).ok_or(Error::Underflow)?)?;
+            self.total_liquidity
+                .set(&self.get_total_liquidity().checked_sub(amount).ok_or(Error::Underflow)?);
+
+            Ok(())
+        }
+
+        /// Credits `amount` of `token` fees to the team and the LPs and returns the
+        /// team part. Rounding dust and the LP part collected while nobody is
+        /// deposited go to the team.
+        fn distribute(&mut self, token: AccountId, amount: Balance) -> Result<Balance> {
+            let total_liquidity = self.get_total_liquidity();
+            let team_amount = amount
+                .checked_mul(self.get_team_share_bps() as Balance)
+                .ok_or(Error::Overflow)?
+                .checked_div(BPS_DENOMINATOR)
+                .ok_or(Error::Underflow)?;
+            let lp_amount = amount.checked_sub(team_amount).ok_or(Error::Underflow)?;
+
+            let epoch_mode = self.get_distribution_mode() == DistributionMode::Epoch;
+
+            let team_amount = if epoch_mode && token == self.erc20 {
+                let epoch_fees = self.epoch_fees.get().unwrap_or_default();
+                self.epoch_fees.set(&epoch_fees.checked_add(lp_amount).ok_or(Error::Overflow)?);
+                team_amount
+            } else if total_liquidity > 0 {
+                // what does not divide evenly is kept for the next distribution,
+                // it is owed to LPs and must not be paid to the team as well
+                let scaled = lp_amount
+                    .checked_mul(ACC_PRECISION)
+                    .ok_or(Error::Overflow)?
+                    .checked_add(self.reward_remainders.get(token).unwrap_or_default())
+                    .ok_or(Error::Overflow)?;
+                let increase = scaled.checked_div(total_liquidity).ok_or(Error::Underflow)?;
+                let remainder = scaled.checked_rem(total_liquidity).ok_or(Error::Underflow)?;
+                let acc_reward_per_share = self
+                    .get_acc_reward_per_share(token)
+                    .checked_add(increase)
+                    .ok_or(Error::Overflow)?;
+                self.acc_reward_per_share.insert(token, &acc_reward_per_share);
+                self.reward_remainders.insert(token, &remainder);
+                team_amount
+            } else {
+                // nobody to pay, the LP part goes to the team
+                amount
+            };
+
+            self.add_team_income(token, team_amount)?;
+
+            Ok(team_amount)
+        }
+
+        fn add_team_income(&mut self, token: AccountId, amount: Balance) -> Result<()> {
+            let team_income = self
+                .get_team_income(token)
+                .checked_add(amount)
+                .ok_or(Error::Overflow)?;
+            self.team_income.insert(token, &team_income);
+
+            if amount == 0 {
+                return Ok(());
+            }
+
+            let now = self.env().block_timestamp();
+            let schedule = self.get_vesting_schedule();
+            let mut vested = self.team_vested.get(token).unwrap_or_default();
+            let mut tranches = Vec::new();
+
+            // fully vested tranches are folded away to keep the list short
+            for tranche in self.get_team_tranches(token) {
+                if tranche.start.saturating_add(schedule.duration) <= now {
+                    vested = vested.checked_add(tranche.amount).ok_or(Error::Overflow)?;
+                } else {
+                    tranches.push(tranche);
+                }
+            }
+
+            let period_end = now
+                .checked_add(TRANCHE_PERIOD - 1)
+                .ok_or(Error::Overflow)?
+                .checked_div(TRANCHE_PERIOD)
+                .ok_or(Error::Underflow)?
+                .checked_mul(TRANCHE_PERIOD)
+                .ok_or(Error::Overflow)?;
+            let start = core::cmp::max(period_end, schedule.start);
+
+            match tranches.last_mut() {
+                Some(tranche) if tranche.start == start => {
+                    tranche.amount = tranche.amount.checked_add(amount).ok_or(Error::Overflow)?;
+                }
+                _ => tranches.push(Tranche { start, amount }),
+            }
+
+            self.team_vested.insert(token, &vested);
+            self.team_tranches.insert(token, &tranches);
+            Ok(())
+        }
+
+        fn vested_from_tranche(schedule: &VestingSchedule, tranche: &Tranche, at: Timestamp) -> Result<Balance> {
+            if at < tranche.start.checked_add(schedule.cliff).ok_or(Error::Overflow)? {
+                return Ok(0);
+            }
+
+            let elapsed = at.checked_sub(tranche.start).ok_or(Error::Underflow)?;
+
+            if elapsed >= schedule.duration {
+                return Ok(tranche.amount);
+            }
+
+            tranche
+                .amount
+                .checked_mul(elapsed as Balance)
+                .ok_or(Error::Overflow)?
+                .checked_div(schedule.duration as Balance)
+                .ok_or(Error::Underflow)
+        }
+
+        fn accumulated(&self, liquidity: Balance, token: AccountId) -> Result<Balance> {
+            liquidity
+                .checked_mul(self.get_acc_reward_per_share(token))
+                .ok_or(Error::Overflow)?
+                .checked_div(ACC_PRECISION)
+                .ok_or(Error::Underflow)
+        }
+
+        /// Like `accumulated` but rounded up. Used for the debt, so the rounding of
+        /// every LP's fees is in favour of the contract.
+        fn accumulated_debt(&self, liquidity: Balance, token: AccountId) -> Result<Balance> {
+            liquidity
+                .checked_mul(self.get_acc_reward_per_share(token))
+                .ok_or(Error::Overflow)?
+                .checked_add(ACC_PRECISION - 1)
+                .ok_or(Error::Overflow)?
+                .checked_div(ACC_PRECISION)
+                .ok_or(Error::Underflow)
+        }
+
+        /// LP fees in `token` earned by `account` since its last settlement.
+        fn pending(&self, account: AccountId, token: AccountId) -> Result<Balance> {
+            let liquidity = self.liquidity_providers.get(account).unwrap_or_default();
+            let debt = self.reward_debt.get((account, token)).unwrap_or_default();
+            // the rounded up debt can be ahead of the rounded down total by one
+            Ok(self.accumulated(liquidity, token)?.saturating_sub(debt))
+        }
+
+        /// Moves pending LP fees of `account` into its claimable rewards.
+        fn settle(&mut self, account: AccountId) -> Result<()> {
+            for token in self.get_reward_tokens() {
+                let pending = self.pending(account, token)?;
+
+                if pending > 0 {
+                    self.credit(account, token, pending)?;
+                    let debt = self.reward_debt.get((account, token)).unwrap_or_default();
+                    self.reward_debt
+                        .insert((account, token), &debt.checked_add(pending).ok_or(Error::Overflow)?);
+                }
+            }
+
+            Ok(())
+        }
+
+        fn set_liquidity(&mut self, account: AccountId, liquidity: Balance) -> Result<()> {
+            self.liquidity_providers.insert(account, &liquidity);
+            for token in self.get_reward_tokens() {
+                self.reward_debt.insert((account, token), &self.accumulated_debt(liquidity, token)?);
+            }
+            self.checkpoint(account, liquidity)
+        }
+
+        fn checkpoint(&mut self, account: AccountId, liquidity: Balance) -> Result<()> {
+            let current_epoch = self.get_current_epoch();
+
+            // a withdrawal lowers what the LP kept for the whole epoch, a deposit
+            // only counts from the next epoch on
+            let eligible = self.eligible_liquidity_at(account, current_epoch);
+            let min_liquidity = core::cmp::min(eligible, liquidity);
+            self.epoch_min_liquidity.insert((account, current_epoch), &min_liquidity);
+            let removed = eligible.checked_sub(min_liquidity).ok_or(Error::Underflow)?;
+            let eligible_liquidity = self.eligible_liquidity.get().unwrap_or_default();
+            self.eligible_liquidity
+                .set(&eligible_liquidity.checked_sub(removed).ok_or(Error::Underflow)?);
+
+            let mut checkpoints = self.checkpoints.get(account).unwrap_or_default();
+
+            match checkpoints.last_mut() {
+                Some((epoch, balance)) if *epoch == current_epoch => *balance = liquidity,
+                _ => checkpoints.push((current_epoch, liquidity)),
+            }
+
+            self.checkpoints.insert(account, &checkpoints);
+
+            Ok(())
+        }
+
+        /// Liquidity of `account` when `epoch` closed.
+        fn liquidity_at(&self, account: AccountId, epoch: EpochId) -> Balance {
+            let checkpoints = self.checkpoints.get(account).unwrap_or_default();
+            let index = checkpoints.partition_point(|(changed, _)| *changed <= epoch);
+
+            match index {
+                0 => 0,
+                _ => checkpoints[index - 1].1,
+            }
+        }
+
+        /// Liquidity `account` kept deposited for all of `epoch` so far. Without a
+        /// change during the epoch that is simply its liquidity.
+        fn eligible_liquidity_at(&self, account: AccountId, epoch: EpochId) -> Balance {
+            self.epoch_min_liquidity
+                .get((account, epoch))
+                .unwrap_or_else(|| self.liquidity_at(account, epoch))
+        }
+
+        fn epoch_share(&self, account: AccountId, epoch: EpochId) -> Result<Balance> {
+            let closed = self.epochs.get(epoch).ok_or(Error::EpochNotEnded)?;
+
+            if closed.total_liquidity == 0 {
+                return Ok(0);
+            }
+
+            closed
+                .lp_fees
+                .checked_mul(self.eligible_liquidity_at(account, epoch))
+                .ok_or(Error::Overflow)?
+                .checked_div(closed.total_liquidity)
+                .ok_or(Error::Underflow)
+        }
+
+        fn record_epoch_close(&mut self) -> Result<EpochId> {
+            let now = self.env().block_timestamp();
+            let end = self
+                .epoch_start
+                .get()
+                .unwrap_or_default()
+                .checked_add(self.get_epoch_duration())
+                .ok_or(Error::Overflow)?;
+
+            if now < end {
+                return Err(Error::EpochNotEnded);
+            }
+
+            let epoch = self.get_current_epoch();
+            let lp_fees = self.epoch_fees.get().unwrap_or_default();
+            let eligible_liquidity = self.eligible_liquidity.get().unwrap_or_default();
+
+            // nobody to pay, the fees go to the team like in continuous mode
+            if eligible_liquidity == 0 && lp_fees > 0 {
+                self.add_team_income(self.erc20, lp_fees)?;
+            }
+
+            self.epochs.insert(
+                epoch,
+                &Epoch {
+                    lp_fees,
+                    total_liquidity: eligible_liquidity,
+                    end: now,
+                },
+            );
+            self.epoch_fees.set(&0);
+            self.eligible_liquidity.set(&self.get_total_liquidity());
+            self.current_epoch.set(&epoch.checked_add(1).ok_or(Error::Overflow)?);
+            self.epoch_start.set(&now);
+
+            Ok(epoch)
+        }
+
+        fn record_release(&mut self, account: AccountId) -> Result<Vec<(AccountId, Balance)>> {
+            let mut released = Vec::new();
+
+            for token in self.get_reward_tokens() {
+                let amount = self.get_releasable(account, token)?;
+
+                if amount == 0 {
+                    continue;
+                }
+
+                let total = self.team_released.get((account, token)).unwrap_or_default();
+                self.team_released
+                    .insert((account, token), &total.checked_add(amount).ok_or(Error::Overflow)?);
+                released.push((token, amount));
+            }
+
+            if released.is_empty() {
+                return Err(Error::NothingToClaim);
+            }
+
+            self.team_release_started.set(&true);
+
+            Ok(released)
+        }
+
+        fn record_epoch_claim(&mut self, account: AccountId, first: EpochId, last: EpochId) -> Result<Balance> {
+            if first > last || last >= self.get_current_epoch() {
+                return Err(Error::InvalidParameter);
+            }
+
+            let mut amount: Balance = 0;
+
+            for epoch in first..=last {
+                if self.epoch_claims.contains((account, epoch)) {
+                    continue;
+                }
+
+                let share = self.epoch_share(account, epoch)?;
+
+                if share > 0 {
+                    self.epoch_claims.insert((account, epoch), &());
+                    amount = amount.checked_add(share).ok_or(Error::Overflow)?;
+                }
+            }
+
+            if amount == 0 {
+                return Err(Error::NothingToClaim);
+            }
+
+            Ok(amount)
+        }
+
+        fn credit(&mut self, account: AccountId, token: AccountId, amount: Balance) -> Result<()> {
+            let rewards = self.rewards.get((account, token)).unwrap_or_default();
+            self.rewards
+                .insert((account, token), &rewards.checked_add(amount).ok_or(Error::Overflow)?);
+            Ok(())
+        }
+
+        fn record_claim(
+            &mut self,
+            account: AccountId,
+            tokens: &[AccountId],
+        ) -> Result<Vec<(AccountId, Balance)>> {
+            for token in tokens {
+                self.check_reward_token(*token)?;
+            }
+
+            self.settle(account)?;
+
+            let mut claimed = Vec::new();
+
+            for token in tokens {
+                let amount = self.rewards.take((account, *token)).unwrap_or_default();
+
+                if amount > 0 {
+                    claimed.push((*token, amount));
+                }
+            }
+
+            if claimed.is_empty() {
+                return Err(Error::NothingToClaim);
+            }
+
+            Ok(claimed)
+        }
+
+        fn check_reward_token(&self, token: AccountId) -> Result<()> {
+            if !self.is_reward_token(token) {
+                return Err(Error::UnknownRewardToken);
+            }
+            Ok(())
+        }
+
+        fn is_reward_token(&self, token: AccountId) -> bool {
+            token == self.erc20 || self.reward_tokens.get().unwrap_or_default().contains(&token)
+        }
+
+        fn transfer_token(&self, token: AccountId, to: AccountId, amount: Balance) {
+            let transfer = build_call::<DefaultEnvironment>()
+                .call(token)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("transfer")))
+                        .push_arg(to)
+                        .push_arg(amount),
+                )
+                .returns::<bool>()
+                .invoke();
+        }
+    }
+
+    impl AccessControlled for Distributor {
+        #[ink(message)]
+        fn grant_role(&mut self, role: RoleType, account: AccountId) -> RoleResult {
+            self.access_control.grant_role(self.env().caller(), role, account)
+        }
+
+        #[ink(message)]
+        fn revoke_role(&mut self, role: RoleType, account: AccountId) -> RoleResult {
+            self.access_control.revoke_role(self.env().caller(), role, account)
+        }
+
+        #[ink(message)]
+        fn renounce_role(&mut self, role: RoleType) -> RoleResult {
+            self.access_control.renounce_role(self.env().caller(), role)
+        }
+
+        #[ink(message)]
+        fn has_role(&self, role: RoleType, account: AccountId) -> bool {
+            self.access_control.has_role(role, account)
         }
     }
 
+    // CROSS CONTRACT CALLS ARE NOT INCLUDED IN TESTS
     #[cfg(test)]
     mod tests {
-        /// Imports all the definitions from the outer scope so we can use them here.
         use super::*;
+        use proptest::prelude::*;
+
+        fn new_distributor() -> Distributor {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            Distributor::new(accounts.eve, erc20())
+        }
+
+        fn erc20() -> AccountId {
+            AccountId::from([0x1; 32])
+        }
 
-        /// We test if the default constructor does its job.
         #[ink::test]
-        fn default_works() {
-            let distributor = Distributor::default();
-            assert_eq!(distributor.get(), false);
+        fn create_distributor_works() {
+            let distributor = new_distributor();
+
+            assert_eq!(distributor.get_team_share_bps(), DEFAULT_TEAM_SHARE_BPS);
+            assert_eq!(distributor.get_total_liquidity(), 0);
+            assert_eq!(distributor.get_vault(), None);
         }
 
-        /// We test a simple use case of our contract.
         #[ink::test]
-        fn it_works() {
-            let mut distributor = Distributor::new(false);
-            assert_eq!(distributor.get(), false);
-            distributor.flip();
-            assert_eq!(distributor.get(), true);
+        fn deposit_and_withdrawal_work() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut distributor = new_distributor();
+
+            assert_eq!(distributor.record_deposit(accounts.bob, 100), Ok(()));
+            assert_eq!(distributor.record_deposit(accounts.bob, 50), Ok(()));
+            assert_eq!(distributor.record_deposit(accounts.charlie, 50), Ok(()));
+            assert_eq!(distributor.get_liquidity(accounts.bob), 150);
+            assert_eq!(distributor.get_total_liquidity(), 200);
+
+            assert_eq!(distributor.record_withdrawal(accounts.bob, 150), Ok(()));
+            assert_eq!(distributor.get_liquidity(accounts.bob), 0);
+            assert_eq!(distributor.get_total_liquidity(), 50);
         }
-    }
 
-    #[cfg(all(test, feature = "e2e-tests"))]
-    mod e2e_tests {
-        /// Imports all the definitions from the outer scope so we can use them here.
-        use super::*;
+        #[ink::test]
+        fn deposit_and_withdrawal_fail() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut distributor = new_distributor();
 
-        /// A helper function used for calling contract messages.
-        use ink_e2e::ContractsBackend;
+            assert_eq!(distributor.add_funds(0), Err(Error::ZeroAmount));
+            assert_eq!(distributor.withdraw_funds(0), Err(Error::ZeroAmount));
+
+            assert_eq!(distributor.record_deposit(accounts.alice, 100), Ok(()));
+            assert_eq!(distributor.withdraw_funds(101), Err(Error::InsufficientBalance));
+        }
 
-        /// The End-to-End test `Result` type.
-        type E2EResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+        #[ink::test]
+        fn distribute_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut distributor = new_distributor();
 
-        /// We test that we can upload and instantiate the contract using its default constructor.
-        #[ink_e2e::test]
-        async fn default_works(mut client: ink_e2e::Client<C, E>) -> E2EResult<()> {
-            // Given
-            let mut constructor = DistributerRef::default();
+            assert_eq!(distributor.record_deposit(accounts.bob, 300), Ok(()));
+            assert_eq!(distributor.record_deposit(accounts.charlie, 100), Ok(()));
 
-            // When
-            let contract = client
-                .instantiate("distributer", &ink_e2e::alice(), &mut constructor)
-                .submit()
-                .await
-                .expect("instantiate failed");
-            let call_builder = contract.call_builder::<Distributer>();
+            // 20% to the team, the rest 3:1
+            assert_eq!(distributor.distribute(erc20(), 1000), Ok(200));
+            assert_eq!(distributor.get_team_income(erc20()), 200);
+            assert_eq!(distributor.get_claimable(accounts.bob, erc20()), 600);
+            assert_eq!(distributor.get_claimable(accounts.charlie, erc20()), 200);
 
-            // Then
-            let get = call_builder.get();
-            let get_result = client.call(&ink_e2e::alice(), &get).dry_run().await?;
-            assert!(matches!(get_result.return_value(), false));
+            // shares are rounded down
+            assert_eq!(distributor.distribute(erc20(), 11), Ok(2));
+            assert_eq!(distributor.get_claimable(accounts.bob, erc20()), 606);
+            assert_eq!(distributor.get_claimable(accounts.charlie, erc20()), 202);
 
-            Ok(())
+            // the LP part of fees collected while nobody is deposited goes to the team
+            assert_eq!(distributor.record_withdrawal(accounts.bob, 300), Ok(()));
+            assert_eq!(distributor.record_withdrawal(accounts.charlie, 100), Ok(()));
+            assert_eq!(distributor.distribute(erc20(), 7), Ok(7));
+            assert_eq!(distributor.get_claimable(accounts.bob, erc20()), 606);
         }
 
-        /// We test that we can read and write a value from the on-chain contract.
-        #[ink_e2e::test]
-        async fn it_works(mut client: ink_e2e::Client<C, E>) -> E2EResult<()> {
-            // Given
-            let mut constructor = DistributerRef::new(false);
-            let contract = client
-                .instantiate("distributer", &ink_e2e::bob(), &mut constructor)
-                .submit()
-                .await
-                .expect("instantiate failed");
-            let mut call_builder = contract.call_builder::<Distributer>();
+        #[ink::test]
+        fn rewards_follow_liquidity_over_time() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut distributor = new_distributor();
+            distributor.team_share_bps.set(&0);
 
-            let get = call_builder.get();
-            let get_result = client.call(&ink_e2e::bob(), &get).dry_run().await?;
-            assert!(matches!(get_result.return_value(), false));
+            assert_eq!(distributor.record_deposit(accounts.bob, 100), Ok(()));
+            assert_eq!(distributor.distribute(erc20(), 100), Ok(0));
 
-            // When
-            let flip = call_builder.flip();
-            let _flip_result = client
-                .call(&ink_e2e::bob(), &flip)
-                .submit()
-                .await
-                .expect("flip failed");
+            // charlie gets nothing from fees distributed before the deposit
+            assert_eq!(distributor.record_deposit(accounts.charlie, 100), Ok(()));
+            assert_eq!(distributor.get_claimable(accounts.charlie, erc20()), 0);
+            assert_eq!(distributor.distribute(erc20(), 100), Ok(0));
+            assert_eq!(distributor.get_claimable(accounts.bob, erc20()), 150);
+            assert_eq!(distributor.get_claimable(accounts.charlie, erc20()), 50);
 
-            // Then
-            let get = call_builder.get();
-            let get_result = client.call(&ink_e2e::bob(), &get).dry_run().await?;
-            assert!(matches!(get_result.return_value(), true));
+            // what bob earned stays claimable after withdrawing
+            assert_eq!(distributor.record_withdrawal(accounts.bob, 100), Ok(()));
+            assert_eq!(distributor.distribute(erc20(), 100), Ok(0));
+            assert_eq!(distributor.get_claimable(accounts.bob, erc20()), 150);
+            assert_eq!(distributor.get_claimable(accounts.charlie, erc20()), 150);
 
-            Ok(())
+            assert_eq!(distributor.record_claim(accounts.bob, &[erc20()]), Ok(vec![(erc20(), 150)]));
+            assert_eq!(distributor.record_claim(accounts.charlie, &[erc20()]), Ok(vec![(erc20(), 150)]));
+            assert_eq!(distributor.get_claimable(accounts.charlie, erc20()), 0);
+        }
+
+        #[ink::test]
+        fn multiple_reward_tokens_work() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut distributor = new_distributor();
+            let usdc = AccountId::from([0x2; 32]);
+
+            assert_eq!(distributor.deposit_rewards(usdc, 100), Err(Error::UnknownRewardToken));
+            assert_eq!(distributor.add_reward_token(usdc), Ok(()));
+            assert_eq!(distributor.add_reward_token(usdc), Err(Error::InvalidParameter));
+            assert_eq!(distributor.get_reward_tokens(), vec![erc20(), usdc]);
+            assert_eq!(distributor.deposit_rewards(usdc, 0), Err(Error::ZeroAmount));
+
+            assert_eq!(distributor.record_deposit(accounts.bob, 300), Ok(()));
+            assert_eq!(distributor.record_deposit(accounts.charlie, 100), Ok(()));
+            assert_eq!(distributor.distribute(erc20(), 1000), Ok(200));
+            assert_eq!(distributor.distribute(usdc, 500), Ok(100));
+            assert_eq!(distributor.get_team_income(usdc), 100);
+            assert_eq!(
+                distributor.pending_rewards(accounts.bob),
+                vec![(erc20(), 600), (usdc, 300)]
+            );
+            assert_eq!(
+                distributor.pending_rewards(accounts.charlie),
+                vec![(erc20(), 200), (usdc, 100)]
+            );
+
+            // claiming a subset leaves the other tokens claimable
+            assert_eq!(distributor.record_claim(accounts.bob, &[usdc]), Ok(vec![(usdc, 300)]));
+            assert_eq!(
+                distributor.pending_rewards(accounts.bob),
+                vec![(erc20(), 600), (usdc, 0)]
+            );
+            assert_eq!(
+                distributor.record_claim(accounts.bob, &[erc20(), usdc]),
+                Ok(vec![(erc20(), 600)])
+            );
+            assert_eq!(
+                distributor.record_claim(accounts.charlie, &[AccountId::from([0x3; 32])]),
+                Err(Error::UnknownRewardToken)
+            );
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(
+                distributor.add_reward_token(AccountId::from([0x3; 32])),
+                Err(Error::MissingRole)
+            );
+        }
+
+        #[ink::test]
+        fn distribute_keeps_remainder_for_lps() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut distributor = new_distributor();
+            distributor.team_share_bps.set(&348);
+
+            assert_eq!(distributor.record_deposit(accounts.bob, 7), Ok(()));
+            assert_eq!(distributor.distribute(erc20(), 5148), Ok(179));
+            assert_eq!(distributor.distribute(erc20(), 36373), Ok(1265));
+
+            // 40_077 went to LPs, the part that does not divide by 7 waits for the
+            // next distribution instead of going to the team
+            assert_eq!(distributor.get_team_income(erc20()), 1444);
+            assert_eq!(distributor.record_claim(accounts.bob, &[erc20()]), Ok(vec![(erc20(), 40_076)]));
+        }
+
+        #[ink::test]
+        fn distribute_without_liquidity_works() {
+            let mut distributor = new_distributor();
+
+            assert_eq!(distributor.distribute(erc20(), 1000), Ok(1000));
+            assert_eq!(distributor.get_team_income(erc20()), 1000);
+        }
+
+        #[ink::test]
+        fn epoch_distribution_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut distributor = new_distributor();
+            distributor.team_share_bps.set(&0);
+
+            assert_eq!(distributor.set_distribution_mode(DistributionMode::Epoch), Ok(()));
+            assert_eq!(distributor.set_epoch_duration(1000), Ok(()));
+
+            assert_eq!(distributor.record_deposit(accounts.bob, 300), Ok(()));
+            assert_eq!(distributor.record_deposit(accounts.charlie, 100), Ok(()));
+
+            // liquidity added during an epoch only earns from the next one
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(1000);
+            assert_eq!(distributor.close_epoch(), Ok(0));
+            assert_eq!(
+                distributor.get_epoch(0),
+                Some(Epoch {
+                    lp_fees: 0,
+                    total_liquidity: 0,
+                    end: 1000,
+                })
+            );
+
+            assert_eq!(distributor.distribute(erc20(), 400), Ok(0));
+
+            // nothing is credited before the epoch closes
+            assert_eq!(distributor.get_claimable(accounts.bob, erc20()), 0);
+            assert_eq!(distributor.get_epoch_share(accounts.bob, 1), Err(Error::EpochNotEnded));
+            assert_eq!(distributor.close_epoch(), Err(Error::EpochNotEnded));
+
+            // a deposit right before the close does not dilute the epoch
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(1999);
+            assert_eq!(distributor.record_deposit(accounts.django, 1000), Ok(()));
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(2000);
+            assert_eq!(distributor.close_epoch(), Ok(1));
+            assert_eq!(
+                distributor.get_epoch(1),
+                Some(Epoch {
+                    lp_fees: 400,
+                    total_liquidity: 400,
+                    end: 2000,
+                })
+            );
+
+            assert_eq!(distributor.record_withdrawal(accounts.django, 1000), Ok(()));
+            assert_eq!(distributor.record_withdrawal(accounts.charlie, 100), Ok(()));
+            assert_eq!(distributor.record_deposit(accounts.bob, 100), Ok(()));
+            assert_eq!(distributor.distribute(erc20(), 200), Ok(0));
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(3000);
+            assert_eq!(distributor.close_epoch(), Ok(2));
+            assert_eq!(distributor.get_current_epoch(), 3);
+            assert_eq!(distributor.get_epoch(2).map(|epoch| epoch.total_liquidity), Some(300));
+
+            assert_eq!(distributor.get_epoch_share(accounts.bob, 0), Ok(0));
+            assert_eq!(distributor.get_epoch_share(accounts.bob, 1), Ok(300));
+            assert_eq!(distributor.get_epoch_share(accounts.charlie, 1), Ok(100));
+            assert_eq!(distributor.get_epoch_share(accounts.django, 1), Ok(0));
+            assert_eq!(distributor.get_epoch_share(accounts.bob, 2), Ok(200));
+            assert_eq!(distributor.get_epoch_share(accounts.charlie, 2), Ok(0));
+            assert_eq!(distributor.get_epoch_share(accounts.django, 2), Ok(0));
+            assert_eq!(distributor.get_unclaimed_epochs(accounts.bob), vec![1, 2]);
+            assert_eq!(distributor.get_unclaimed_epochs(accounts.charlie), vec![1]);
+            assert!(distributor.get_unclaimed_epochs(accounts.django).is_empty());
+
+            assert_eq!(distributor.record_epoch_claim(accounts.bob, 1, 1), Ok(300));
+            assert_eq!(distributor.get_unclaimed_epochs(accounts.bob), vec![2]);
+            // epochs already claimed are skipped
+            assert_eq!(distributor.record_epoch_claim(accounts.bob, 0, 2), Ok(200));
+            assert_eq!(distributor.record_epoch_claim(accounts.bob, 0, 2), Err(Error::NothingToClaim));
+            assert!(distributor.get_unclaimed_epochs(accounts.bob).is_empty());
+
+            assert_eq!(distributor.record_epoch_claim(accounts.charlie, 2, 2), Err(Error::NothingToClaim));
+            assert_eq!(distributor.record_epoch_claim(accounts.charlie, 0, 3), Err(Error::InvalidParameter));
+            assert_eq!(distributor.record_epoch_claim(accounts.charlie, 2, 1), Err(Error::InvalidParameter));
+            assert_eq!(distributor.record_epoch_claim(accounts.charlie, 0, 2), Ok(100));
+            assert_eq!(distributor.record_epoch_claim(accounts.django, 0, 2), Err(Error::NothingToClaim));
+        }
+
+        #[ink::test]
+        fn epoch_without_liquidity_pays_team() {
+            let mut distributor = new_distributor();
+
+            assert_eq!(distributor.set_distribution_mode(DistributionMode::Epoch), Ok(()));
+            assert_eq!(distributor.distribute(erc20(), 100), Ok(20));
+            assert_eq!(distributor.get_team_income(erc20()), 20);
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(DEFAULT_EPOCH_DURATION);
+            assert_eq!(distributor.close_epoch(), Ok(0));
+            assert_eq!(distributor.get_team_income(erc20()), 100);
+        }
+
+        #[ink::test]
+        fn vesting_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut distributor = new_distributor();
+            distributor.team_share_bps.set(&10_000);
+
+            let schedule = VestingSchedule {
+                start: TRANCHE_PERIOD,
+                duration: 4 * TRANCHE_PERIOD,
+                cliff: TRANCHE_PERIOD,
+            };
+            assert_eq!(distributor.set_vesting_schedule(schedule), Ok(()));
+            assert_eq!(
+                distributor.set_team_beneficiaries(vec![(accounts.eve, 3), (accounts.frank, 1)]),
+                Ok(())
+            );
+            assert_eq!(distributor.distribute(erc20(), 800), Ok(800));
+            assert_eq!(
+                distributor.get_team_tranches(erc20()),
+                vec![Tranche {
+                    start: TRANCHE_PERIOD,
+                    amount: 800,
+                }]
+            );
+
+            assert_eq!(distributor.vested_amount(erc20(), TRANCHE_PERIOD), Ok(0));
+            assert_eq!(distributor.vested_amount(erc20(), 2 * TRANCHE_PERIOD - 1), Ok(0));
+            assert_eq!(distributor.vested_amount(erc20(), 2 * TRANCHE_PERIOD), Ok(200));
+            assert_eq!(distributor.vested_amount(erc20(), 3 * TRANCHE_PERIOD), Ok(400));
+            assert_eq!(distributor.vested_amount(erc20(), 10 * TRANCHE_PERIOD), Ok(800));
+
+            // before the cliff nothing can be released
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(TRANCHE_PERIOD + 1);
+            assert_eq!(distributor.record_release(accounts.eve), Err(Error::NothingToClaim));
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(3 * TRANCHE_PERIOD);
+            assert_eq!(distributor.get_releasable(accounts.eve, erc20()), Ok(300));
+            assert_eq!(distributor.get_releasable(accounts.frank, erc20()), Ok(100));
+            assert_eq!(distributor.record_release(accounts.eve), Ok(vec![(erc20(), 300)]));
+            assert_eq!(distributor.get_releasable(accounts.eve, erc20()), Ok(0));
+
+            // the weights are fixed once anything was released
+            assert_eq!(
+                distributor.set_team_beneficiaries(vec![(accounts.eve, 1)]),
+                Err(Error::BeneficiariesLocked)
+            );
+
+            // income arriving later vests on its own schedule
+            assert_eq!(distributor.distribute(erc20(), 400), Ok(400));
+            assert_eq!(distributor.get_releasable(accounts.eve, erc20()), Ok(0));
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(3 * TRANCHE_PERIOD + 1);
+            assert_eq!(distributor.distribute(erc20(), 100), Ok(100));
+            assert_eq!(
+                distributor.get_team_tranches(erc20()),
+                vec![
+                    Tranche {
+                        start: TRANCHE_PERIOD,
+                        amount: 800,
+                    },
+                    Tranche {
+                        start: 3 * TRANCHE_PERIOD,
+                        amount: 400,
+                    },
+                    Tranche {
+                        start: 4 * TRANCHE_PERIOD,
+                        amount: 100,
+                    },
+                ]
+            );
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(5 * TRANCHE_PERIOD);
+            assert_eq!(distributor.vested_amount(erc20(), 5 * TRANCHE_PERIOD), Ok(1025));
+            assert_eq!(distributor.record_release(accounts.eve), Ok(vec![(erc20(), 468)]));
+
+            // fully vested tranches are folded away when more income arrives
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(8 * TRANCHE_PERIOD);
+            assert_eq!(distributor.distribute(erc20(), 200), Ok(200));
+            assert_eq!(
+                distributor.get_team_tranches(erc20()),
+                vec![Tranche {
+                    start: 8 * TRANCHE_PERIOD,
+                    amount: 200,
+                }]
+            );
+            assert_eq!(distributor.vested_amount(erc20(), 8 * TRANCHE_PERIOD), Ok(1300));
+
+            assert_eq!(distributor.record_release(accounts.eve), Ok(vec![(erc20(), 207)]));
+            assert_eq!(distributor.record_release(accounts.frank), Ok(vec![(erc20(), 325)]));
+            assert_eq!(distributor.record_release(accounts.frank), Err(Error::NothingToClaim));
+            assert_eq!(distributor.get_releasable(accounts.bob, erc20()), Err(Error::NotBeneficiary));
+        }
+
+        #[ink::test]
+        fn vesting_settings_fail() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut distributor = new_distributor();
+
+            let schedule = VestingSchedule {
+                start: 0,
+                duration: 1000,
+                cliff: 1001,
+            };
+            assert_eq!(distributor.set_vesting_schedule(schedule), Err(Error::InvalidParameter));
+            assert_eq!(distributor.set_team_beneficiaries(Vec::new()), Err(Error::InvalidParameter));
+            assert_eq!(
+                distributor.set_team_beneficiaries(vec![(accounts.eve, 0)]),
+                Err(Error::InvalidParameter)
+            );
+            assert_eq!(distributor.get_team_beneficiaries(), vec![(accounts.eve, 1)]);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(
+                distributor.set_team_beneficiaries(vec![(accounts.bob, 1)]),
+                Err(Error::MissingRole)
+            );
+            assert_eq!(distributor.release(), Err(Error::NotBeneficiary));
+        }
+
+        #[ink::test]
+        fn claim_fails() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut distributor = new_distributor();
+
+            assert_eq!(distributor.claim(), Err(Error::NothingToClaim));
+
+            assert_eq!(distributor.record_deposit(accounts.bob, 100), Ok(()));
+            assert_eq!(distributor.distribute(erc20(), 100), Ok(20));
+            assert_eq!(distributor.record_claim(accounts.bob, &[erc20()]), Ok(vec![(erc20(), 80)]));
+            assert_eq!(distributor.record_claim(accounts.bob, &[erc20()]), Err(Error::NothingToClaim));
+        }
+
+        #[ink::test]
+        fn withdraw_from_vault_fails() {
+            let mut distributor = new_distributor();
+            assert_eq!(distributor.withdraw_funds_from_vault(), Err(Error::VaultNotSet));
+        }
+
+        #[ink::test]
+        fn settings_only_admin() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut distributor = new_distributor();
+
+            assert_eq!(distributor.set_team_share_bps(10_001), Err(Error::InvalidParameter));
+            assert_eq!(distributor.set_team_share_bps(5_000), Ok(()));
+            assert_eq!(distributor.get_team_share_bps(), 5_000);
+            assert_eq!(distributor.set_vault(accounts.django), Ok(()));
+            assert_eq!(distributor.get_vault(), Some(accounts.django));
+            assert_eq!(distributor.set_epoch_duration(0), Err(Error::InvalidParameter));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(distributor.set_team_share_bps(0), Err(Error::MissingRole));
+            assert_eq!(distributor.set_vault(accounts.bob), Err(Error::MissingRole));
+            assert_eq!(
+                distributor.set_distribution_mode(DistributionMode::Epoch),
+                Err(Error::MissingRole)
+            );
+            assert_eq!(distributor.set_epoch_duration(1000), Err(Error::MissingRole));
+        }
+
+        #[derive(Debug, Clone)]
+        enum Action {
+            Deposit(usize, Balance),
+            Withdraw(usize, Balance),
+            Distribute(Balance),
+        }
+
+        fn action() -> impl Strategy<Value = Action> {
+            prop_oneof![
+                (0..3usize, 1..1_000_000 as Balance).prop_map(|(lp, amount)| Action::Deposit(lp, amount)),
+                (0..3usize, 1..1_000_000 as Balance).prop_map(|(lp, amount)| Action::Withdraw(lp, amount)),
+                (0..1_000_000 as Balance).prop_map(Action::Distribute),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn claims_never_exceed_distributed(
+                actions in proptest::collection::vec(action(), 1..50),
+                team_share_bps in 0..=10_000u32,
+            ) {
+                ink::env::test::run_test::<ink::env::DefaultEnvironment, _>(|accounts| {
+                    let mut distributor = new_distributor();
+                    distributor.team_share_bps.set(&team_share_bps);
+
+                    let lps = [accounts.bob, accounts.charlie, accounts.django];
+                    let mut distributed: Balance = 0;
+                    let mut lp_distributed: Balance = 0;
+
+                    for action in actions {
+                        match action {
+                            Action::Deposit(lp, amount) => {
+                                distributor.record_deposit(lps[lp], amount).unwrap();
+                            }
+                            Action::Withdraw(lp, amount) => {
+                                // withdrawing more than deposited is rejected and changes nothing
+                                let _ = distributor.record_withdrawal(lps[lp], amount);
+                            }
+                            Action::Distribute(amount) => {
+                                let team_amount = distributor.distribute(erc20(), amount).unwrap();
+                                distributed += amount;
+                                lp_distributed += amount - team_amount;
+                            }
+                        }
+                    }
+
+                    let mut lp_claimed: Balance = 0;
+                    for lp in lps {
+                        let claimed: Balance = distributor
+                            .record_claim(lp, &[erc20()])
+                            .unwrap_or_default()
+                            .iter()
+                            .map(|(_, amount)| amount)
+                            .sum();
+                        assert!(claimed <= lp_distributed);
+                        lp_claimed += claimed;
+                    }
+
+                    assert!(lp_claimed <= lp_distributed);
+                    assert!(lp_claimed + distributor.get_team_income(erc20()) <= distributed);
+                    Ok(())
+                })
+                .unwrap();
+            }
         }
     }
 }
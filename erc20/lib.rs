@@ -1,8 +1,13 @@
 #![cfg_attr(not(feature = "std"), no_std, no_main)]
 
+pub use self::erc20::{Erc20, Erc20Ref, Error};
+
 #[ink::contract]
 mod erc20 {
-    use ink::storage::Mapping;
+    use access_control::{Ownership, OwnershipTransferStarted, OwnershipTransferred};
+    use ink::storage::{Lazy, Mapping};
+
+    const STORAGE_VERSION: u32 = 1;
 
     #[ink(storage)]
     #[derive(Default)]
@@ -10,6 +15,11 @@ mod erc20 {
         total_supply: Balance,
         balances: Mapping<AccountId, Balance>,
         allowances: Mapping<(AccountId, AccountId), Balance>,
+        ownership: Ownership,
+        version: u32,
+        /// Contract allowed to set allowances for its own caller while it trades
+        /// for them.
+        router: Lazy<AccountId>,
     }
 
     #[ink(event)]
@@ -36,10 +46,31 @@ mod erc20 {
     pub enum Error {
         InsufficientBalance,
         InsufficientAllowance,
+        NotOwner,
+        NotPendingOwner,
+        AlreadyMigrated,
+        NotRouter,
     }
 
     pub type Result<T> = core::result::Result<T, Error>;
 
+    #[ink(event)]
+    pub struct RouterUpdated {
+        old: Option<AccountId>,
+        #[ink(topic)]
+        new: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct Upgraded {
+        code_hash: Hash,
+    }
+
+    #[ink(event)]
+    pub struct Migrated {
+        version: u32,
+    }
+
     impl Erc20 {
         #[ink(constructor)]
         pub fn new(total_supply: Balance) -> Self {
@@ -55,6 +86,9 @@ mod erc20 {
                 total_supply,
                 balances,
                 allowances: Default::default(),
+                ownership: Ownership::new(caller),
+                version: STORAGE_VERSION,
+                router: Lazy::new(),
             }
         }
 
@@ -121,6 +155,153 @@ mod erc20 {
             Ok(())
         }
 
+        /// Sets the allowance of `owner` for `spender` to `value` and returns the
+        /// previous one. Only the router can call this, for the trader whose call
+        /// it is forwarding, so that trading through it needs no approval: it lets
+        /// the vault pull the trader's funds for the one call and restores the
+        /// previous allowance afterwards.
+        #[ink(message)]
+        pub fn approve_from(&mut self, owner: AccountId, spender: AccountId, value: Balance) -> Result<Balance> {
+            if self.router.get() != Some(self.env().caller()) {
+                return Err(Error::NotRouter);
+            }
+
+            let previous = self.allowance_impl(&owner, &spender);
+            self.allowances.insert((&owner, &spender), &value);
+            self.env().emit_event(Approval {
+                owner,
+                spender,
+                value,
+            });
+            Ok(previous)
+        }
+
+        /// Sets the contract allowed to call `approve_from`. It must only ever
+        /// pass its own caller as `owner`.
+        #[ink(message)]
+        pub fn set_router(&mut self, router: AccountId) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            let old = self.router.get();
+            self.router.set(&router);
+
+            self.env().emit_event(RouterUpdated { old, new: router });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_router(&self) -> Option<AccountId> {
+            self.router.get()
+        }
+
+        /// Replaces the token code with `code_hash`. Balances and allowances are
+        /// kept as long as the new code leaves the plain fields alone, as they
+        /// are packed into one cell, and stores new fields in `Lazy` or
+        /// `Mapping`.
+        #[ink(message)]
+        pub fn upgrade(&mut self, code_hash: Hash) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            self.env().set_code_hash(&code_hash).unwrap_or_else(|err| {
+                panic!("failed to set code hash {:?}: {:?}", code_hash, err)
+            });
+
+            self.env().emit_event(Upgraded { code_hash });
+
+            Ok(())
+        }
+
+        /// Migrates storage from an older code version after an `upgrade`.
+        #[ink(message)]
+        pub fn migrate(&mut self) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            if self.version >= STORAGE_VERSION {
+                return Err(Error::AlreadyMigrated);
+            }
+
+            self.version = STORAGE_VERSION;
+
+            self.env().emit_event(Migrated {
+                version: self.version,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_version(&self) -> u32 {
+            self.version
+        }
+
+        /// Proposes `new_owner` as the next owner. Nothing changes until
+        /// `new_owner` calls `accept_ownership`.
+        #[ink(message)]
+        pub fn transfer_ownership(&mut self, new_owner: AccountId) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            self.ownership.start_transfer(new_owner);
+
+            self.env().emit_event(OwnershipTransferStarted {
+                previous_owner: self.ownership.owner(),
+                new_owner,
+            });
+
+            Ok(())
+        }
+
+        /// Completes a transfer started by `transfer_ownership`.
+        #[ink(message)]
+        pub fn accept_ownership(&mut self) -> Result<()> {
+            let caller = self.env().caller();
+            let previous_owner = self.ownership.owner();
+
+            if !self.ownership.accept(caller) {
+                return Err(Error::NotPendingOwner);
+            }
+
+            self.env().emit_event(OwnershipTransferred {
+                previous_owner,
+                new_owner: Some(caller),
+            });
+
+            Ok(())
+        }
+
+        /// Leaves the contract without an owner, which disables `upgrade` and
+        /// `migrate` for good.
+        #[ink(message)]
+        pub fn renounce_ownership(&mut self) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            let previous_owner = self.ownership.owner();
+            self.ownership.renounce();
+
+            self.env().emit_event(OwnershipTransferred {
+                previous_owner,
+                new_owner: None,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn owner(&self) -> Option<AccountId> {
+            self.ownership.owner()
+        }
+
+        #[ink(message)]
+        pub fn pending_owner(&self) -> Option<AccountId> {
+            self.ownership.pending_owner()
+        }
+
+        fn check_owner(&self, account: AccountId) -> Result<()> {
+            if !self.ownership.is_owner(account) {
+                return Err(Error::NotOwner);
+            }
+            Ok(())
+        }
+
         fn transfer_from_to(
             &mut self,
             from: &AccountId,
@@ -399,6 +580,102 @@ mod erc20 {
             )
         }
 
+        #[ink::test]
+        fn approve_from_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut erc20 = Erc20::new(100);
+            assert_eq!(erc20.approve(accounts.eve, 5), Ok(()));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(erc20.set_router(accounts.bob), Err(Error::NotOwner));
+            assert_eq!(
+                erc20.approve_from(accounts.alice, accounts.eve, 20),
+                Err(Error::NotRouter)
+            );
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(erc20.set_router(accounts.bob), Ok(()));
+            assert_eq!(erc20.get_router(), Some(accounts.bob));
+
+            // the router opens the allowance for one call and restores it afterwards
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(erc20.approve_from(accounts.alice, accounts.eve, 20), Ok(5));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.eve);
+            assert_eq!(erc20.transfer_from(accounts.alice, accounts.eve, 15), Ok(()));
+            assert_eq!(erc20.allowance(accounts.alice, accounts.eve), 5);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(erc20.approve_from(accounts.alice, accounts.eve, 5), Ok(5));
+            assert_eq!(erc20.balance_of(accounts.eve), 15);
+        }
+
+        #[ink::test]
+        fn upgrade_only_owner() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut erc20 = Erc20::new(100);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(erc20.upgrade(Hash::from([0x42; 32])), Err(Error::NotOwner));
+            assert_eq!(erc20.migrate(), Err(Error::NotOwner));
+        }
+
+        #[ink::test]
+        fn migrate_current_version_fails() {
+            let mut erc20 = Erc20::new(100);
+            assert_eq!(erc20.get_version(), STORAGE_VERSION);
+            assert_eq!(erc20.migrate(), Err(Error::AlreadyMigrated));
+        }
+
+        mod v2 {
+            use access_control::Ownership;
+            use ink::primitives::AccountId;
+            use ink::storage::{Lazy, Mapping};
+
+            type Balance = u128;
+
+            /// Token layout of a later version: the v1 fields in the same order
+            /// with a `Lazy` one appended.
+            #[ink::storage_item]
+            pub struct Erc20 {
+                pub total_supply: Balance,
+                pub balances: Mapping<AccountId, Balance>,
+                pub allowances: Mapping<(AccountId, AccountId), Balance>,
+                pub ownership: Ownership,
+                pub version: u32,
+                pub router: Lazy<AccountId>,
+                pub minter: Lazy<AccountId>,
+            }
+        }
+
+        #[ink::test]
+        fn storage_survives_upgrade() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let root_key = <Erc20 as ink::storage::traits::StorageKey>::KEY;
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut erc20 = Erc20::new(100);
+            assert_eq!(erc20.transfer(accounts.bob, 10), Ok(()));
+            assert_eq!(erc20.approve(accounts.charlie, 20), Ok(()));
+            ink::env::set_contract_storage(&root_key, &erc20);
+
+            let upgraded = ink::env::get_contract_storage::<_, v2::Erc20>(&root_key)
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(upgraded.total_supply, 100);
+            assert_eq!(upgraded.balances.get(accounts.alice), Some(90));
+            assert_eq!(upgraded.balances.get(accounts.bob), Some(10));
+            assert_eq!(upgraded.allowances.get((accounts.alice, accounts.charlie)), Some(20));
+            assert_eq!(upgraded.ownership.owner(), Some(accounts.alice));
+            assert_eq!(upgraded.version, STORAGE_VERSION);
+            assert_eq!(upgraded.minter.get(), None);
+        }
+
         fn encoded_into_hash<T>(entity: T) -> Hash
         where
             T: ink::scale::Encode,
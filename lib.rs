@@ -1,40 +1,444 @@
 #![cfg_attr(not(feature = "std"), no_std, no_main)]
 
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, PartialEq, Eq)]
+#[ink::scale_derive(Encode, Decode, TypeInfo)]
+pub enum Error {
+    NotOwner,
+    AlreadyDeployed,
+    NotDeployed,
+    InstantiationFailed,
+    SetupFailed,
+    Manager(manager::Error),
+    Erc20(erc20::Error),
+}
+
+/// Deploys and wires the whole system in one transaction. Contracts are created
+/// in dependency order (erc20, oracle, distributor, vault, manager, payment
+/// manager) next to a timelock, roles between them are granted and ownership of
+/// every contract is offered to the timelock. The factory owner, `ADMIN` of the
+/// timelock, queues `accept_ownership` on each through it, which also moves
+/// `ADMIN` of every contract to the timelock, so any later change of parameters
+/// or code waits for the timelock delay.
+///
+/// Once deployed it is also the entry point for traders: it is the manager's
+/// router and forwards every call with the trader as `user`. It is the erc20's
+/// router as well, so instead of approving the vault first, a trader's call lets
+/// the vault pull their funds for that call only.
 #[ink::contract]
 mod dazhbog {
+    use super::*;
+    use access_control::{AccessControlled, ADMIN, FEEDER, KEEPER, MANAGER_CONTRACT};
+    use distributor::DistributorRef;
+    use erc20::Erc20Ref;
+    use ink::env::call::FromAccountId;
+    use ink::prelude::vec::Vec;
+    use ink::ToAccountId;
+    use manager::{ManagerRef, PositionId, PositionType, TokenId};
+    use oracle::OracleRef;
+    use payment_manager::PaymentManagerRef;
+    use timelock::TimelockRef;
+    use vault::VaultRef;
+
+    /// Uploaded code of every contract in the system.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct CodeHashes {
+        pub erc20: Hash,
+        pub oracle: Hash,
+        pub distributor: Hash,
+        pub vault: Hash,
+        pub manager: Hash,
+        pub payment_manager: Hash,
+        pub timelock: Hash,
+    }
+
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct System {
+        pub erc20: AccountId,
+        pub oracle: AccountId,
+        pub distributor: AccountId,
+        pub vault: AccountId,
+        pub manager: AccountId,
+        pub payment_manager: AccountId,
+        pub timelock: AccountId,
+    }
+
+    /// A single trading action, as batched by `multicall`.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum RouterCall {
+        Open {
+            token: TokenId,
+            amount: Balance,
+            position_type: PositionType,
+            leverage: u32,
+        },
+        Close {
+            position_id: PositionId,
+        },
+        Modify {
+            position_id: PositionId,
+            amount: Balance,
+        },
+        AddMargin {
+            position_id: PositionId,
+            amount: Balance,
+        },
+    }
+
+    #[ink(event)]
+    pub struct SystemDeployed {
+        #[ink(topic)]
+        admin: AccountId,
+        erc20: AccountId,
+        oracle: AccountId,
+        distributor: AccountId,
+        vault: AccountId,
+        manager: AccountId,
+        payment_manager: AccountId,
+        timelock: AccountId,
+    }
 
-    /// Defines the storage of your contract.
-    /// Add new fields to the below struct in order
-    /// to add new static storage fields to your contract.
     #[ink(storage)]
     pub struct Dazhbog {
-        /// Stores a single `bool` value on the storage.
-        value: bool,
+        owner: AccountId,
+        system: Option<System>,
     }
 
     impl Dazhbog {
-        /// Constructor that initializes the `bool` value to the given `init_value`.
         #[ink(constructor)]
-        pub fn new(init_value: bool) -> Self {
-            Self { value: init_value }
-        }
-
-        #[ink(message)]
-        pub fn create_something_test(&mut self) {
-            //close user position in manager contract
-            // // transfer fees to vault
-            // let send_fee_to_vault = build_call::<DefaultEnvironment>()
-            //     .call(self.erc20)
-            //     .call_v1()
-            //     .gas_limit(0)
-            //     .exec_input(
-            //         ExecutionInput::new(Selector::new(ink::selector_bytes!("transfer")))
-            //             .push_arg(self.vault)
-            //             .push_arg(self.fee),
-            //     )
-            //     .returns::<bool>()
-            //     .invoke();
-            //update user balance
+        pub fn new() -> Self {
+            Self {
+                owner: Self::env().caller(),
+                system: None,
+            }
+        }
+
+        /// Instantiates every contract from `code_hashes` with `salt` and wires
+        /// them together. The whole erc20 supply goes to the owner, who also gets
+        /// `FEEDER` on the oracle, `KEEPER` on the payment manager and `ADMIN` on
+        /// the timelock, which delays calls by at least `min_delay`.
+        #[ink(message)]
+        pub fn deploy(
+            &mut self,
+            code_hashes: CodeHashes,
+            total_supply: Balance,
+            price: u32,
+            team: AccountId,
+            min_delay: Timestamp,
+            salt: [u8; 32],
+        ) -> Result<System> {
+            let admin = self.env().caller();
+
+            if admin != self.owner {
+                return Err(Error::NotOwner);
+            }
+
+            if self.system.is_some() {
+                return Err(Error::AlreadyDeployed);
+            }
+
+            let mut erc20 = Erc20Ref::new(total_supply)
+                .code_hash(code_hashes.erc20)
+                .endowment(0)
+                .salt_bytes(salt)
+                .try_instantiate()
+                .map_err(|_| Error::InstantiationFailed)?
+                .map_err(|_| Error::InstantiationFailed)?;
+
+            let mut oracle = OracleRef::new(price)
+                .code_hash(code_hashes.oracle)
+                .endowment(0)
+                .salt_bytes(salt)
+                .try_instantiate()
+                .map_err(|_| Error::InstantiationFailed)?
+                .map_err(|_| Error::InstantiationFailed)?;
+
+            let mut distributor = DistributorRef::new(team, erc20.to_account_id())
+                .code_hash(code_hashes.distributor)
+                .endowment(0)
+                .salt_bytes(salt)
+                .try_instantiate()
+                .map_err(|_| Error::InstantiationFailed)?
+                .map_err(|_| Error::InstantiationFailed)?;
+
+            let mut vault = VaultRef::new(erc20.to_account_id(), distributor.to_account_id())
+                .code_hash(code_hashes.vault)
+                .endowment(0)
+                .salt_bytes(salt)
+                .try_instantiate()
+                .map_err(|_| Error::InstantiationFailed)?
+                .map_err(|_| Error::InstantiationFailed)?;
+
+            let mut manager = ManagerRef::new(vault.to_account_id(), oracle.to_account_id())
+                .code_hash(code_hashes.manager)
+                .endowment(0)
+                .salt_bytes(salt)
+                .try_instantiate()
+                .map_err(|_| Error::InstantiationFailed)?
+                .map_err(|_| Error::InstantiationFailed)?;
+
+            let mut payment_manager =
+                PaymentManagerRef::new(manager.to_account_id(), oracle.to_account_id())
+                    .code_hash(code_hashes.payment_manager)
+                    .endowment(0)
+                    .salt_bytes(salt)
+                    .try_instantiate()
+                    .map_err(|_| Error::InstantiationFailed)?
+                    .map_err(|_| Error::InstantiationFailed)?;
+
+            let mut timelock = TimelockRef::new(min_delay)
+                .code_hash(code_hashes.timelock)
+                .endowment(0)
+                .salt_bytes(salt)
+                .try_instantiate()
+                .map_err(|_| Error::InstantiationFailed)?
+                .map_err(|_| Error::InstantiationFailed)?;
+
+            let system = System {
+                erc20: erc20.to_account_id(),
+                oracle: oracle.to_account_id(),
+                distributor: distributor.to_account_id(),
+                vault: vault.to_account_id(),
+                manager: manager.to_account_id(),
+                payment_manager: payment_manager.to_account_id(),
+                timelock: timelock.to_account_id(),
+            };
+
+            // contract to contract permissions
+            vault
+                .grant_role(MANAGER_CONTRACT, system.manager)
+                .map_err(|_| Error::SetupFailed)?;
+            manager
+                .grant_role(KEEPER, system.payment_manager)
+                .map_err(|_| Error::SetupFailed)?;
+            manager
+                .set_router(self.env().account_id())
+                .map_err(|_| Error::SetupFailed)?;
+            erc20
+                .set_router(self.env().account_id())
+                .map_err(|_| Error::SetupFailed)?;
+            distributor
+                .set_vault(system.vault)
+                .map_err(|_| Error::SetupFailed)?;
+
+            // operator permissions
+            oracle
+                .grant_role(FEEDER, admin)
+                .map_err(|_| Error::SetupFailed)?;
+            payment_manager
+                .grant_role(KEEPER, admin)
+                .map_err(|_| Error::SetupFailed)?;
+
+            erc20
+                .transfer(admin, total_supply)
+                .map_err(|_| Error::SetupFailed)?;
+
+            // the factory keeps ADMIN until the timelock accepts, when it moves over
+            erc20
+                .transfer_ownership(system.timelock)
+                .map_err(|_| Error::SetupFailed)?;
+            oracle
+                .transfer_ownership(system.timelock)
+                .map_err(|_| Error::SetupFailed)?;
+            distributor
+                .transfer_ownership(system.timelock)
+                .map_err(|_| Error::SetupFailed)?;
+            vault
+                .transfer_ownership(system.timelock)
+                .map_err(|_| Error::SetupFailed)?;
+            manager
+                .transfer_ownership(system.timelock)
+                .map_err(|_| Error::SetupFailed)?;
+            payment_manager
+                .transfer_ownership(system.timelock)
+                .map_err(|_| Error::SetupFailed)?;
+
+            timelock
+                .grant_role(ADMIN, admin)
+                .map_err(|_| Error::SetupFailed)?;
+            timelock
+                .renounce_role(ADMIN)
+                .map_err(|_| Error::SetupFailed)?;
+
+            self.system = Some(system);
+
+            self.env().emit_event(SystemDeployed {
+                admin,
+                erc20: system.erc20,
+                oracle: system.oracle,
+                distributor: system.distributor,
+                vault: system.vault,
+                manager: system.manager,
+                payment_manager: system.payment_manager,
+                timelock: system.timelock,
+            });
+
+            Ok(system)
+        }
+
+        #[ink(message)]
+        pub fn get_system(&self) -> Option<System> {
+            self.system
+        }
+
+        #[ink(message)]
+        pub fn owner(&self) -> AccountId {
+            self.owner
+        }
+
+        #[ink(message)]
+        pub fn open(
+            &mut self,
+            token: TokenId,
+            amount: Balance,
+            position_type: PositionType,
+            leverage: u32,
+        ) -> Result<()> {
+            self.route(RouterCall::Open {
+                token,
+                amount,
+                position_type,
+                leverage,
+            })
+        }
+
+        #[ink(message)]
+        pub fn close(&mut self, position_id: PositionId) -> Result<()> {
+            self.route(RouterCall::Close { position_id })
+        }
+
+        /// Changes the collateral of a position to `amount`.
+        #[ink(message)]
+        pub fn modify(&mut self, position_id: PositionId, amount: Balance) -> Result<()> {
+            self.route(RouterCall::Modify {
+                position_id,
+                amount,
+            })
+        }
+
+        #[ink(message)]
+        pub fn add_margin(&mut self, position_id: PositionId, amount: Balance) -> Result<()> {
+            self.route(RouterCall::AddMargin {
+                position_id,
+                amount,
+            })
+        }
+
+        /// Runs `calls` in order. If any of them fails the whole batch is reverted.
+        #[ink(message)]
+        pub fn multicall(&mut self, calls: Vec<RouterCall>) -> Result<()> {
+            for call in calls {
+                self.route(call)?;
+            }
+
+            Ok(())
+        }
+
+        fn route(&mut self, call: RouterCall) -> Result<()> {
+            let user = self.env().caller();
+            let system = self.system.ok_or(Error::NotDeployed)?;
+            let mut manager: ManagerRef = FromAccountId::from_account_id(system.manager);
+            let mut erc20: Erc20Ref = FromAccountId::from_account_id(system.erc20);
+
+            // calls that can take collateral let the vault pull it for this call only
+            let previous_allowance = match call {
+                RouterCall::Close { .. } => None,
+                _ => Some(
+                    erc20
+                        .approve_from(user, system.vault, Balance::MAX)
+                        .map_err(Error::Erc20)?,
+                ),
+            };
+
+            let result = match call {
+                RouterCall::Open {
+                    token,
+                    amount,
+                    position_type,
+                    leverage,
+                } => manager.open_position(token, amount, position_type, leverage, user),
+                RouterCall::Close { position_id } => manager.close_position(position_id, user),
+                RouterCall::Modify {
+                    position_id,
+                    amount,
+                } => manager.update_position(amount, position_id, user),
+                RouterCall::AddMargin {
+                    position_id,
+                    amount,
+                } => manager.add_margin(position_id, amount, user),
+            }
+            .map_err(Error::Manager);
+
+            if let Some(allowance) = previous_allowance {
+                erc20
+                    .approve_from(user, system.vault, allowance)
+                    .map_err(Error::Erc20)?;
+            }
+
+            result
+        }
+    }
+
+    // CROSS CONTRACT CALLS ARE NOT INCLUDED IN TESTS
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn code_hashes() -> CodeHashes {
+            CodeHashes {
+                erc20: Hash::from([1; 32]),
+                oracle: Hash::from([2; 32]),
+                distributor: Hash::from([3; 32]),
+                vault: Hash::from([4; 32]),
+                manager: Hash::from([5; 32]),
+                payment_manager: Hash::from([6; 32]),
+                timelock: Hash::from([7; 32]),
+            }
+        }
+
+        #[ink::test]
+        fn new_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let dazhbog = Dazhbog::new();
+
+            assert_eq!(dazhbog.owner(), accounts.alice);
+            assert_eq!(dazhbog.get_system(), None);
+        }
+
+        #[ink::test]
+        fn deploy_only_owner() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut dazhbog = Dazhbog::new();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(
+                dazhbog.deploy(code_hashes(), 1000, 100, accounts.charlie, 1000, [0; 32]),
+                Err(Error::NotOwner)
+            );
+            assert_eq!(dazhbog.get_system(), None);
+        }
+
+        #[ink::test]
+        fn route_before_deploy_fails() {
+            let mut dazhbog = Dazhbog::new();
+
+            assert_eq!(
+                dazhbog.open(1, 100, PositionType::LONG, 10),
+                Err(Error::NotDeployed)
+            );
+            assert_eq!(dazhbog.close(0), Err(Error::NotDeployed));
+            assert_eq!(
+                dazhbog.multicall(vec![RouterCall::AddMargin {
+                    position_id: 0,
+                    amount: 50,
+                }]),
+                Err(Error::NotDeployed)
+            );
         }
     }
 }
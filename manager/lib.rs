@@ -1,8 +1,11 @@
 #![cfg_attr(not(feature = "std"), no_std, no_main)]
 
+pub use self::manager::{Call, CallOutput, Manager, ManagerRef, PositionType};
+
 pub type TokenId = u128;
 pub type Result<T> = core::result::Result<T, Error>;
 pub type PositionId = u128;
+pub type ReferralCode = [u8; 32];
 
 #[derive(Debug, PartialEq, Eq)]
 #[ink::scale_derive(Encode, Decode, TypeInfo)]
@@ -12,14 +15,43 @@ pub enum Error {
     NotFound,
     NonZeroAmount,
     ZeroAmount,
+    NotOwner,
+    NotPendingOwner,
+    InvalidParameter,
+    InsufficientPoolLiquidity,
+    Paused,
+    CircuitBreakerTripped,
+    AlreadyMigrated,
+    MissingRole,
+    UpdateNotReady,
+    Unauthorized,
+    CodeTaken,
+    CodeNotFound,
 }
 
 #[ink::contract]
 mod manager {
     use super::*;
+    use access_control::{
+        AccessControl, AccessControlled, Ownership, OwnershipTransferStarted,
+        OwnershipTransferred, RoleGranted, RoleResult, RoleRevoked, RoleType,
+        ADMIN, GUARDIAN, KEEPER,
+    };
     use ink::env::call::{build_call, ExecutionInput, Selector};
     use ink::env::DefaultEnvironment;
-    use ink::storage::Mapping;
+    use ink::prelude::vec::Vec;
+    use ink::storage::{Lazy, Mapping};
+
+    const STORAGE_VERSION: u32 = 1;
+    const ADL_QUANTILES: u8 = 5;
+    const MAX_ADL_SCAN: u32 = 50;
+    const BPS_DENOMINATOR: Balance = 10_000;
+    const DEFAULT_MAX_UTILIZATION_BPS: u32 = 8_000;
+    const DEFAULT_RESERVE_FACTOR_BPS: u32 = 10_000;
+    const MILLIS_PER_HOUR: Balance = 60 * 60 * 1000;
+    const MILLIS_PER_DAY: Timestamp = 24 * 60 * 60 * 1000;
+    const VOLUME_WINDOW_DAYS: Timestamp = 30;
+    const MAX_FEE_TIERS: usize = 10;
 
     #[ink::scale_derive(Encode, Decode, TypeInfo)]
     #[derive(Debug, PartialEq, Eq)]
@@ -40,6 +72,146 @@ mod manager {
         leverage: u32,
         position_value: Balance,
         creation_time: u128,
+        reserved: Balance,
+        last_fee_time: u128,
+    }
+
+    /// Fees charged on a market, in basis points of the position's notional
+    /// (`amount * leverage`).
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct FeeSchedule {
+        pub open_fee_bps: u32,
+        pub close_fee_bps: u32,
+        pub maintenance_fee_bps_per_hour: u32,
+    }
+
+    /// Open and close fee reduction for traders with a rolling 30 day notional
+    /// volume of at least `min_volume`.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct FeeTier {
+        pub min_volume: Balance,
+        /// Subtracted from the market's fee rate, which doesn't go below zero.
+        pub discount_bps: u32,
+    }
+
+    /// What an operator may do with the positions of the account that approved
+    /// it. Closing and reducing always pay out to the account itself, so no
+    /// permission lets an operator move funds anywhere else.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct OperatorPermissions {
+        pub open: bool,
+        pub close: bool,
+        /// Changing the collateral of a position and adding margin.
+        pub modify: bool,
+    }
+
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct OperatorApproval {
+        pub permissions: OperatorPermissions,
+        /// The approval stops working at this time.
+        pub expiry: Timestamp,
+    }
+
+    /// Trading message being authorized by `check_user`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum TradeAction {
+        Open,
+        Close,
+        Modify,
+    }
+
+    impl OperatorPermissions {
+        fn allows(&self, action: TradeAction) -> bool {
+            match action {
+                TradeAction::Open => self.open,
+                TradeAction::Close => self.close,
+                TradeAction::Modify => self.modify,
+            }
+        }
+    }
+
+    /// Referral rates of a tier, in basis points of the open and close fees. A
+    /// trader using the code of a referrer in the tier pays `discount_bps` less
+    /// and the referrer earns `rebate_bps` of the fee.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct ReferralTier {
+        pub discount_bps: u32,
+        pub rebate_bps: u32,
+    }
+
+    /// Trading restriction of a market or of the whole manager, from least to
+    /// most strict. A market is held to the stricter of its own and the global state.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub enum PauseState {
+        #[default]
+        Active,
+        /// New positions and increases are rejected.
+        OpeningPaused,
+        /// Only closing, reducing, liquidating and fee settlement are allowed.
+        CloseOnly,
+        /// Everything is rejected.
+        Paused,
+    }
+
+    /// One of the trading messages, as batched by `multicall`. Every call acts on
+    /// the positions of the caller.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum Call {
+        OpenPosition {
+            token: TokenId,
+            amount: Balance,
+            position_type: PositionType,
+            leverage: u32,
+        },
+        UpdatePosition {
+            position_id: PositionId,
+            amount: Balance,
+        },
+        ClosePosition {
+            position_id: PositionId,
+        },
+        AddMargin {
+            position_id: PositionId,
+            amount: Balance,
+        },
+    }
+
+    /// Result of a successful `Call`.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum CallOutput {
+        PositionOpened(PositionId),
+        PositionUpdated,
+        PositionClosed,
+        MarginAdded,
+    }
+
+    #[ink(event)]
+    pub struct Paused {
+        #[ink(topic)]
+        token: Option<TokenId>,
+        state: PauseState,
+        #[ink(topic)]
+        by: Option<AccountId>,
+    }
+
+    #[ink(event)]
+    pub struct Unpaused {
+        #[ink(topic)]
+        token: Option<TokenId>,
     }
 
     #[ink(event)]
@@ -49,6 +221,7 @@ mod manager {
         position_id: PositionId,
         #[ink(topic)]
         amount: Balance,
+        fee: Balance,
     }
 
     #[ink(event)]
@@ -56,6 +229,83 @@ mod manager {
         #[ink(topic)]
         from: Option<AccountId>,
         position_id: PositionId,
+        fee: Balance,
+    }
+
+    #[ink(event)]
+    pub struct MaintenanceFeeCharged {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        position_id: PositionId,
+        fee: Balance,
+    }
+
+    #[ink(event)]
+    pub struct FeeScheduleUpdated {
+        #[ink(topic)]
+        token: Option<TokenId>,
+        schedule: FeeSchedule,
+    }
+
+    #[ink(event)]
+    pub struct OperatorApproved {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        #[ink(topic)]
+        operator: AccountId,
+        permissions: OperatorPermissions,
+        expiry: Timestamp,
+    }
+
+    #[ink(event)]
+    pub struct OperatorRevoked {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        #[ink(topic)]
+        operator: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct FeeTiersUpdated {
+        tiers: Vec<FeeTier>,
+    }
+
+    #[ink(event)]
+    pub struct ReferralCodeRegistered {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        #[ink(topic)]
+        code: ReferralCode,
+    }
+
+    #[ink(event)]
+    pub struct ReferralCodeSet {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        #[ink(topic)]
+        code: ReferralCode,
+    }
+
+    #[ink(event)]
+    pub struct ReferralTierUpdated {
+        tier: u8,
+        rates: ReferralTier,
+    }
+
+    #[ink(event)]
+    pub struct ReferrerTierUpdated {
+        #[ink(topic)]
+        referrer: AccountId,
+        tier: u8,
+    }
+
+    #[ink(event)]
+    pub struct ReferralRebate {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        #[ink(topic)]
+        referrer: AccountId,
+        rebate: Balance,
     }
 
     #[ink(event)]
@@ -65,12 +315,127 @@ mod manager {
         position_id: PositionId,
     }
 
+    #[ink(event)]
+    pub struct AutoDeleveraged {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        position_id: PositionId,
+        amount: Balance,
+        price: Balance,
+        deficit_covered: Balance,
+    }
+
+    #[ink(event)]
+    pub struct BadDebtRecorded {
+        amount: Balance,
+        bad_debt: Balance,
+    }
+
     #[ink(event)]
     pub struct PositionUpdated {
         #[ink(topic)]
         from: Option<AccountId>,
         position_id: PositionId,
         amount: Balance,
+        fee: Balance,
+    }
+
+    #[ink(event)]
+    pub struct MarginAdded {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        position_id: PositionId,
+        amount: Balance,
+        leverage: u32,
+    }
+
+    #[ink(event)]
+    pub struct Upgraded {
+        code_hash: Hash,
+    }
+
+    #[ink(event)]
+    pub struct Migrated {
+        version: u32,
+    }
+
+    /// Contract address this contract talks to that the admin can replace.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub enum AddressKind {
+        Oracle,
+        Vault,
+        Router,
+    }
+
+    /// Address change waiting for `address_update_delay` to pass.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct PendingAddressUpdate {
+        pub address: AccountId,
+        pub eta: Timestamp,
+    }
+
+    /// Change of `address_update_delay` waiting for the current delay to pass.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct PendingDelayUpdate {
+        pub delay: Timestamp,
+        pub eta: Timestamp,
+    }
+
+    #[ink(event)]
+    pub struct OracleUpdated {
+        old: AccountId,
+        #[ink(topic)]
+        new: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct VaultUpdated {
+        old: AccountId,
+        #[ink(topic)]
+        new: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct RouterUpdated {
+        old: Option<AccountId>,
+        #[ink(topic)]
+        new: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct AddressUpdateQueued {
+        kind: AddressKind,
+        #[ink(topic)]
+        address: AccountId,
+        eta: Timestamp,
+    }
+
+    #[ink(event)]
+    pub struct AddressUpdateCancelled {
+        kind: AddressKind,
+    }
+
+    #[ink(event)]
+    pub struct AddressUpdateDelayQueued {
+        delay: Timestamp,
+        eta: Timestamp,
+    }
+
+    #[ink(event)]
+    pub struct AddressUpdateDelayUpdated {
+        old: Timestamp,
+        new: Timestamp,
+    }
+
+    #[ink(event)]
+    pub struct AddressUpdateDelayCancelled {
+        delay: Timestamp,
     }
 
     #[ink(storage)]
@@ -81,6 +446,43 @@ mod manager {
         vault: AccountId,
         long_total: Balance,
         short_total: Balance,
+        ownership: Ownership,
+        max_utilization_bps: u32,
+        reserve_factor_bps: u32,
+        fee_schedules: Mapping<TokenId, FeeSchedule>,
+        default_fee_schedule: FeeSchedule,
+        /// Open positions of each side, stored densely by slot so a trade only
+        /// touches the slots it adds or removes.
+        open_positions: Mapping<(u8, u32), (AccountId, PositionId)>,
+        open_position_counts: Mapping<u8, u32>,
+        open_position_slots: Mapping<(AccountId, PositionId), u32>,
+        pause_state: PauseState,
+        market_pause_states: Mapping<TokenId, PauseState>,
+        max_price_move_bps: u32,
+        version: u32,
+        access_control: AccessControl,
+        address_update_delay: Lazy<Timestamp>,
+        pending_address_updates: Mapping<AddressKind, PendingAddressUpdate>,
+        referral_code_owners: Mapping<ReferralCode, AccountId>,
+        trader_referral_codes: Mapping<AccountId, ReferralCode>,
+        referral_tiers: Mapping<u8, ReferralTier>,
+        referrer_tiers: Mapping<AccountId, u8>,
+        /// Ascending by `min_volume`.
+        fee_tiers: Lazy<Vec<FeeTier>>,
+        /// Notional traded per day, as `(day, volume)` for the days of the
+        /// current window only.
+        trader_volumes: Mapping<AccountId, Vec<(Timestamp, Balance)>>,
+        /// Approvals given by an account, keyed by `(account, operator)`.
+        operators: Mapping<(AccountId, AccountId), OperatorApproval>,
+        /// Liquidation deficits neither the insurance fund nor auto-deleveraging
+        /// could cover.
+        bad_debt: Lazy<Balance>,
+        pending_address_update_delay: Lazy<Option<PendingDelayUpdate>>,
+        /// Contract that forwards trades with its own caller as `user`.
+        router: Lazy<AccountId>,
+        /// Slot of each side where the next auto-deleveraging scan starts, so that
+        /// consecutive liquidations go through all open positions in turn.
+        adl_cursors: Mapping<u8, u32>,
     }
 
     impl Manager {
@@ -92,6 +494,7 @@ mod manager {
             let vault = vault_address;
             let long_total = 0;
             let short_total = 0;
+            let owner = Self::env().caller();
             Self {
                 positions,
                 position_id,
@@ -99,6 +502,32 @@ mod manager {
                 vault,
                 long_total,
                 short_total,
+                ownership: Ownership::new(owner),
+                max_utilization_bps: DEFAULT_MAX_UTILIZATION_BPS,
+                reserve_factor_bps: DEFAULT_RESERVE_FACTOR_BPS,
+                fee_schedules: Mapping::default(),
+                default_fee_schedule: FeeSchedule::default(),
+                open_positions: Mapping::default(),
+                open_position_counts: Mapping::default(),
+                open_position_slots: Mapping::default(),
+                pause_state: PauseState::Active,
+                market_pause_states: Mapping::default(),
+                max_price_move_bps: 0,
+                version: STORAGE_VERSION,
+                access_control: AccessControl::new(owner),
+                address_update_delay: Lazy::new(),
+                pending_address_updates: Mapping::default(),
+                referral_code_owners: Mapping::default(),
+                trader_referral_codes: Mapping::default(),
+                referral_tiers: Mapping::default(),
+                referrer_tiers: Mapping::default(),
+                fee_tiers: Lazy::new(),
+                trader_volumes: Mapping::default(),
+                operators: Mapping::default(),
+                bad_debt: Lazy::new(),
+                pending_address_update_delay: Lazy::new(),
+                router: Lazy::new(),
+                adl_cursors: Mapping::default(),
             }
         }
 
@@ -111,14 +540,18 @@ mod manager {
             leverage: u32,
             user: AccountId,
         ) -> Result<()> {
+            self.check_user(user, TradeAction::Open)?;
+            self.check_market(token, PauseState::Active)?;
+
             let temp = self.positions.get(&(user, self.position_id));
 
             if temp.is_some() {
                 return Err(Error::NonZeroAmount);
             }
 
-            let entry_price = self.get_price();
-            let creation_time = self.env().block_timestamp().into();
+            self.check_circuit_breaker(token)?;
+            let entry_price = self.get_token_price(token);
+            let creation_time: u128 = self.env().block_timestamp().into();
             let position_id = self.position_id;
             self.position_id = self.position_id.checked_add(1).ok_or(Error::Overflow)?;
 
@@ -138,6 +571,9 @@ mod manager {
             }
 
             let position_value = amount.wrapping_mul(entry_price as Balance);
+            let reserved = self.reserve_amount(amount, leverage, &position_type)?;
+
+            self.reserve_pool_liquidity(reserved)?;
 
             let new_position: Position = Position {
                 state: true,
@@ -147,9 +583,17 @@ mod manager {
                 leverage,
                 position_value,
                 creation_time,
+                reserved,
+                last_fee_time: creation_time,
             };
 
             self.positions.insert((user, position_id), &new_position);
+            self.track_position(user, position_id, &new_position.position_type);
+
+            let fee_bps = self.tiered_fee_bps(user, self.get_fee_schedule(token).open_fee_bps);
+            let fee = self.calculate_fee(amount, leverage, fee_bps)?;
+            let (fee, rebate) = self.apply_referral(user, fee)?;
+            self.record_volume(user, amount.checked_mul(leverage as Balance).ok_or(Error::Overflow)?)?;
 
             let deposit = build_call::<DefaultEnvironment>()
                 .call(self.vault)
@@ -159,15 +603,19 @@ mod manager {
                     ExecutionInput::new(Selector::new(ink::selector_bytes!("add_liquidity")))
                         .push_arg(token)
                         .push_arg(amount)
+                        .push_arg(fee)
                         .push_arg(user),
                 )
                 .returns::<bool>()
                 .invoke();
 
+            self.pay_rebate(user, rebate);
+
             self.env().emit_event(PositionOpened {
                 from: Some(user),
                 position_id,
                 amount,
+                fee,
             });
 
             Ok(())
@@ -180,20 +628,32 @@ mod manager {
             position_id: PositionId,
             user: AccountId,
         ) -> Result<()> {
+            self.check_user(user, TradeAction::Modify)?;
+
             let temp = self.get_position(user, position_id);
             
             if temp.is_err() {
                 return Err(Error::NotFound)
             }
 
-            let current_price = self.get_price();
-            let position = temp.unwrap();
+            // the accrued fee is charged on the size it accrued on
+            self.charge_maintenance_fee(user, position_id)?;
+            let position = self.get_position(user, position_id)?;
             let amount = position.amount;
 
+            if updated_amount > amount {
+                self.check_market(position.token, PauseState::Active)?;
+            } else {
+                self.check_market(position.token, PauseState::CloseOnly)?;
+            }
+
             if amount == 0 {
                 return Err(Error::ZeroAmount)
             }
 
+            self.check_circuit_breaker(position.token)?;
+            let current_price = self.get_token_price(position.token);
+
             let mut new_amount = 0;
             let mut new_position_value: Balance = 0;
 
@@ -205,6 +665,14 @@ mod manager {
                 new_position_value = position.position_value.checked_sub(current_price.wrapping_mul(new_amount  as u32) as u128).ok_or(Error::Underflow)?;
             }
 
+            let reserved = self.reserve_amount(updated_amount, position.leverage, &position.position_type)?;
+
+            if reserved > position.reserved {
+                self.reserve_pool_liquidity(reserved.checked_sub(position.reserved).ok_or(Error::Underflow)?)?;
+            } else {
+                self.release_pool_liquidity(position.reserved.checked_sub(reserved).ok_or(Error::Underflow)?);
+            }
+
             let new_position: Position = Position {
                 state: true,
                 token: position.token,
@@ -213,10 +681,25 @@ mod manager {
                 leverage: position.leverage,
                 position_value: new_position_value,
                 creation_time: position.creation_time,
+                reserved,
+                last_fee_time: self.env().block_timestamp().into(),
             };
 
             self.positions.insert((user, position_id), &new_position);
 
+            let fee_schedule = self.get_fee_schedule(position.token);
+            let fee_bps = if updated_amount > amount {
+                fee_schedule.open_fee_bps
+            } else {
+                fee_schedule.close_fee_bps
+            };
+            let fee = self.calculate_fee(new_amount, position.leverage, self.tiered_fee_bps(user, fee_bps))?;
+            let (fee, rebate) = self.apply_referral(user, fee)?;
+            self.record_volume(
+                user,
+                new_amount.checked_mul(position.leverage as Balance).ok_or(Error::Overflow)?,
+            )?;
+
             let collect_fee = build_call::<DefaultEnvironment>()
                 .call(self.vault)
                 .call_v1()
@@ -225,15 +708,78 @@ mod manager {
                     ExecutionInput::new(Selector::new(ink::selector_bytes!("update_liquidity")))
                         .push_arg(position.token)
                         .push_arg(updated_amount)
+                        .push_arg(fee)
                         .push_arg(user),
                 )
                 .returns::<bool>()
                 .invoke();
 
+            self.pay_rebate(user, rebate);
+
             self.env().emit_event(PositionUpdated {
                 from: Some(user),
                 position_id,
                 amount: updated_amount,
+                fee,
+            });
+
+            Ok(())
+        }
+
+        /// Adds `amount` of collateral to a position while keeping its size, which
+        /// lowers its leverage and moves its liquidation price away.
+        #[ink(message)]
+        pub fn add_margin(&mut self, position_id: PositionId, amount: Balance, user: AccountId) -> Result<()> {
+            self.check_user(user, TradeAction::Modify)?;
+
+            if amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            let mut position = self.get_position(user, position_id)?;
+            self.check_market(position.token, PauseState::CloseOnly)?;
+
+            let new_amount = position.amount.checked_add(amount).ok_or(Error::Overflow)?;
+            let leverage = Self::leverage_after_margin(&position, new_amount)?;
+            let reserved = self.reserve_amount(new_amount, leverage, &position.position_type)?;
+
+            if reserved > position.reserved {
+                self.reserve_pool_liquidity(reserved.checked_sub(position.reserved).ok_or(Error::Underflow)?)?;
+            } else {
+                self.release_pool_liquidity(position.reserved.checked_sub(reserved).ok_or(Error::Underflow)?);
+            }
+
+            // the entry price stays the same
+            position.position_value = position
+                .position_value
+                .checked_mul(new_amount)
+                .ok_or(Error::Overflow)?
+                .checked_div(position.amount)
+                .ok_or(Error::ZeroAmount)?;
+            position.amount = new_amount;
+            position.leverage = leverage;
+            position.reserved = reserved;
+            self.positions.insert((user, position_id), &position);
+
+            let deposit = build_call::<DefaultEnvironment>()
+                .call(self.vault)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("update_liquidity")))
+                        .push_arg(position.token)
+                        .push_arg(new_amount)
+                        .push_arg(0 as Balance)
+                        .push_arg(user),
+                )
+                .returns::<bool>()
+                .invoke();
+
+            self.env().emit_event(MarginAdded {
+                from: Some(user),
+                position_id,
+                amount,
+                leverage,
             });
 
             Ok(())
@@ -241,6 +787,8 @@ mod manager {
 
         #[ink(message)]
         pub fn close_position(&mut self, position_id: PositionId, user: AccountId) -> Result<()> {
+            self.check_user(user, TradeAction::Close)?;
+
             let temp = self.get_position(user, position_id);
 
             if temp.is_err() {
@@ -248,13 +796,24 @@ mod manager {
             }
 
             let token = self.get_position(user, position_id).unwrap().token;
-
-            match self
-                .positions
-                .get((user, position_id))
-                .unwrap()
-                .position_type
-            {
+            self.check_market(token, PauseState::CloseOnly)?;
+            self.check_circuit_breaker(token)?;
+            self.charge_maintenance_fee(user, position_id)?;
+
+            let position = self.get_position(user, position_id).unwrap();
+            let reserved = position.reserved;
+            let fee = self.calculate_fee(
+                position.amount,
+                position.leverage,
+                self.tiered_fee_bps(user, self.get_fee_schedule(token).close_fee_bps),
+            )?;
+            let (fee, rebate) = self.apply_referral(user, fee)?;
+            self.record_volume(
+                user,
+                position.amount.checked_mul(position.leverage as Balance).ok_or(Error::Overflow)?,
+            )?;
+
+            match position.position_type {
                 PositionType::LONG => {
                     self.long_total = self.long_total.checked_sub(1).unwrap();
                 }
@@ -264,6 +823,8 @@ mod manager {
             }
 
             self.positions.remove((user, position_id));
+            self.untrack_position(user, position_id, &position.position_type);
+            self.release_pool_liquidity(reserved);
 
             let withdraw = build_call::<DefaultEnvironment>()
                 .call(self.vault)
@@ -272,35 +833,96 @@ mod manager {
                 .exec_input(
                     ExecutionInput::new(Selector::new(ink::selector_bytes!("remove_liquidity")))
                         .push_arg(token)
+                        .push_arg(fee)
                         .push_arg(user),
                 )
                 .returns::<bool>()
                 .invoke();
 
+            self.pay_rebate(user, rebate);
+
             self.env().emit_event(PositionClosed {
                 from: Some(user),
                 position_id,
+                fee,
             });
 
             Ok(())
         }
 
+        /// Executes `calls` in order, e.g. closing a position and opening the
+        /// opposite one. The first failing call aborts the batch and its error is
+        /// returned, which reverts everything done by the calls before it.
+        #[ink(message)]
+        pub fn multicall(&mut self, calls: Vec<Call>) -> Result<Vec<CallOutput>> {
+            let user = self.env().caller();
+            let mut outputs = Vec::with_capacity(calls.len());
+
+            for call in calls {
+                let output = match call {
+                    Call::OpenPosition {
+                        token,
+                        amount,
+                        position_type,
+                        leverage,
+                    } => {
+                        let position_id = self.position_id;
+                        self.open_position(token, amount, position_type, leverage, user)?;
+                        CallOutput::PositionOpened(position_id)
+                    }
+                    Call::UpdatePosition {
+                        position_id,
+                        amount,
+                    } => {
+                        self.update_position(amount, position_id, user)?;
+                        CallOutput::PositionUpdated
+                    }
+                    Call::ClosePosition { position_id } => {
+                        self.close_position(position_id, user)?;
+                        CallOutput::PositionClosed
+                    }
+                    Call::AddMargin {
+                        position_id,
+                        amount,
+                    } => {
+                        self.add_margin(position_id, amount, user)?;
+                        CallOutput::MarginAdded
+                    }
+                };
+                outputs.push(output);
+            }
+
+            Ok(outputs)
+        }
+
+        /// Liquidates a position. If its loss exceeds its collateral, the deficit is
+        /// covered from the insurance fund first and by auto-deleveraging the most
+        /// profitable opposing positions once the insurance fund is exhausted. What
+        /// neither can cover is recorded as bad debt. If the market's price moved more
+        /// than `max_price_move_bps` within the block, the circuit breaker pauses the
+        /// market instead and the position is left open.
         #[ink(message)]
         pub fn liquidation(&mut self, position_id: PositionId, user: AccountId) -> Result<()> {
+            self.check_role(KEEPER, self.env().caller())?;
+
             let temp = self.get_position(user, position_id);
 
             if temp.is_err() {
                 return Err(Error::NotFound);
             }
 
-            let token = self.get_position(user, position_id).unwrap().token;
+            let position = temp.unwrap();
+            let token = position.token;
+            let reserved = position.reserved;
 
-            match self
-                .positions
-                .get((user, position_id))
-                .unwrap()
-                .position_type
-            {
+            self.check_market(token, PauseState::CloseOnly)?;
+
+            // a market whose price jumped is not liquidated at that price
+            if self.trip_on_price_move(token) {
+                return Ok(());
+            }
+
+            match position.position_type {
                 PositionType::LONG => {
                     self.long_total = self.long_total.checked_sub(1).unwrap();
                 }
@@ -310,6 +932,8 @@ mod manager {
             }
 
             self.positions.remove((user, position_id));
+            self.untrack_position(user, position_id, &position.position_type);
+            self.release_pool_liquidity(reserved);
 
             let withdraw = build_call::<DefaultEnvironment>()
                 .call(self.vault)
@@ -328,256 +952,2713 @@ mod manager {
                 position_id,
             });
 
+            let current_price = self.get_token_price(token) as Balance;
+            let deficit = self.loss(&position, current_price)?.saturating_sub(position.amount);
+
+            if deficit > 0 {
+                self.cover_deficit(&position, deficit, current_price)?;
+            }
+
             Ok(())
         }
 
         #[ink(message)]
-        pub fn get_price(&self) -> u32 {
-            let price = build_call::<DefaultEnvironment>()
-                .call(self.oracle)
-                .call_v1()
-                .gas_limit(0)
-                .exec_input(
-                    ExecutionInput::new(Selector::new(ink::selector_bytes!("get_price")))
-                )
-                .returns::<u32>()
-                .invoke();
-            price
+        pub fn get_bad_debt(&self) -> Balance {
+            self.bad_debt.get().unwrap_or_default()
         }
 
+        /// ADL quantile of a position from 1 (deleveraged last) to 5 (deleveraged
+        /// first), or 0 if the position is not in profit and cannot be deleveraged.
         #[ink(message)]
-        pub fn get_position(&self, user: AccountId, position_id: u128) -> Result<Position> {
-            self.positions.get(&(user, position_id)).ok_or(Error::NotFound)
+        pub fn get_adl_rank(&self, user: AccountId, position_id: PositionId) -> Result<u8> {
+            let token = self.get_position(user, position_id)?.token;
+            let current_price = self.get_token_price(token) as Balance;
+            self.adl_rank(user, position_id, current_price)
         }
 
+        /// Charges the maintenance fee accrued since the position was opened or last
+        /// settled and returns it. Settling again within the same block charges nothing.
         #[ink(message)]
-        pub fn get_number_longs(&self) -> Result<Balance> {
-            Ok(self.long_total)
+        pub fn settle_maintenance_fee(&mut self, position_id: PositionId, user: AccountId) -> Result<Balance> {
+            self.check_role(KEEPER, self.env().caller())?;
+            self.charge_maintenance_fee(user, position_id)
         }
 
         #[ink(message)]
-        pub fn get_number_shorts(&self) -> Result<Balance> {
-            Ok(self.short_total)
+        pub fn get_accrued_maintenance_fee(&self, user: AccountId, position_id: PositionId) -> Result<Balance> {
+            let position = self.get_position(user, position_id)?;
+            self.accrued_maintenance_fee(&position, self.env().block_timestamp().into())
         }
 
         #[ink(message)]
-        pub fn calculate_funding_rate(&self) -> Result<u32> {
-            let spot_price: u32 = 100; // TODO: fetch from oracle
-            let contract_price: u32 = 100; // TODO: fetch from oracle
-            let long_short_sub = contract_price
-                .checked_sub(spot_price)
-                .ok_or(Error::Underflow)?;
-            let ff = long_short_sub
-                .checked_div(spot_price)
-                .ok_or(Error::Underflow)?;
-            let mut oii = 0;
+        pub fn set_fee_schedule(&mut self, token: TokenId, schedule: FeeSchedule) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+            Self::validate_fee_schedule(&schedule)?;
 
-            if self.long_total > self.short_total {
-                oii = self
-                    .long_total
-                    .checked_sub(self.short_total)
-                    .ok_or(Error::Underflow)?;
-            } else {
-                oii = self
-                    .short_total
-                    .checked_sub(self.long_total)
-                    .ok_or(Error::Underflow)?;
+            self.fee_schedules.insert(token, &schedule);
+
+            self.env().emit_event(FeeScheduleUpdated {
+                token: Some(token),
+                schedule,
+            });
+
+            Ok(())
+        }
+
+        /// Sets the schedule used by markets without one of their own.
+        #[ink(message)]
+        pub fn set_default_fee_schedule(&mut self, schedule: FeeSchedule) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+            Self::validate_fee_schedule(&schedule)?;
+
+            self.default_fee_schedule = schedule;
+
+            self.env().emit_event(FeeScheduleUpdated {
+                token: None,
+                schedule,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_fee_schedule(&self, token: TokenId) -> FeeSchedule {
+            self.fee_schedules.get(token).unwrap_or(self.default_fee_schedule)
+        }
+
+        /// Lets `operator` trade for the caller as `permissions` allow until
+        /// `expiry`. Approving again replaces the previous approval.
+        #[ink(message)]
+        pub fn approve_operator(
+            &mut self,
+            operator: AccountId,
+            permissions: OperatorPermissions,
+            expiry: Timestamp,
+        ) -> Result<()> {
+            let caller = self.env().caller();
+
+            if operator == caller || expiry <= self.env().block_timestamp() {
+                return Err(Error::InvalidParameter);
             }
 
-            let toi = self
-                .long_total
-                .checked_add(self.short_total)
-                .ok_or(Error::Underflow)?;
+            self.operators.insert(
+                (caller, operator),
+                &OperatorApproval {
+                    permissions,
+                    expiry,
+                },
+            );
+
+            self.env().emit_event(OperatorApproved {
+                from: Some(caller),
+                operator,
+                permissions,
+                expiry,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn revoke_operator(&mut self, operator: AccountId) -> Result<()> {
+            let caller = self.env().caller();
+
+            if self.operators.take((caller, operator)).is_none() {
+                return Err(Error::NotFound);
+            }
+
+            self.env().emit_event(OperatorRevoked {
+                from: Some(caller),
+                operator,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_operator(&self, user: AccountId, operator: AccountId) -> Option<OperatorApproval> {
+            self.operators.get((user, operator))
+        }
+
+        /// Replaces the volume fee tiers. `tiers` must be ordered by strictly
+        /// increasing `min_volume`.
+        #[ink(message)]
+        pub fn set_fee_tiers(&mut self, tiers: Vec<FeeTier>) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            if tiers.len() > MAX_FEE_TIERS
+                || tiers.iter().any(|tier| tier.discount_bps as Balance > BPS_DENOMINATOR)
+                || tiers.windows(2).any(|pair| pair[0].min_volume >= pair[1].min_volume)
+            {
+                return Err(Error::InvalidParameter);
+            }
+
+            self.fee_tiers.set(&tiers);
+
+            self.env().emit_event(FeeTiersUpdated { tiers });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_fee_tiers(&self) -> Vec<FeeTier> {
+            self.fee_tiers.get().unwrap_or_default()
+        }
+
+        /// Highest tier `account` qualifies for with its current volume, or the
+        /// default tier without a discount.
+        #[ink(message)]
+        pub fn fee_tier(&self, account: AccountId) -> FeeTier {
+            let volume = self.get_volume(account);
+
+            self.get_fee_tiers()
+                .into_iter()
+                .rev()
+                .find(|tier| volume >= tier.min_volume)
+                .unwrap_or_default()
+        }
+
+        /// Notional traded by `account` over the last 30 days, today included.
+        #[ink(message)]
+        pub fn get_volume(&self, account: AccountId) -> Balance {
+            let today = self.today();
+
+            self.trader_volumes
+                .get(account)
+                .unwrap_or_default()
+                .iter()
+                .filter(|(day, _)| Self::in_volume_window(*day, today))
+                .fold(0, |total: Balance, (_, volume)| total.saturating_add(*volume))
+        }
+
+        /// Registers `code` for the caller, who then earns rebates on the fees of
+        /// every trader using it.
+        #[ink(message)]
+        pub fn register_code(&mut self, code: ReferralCode) -> Result<()> {
+            let caller = self.env().caller();
+
+            if code == ReferralCode::default() {
+                return Err(Error::InvalidParameter);
+            }
+
+            if self.referral_code_owners.contains(code) {
+                return Err(Error::CodeTaken);
+            }
+
+            self.referral_code_owners.insert(code, &caller);
+
+            self.env().emit_event(ReferralCodeRegistered {
+                from: Some(caller),
+                code,
+            });
+
+            Ok(())
+        }
+
+        /// Makes the caller a referee of the owner of `code` for all later trades.
+        #[ink(message)]
+        pub fn set_referral_code(&mut self, code: ReferralCode) -> Result<()> {
+            let caller = self.env().caller();
+            let referrer = self.referral_code_owners.get(code).ok_or(Error::CodeNotFound)?;
+
+            if referrer == caller {
+                return Err(Error::InvalidParameter);
+            }
+
+            self.trader_referral_codes.insert(caller, &code);
+
+            self.env().emit_event(ReferralCodeSet {
+                from: Some(caller),
+                code,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_code_owner(&self, code: ReferralCode) -> Option<AccountId> {
+            self.referral_code_owners.get(code)
+        }
+
+        #[ink(message)]
+        pub fn get_referral_code(&self, account: AccountId) -> Option<ReferralCode> {
+            self.trader_referral_codes.get(account)
+        }
+
+        /// Sets the rates of `tier`. Referrers are in tier 0 until assigned
+        /// another one.
+        #[ink(message)]
+        pub fn set_referral_tier(&mut self, tier: u8, rates: ReferralTier) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            let total_bps = (rates.discount_bps as Balance).saturating_add(rates.rebate_bps as Balance);
+            if total_bps > BPS_DENOMINATOR {
+                return Err(Error::InvalidParameter);
+            }
+
+            self.referral_tiers.insert(tier, &rates);
+
+            self.env().emit_event(ReferralTierUpdated { tier, rates });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_referral_tier(&self, tier: u8) -> ReferralTier {
+            self.referral_tiers.get(tier).unwrap_or_default()
+        }
+
+        #[ink(message)]
+        pub fn set_referrer_tier(&mut self, referrer: AccountId, tier: u8) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            self.referrer_tiers.insert(referrer, &tier);
+
+            self.env().emit_event(ReferrerTierUpdated { referrer, tier });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_referrer_tier(&self, referrer: AccountId) -> u8 {
+            self.referrer_tiers.get(referrer).unwrap_or_default()
+        }
+
+        /// Upgrades the contract to the code at `code_hash`. Positions and settings
+        /// stay in place as long as the new code keeps the plain fields of
+        /// `Manager` unchanged, since they share the root storage cell, and
+        /// adds any new field as `Lazy` or `Mapping`.
+        #[ink(message)]
+        pub fn upgrade(&mut self, code_hash: Hash) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            self.env().set_code_hash(&code_hash).unwrap_or_else(|err| {
+                panic!("failed to set code hash {:?}: {:?}", code_hash, err)
+            });
+
+            self.env().emit_event(Upgraded { code_hash });
+
+            Ok(())
+        }
+
+        /// Migrates storage left by a previous code version to `STORAGE_VERSION`.
+        #[ink(message)]
+        pub fn migrate(&mut self) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            if self.version >= STORAGE_VERSION {
+                return Err(Error::AlreadyMigrated);
+            }
+
+            self.version = STORAGE_VERSION;
+
+            self.env().emit_event(Migrated {
+                version: self.version,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_version(&self) -> u32 {
+            self.version
+        }
+
+        /// Proposes `new_owner` as the next owner. Nothing changes until
+        /// `new_owner` calls `accept_ownership`.
+        #[ink(message)]
+        pub fn transfer_ownership(&mut self, new_owner: AccountId) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            self.ownership.start_transfer(new_owner);
+
+            self.env().emit_event(OwnershipTransferStarted {
+                previous_owner: self.ownership.owner(),
+                new_owner,
+            });
+
+            Ok(())
+        }
+
+        /// Completes a transfer started by `transfer_ownership`. `ADMIN` moves from
+        /// the previous owner to the caller along with ownership.
+        #[ink(message)]
+        pub fn accept_ownership(&mut self) -> Result<()> {
+            let caller = self.env().caller();
+            let previous_owner = self.ownership.owner();
+
+            if !self.ownership.accept(caller) {
+                return Err(Error::NotPendingOwner);
+            }
+
+            if let Some(previous_owner) = previous_owner {
+                if self.access_control.revoke(ADMIN, previous_owner) {
+                    self.env().emit_event(RoleRevoked {
+                        role: ADMIN,
+                        account: previous_owner,
+                        sender: caller,
+                    });
+                }
+            }
+
+            if self.access_control.grant(ADMIN, caller) {
+                self.env().emit_event(RoleGranted {
+                    role: ADMIN,
+                    account: caller,
+                    sender: caller,
+                });
+            }
+
+            self.env().emit_event(OwnershipTransferred {
+                previous_owner,
+                new_owner: Some(caller),
+            });
+
+            Ok(())
+        }
+
+        /// Leaves the contract without an owner, which disables `upgrade` and
+        /// `migrate` for good. The owner's `ADMIN` goes with it, other admins
+        /// keep theirs.
+        #[ink(message)]
+        pub fn renounce_ownership(&mut self) -> Result<()> {
+            let caller = self.env().caller();
+            self.check_owner(caller)?;
+
+            let previous_owner = self.ownership.owner();
+            self.ownership.renounce();
+
+            if self.access_control.revoke(ADMIN, caller) {
+                self.env().emit_event(RoleRevoked {
+                    role: ADMIN,
+                    account: caller,
+                    sender: caller,
+                });
+            }
+
+            self.env().emit_event(OwnershipTransferred {
+                previous_owner,
+                new_owner: None,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn owner(&self) -> Option<AccountId> {
+            self.ownership.owner()
+        }
+
+        #[ink(message)]
+        pub fn pending_owner(&self) -> Option<AccountId> {
+            self.ownership.pending_owner()
+        }
+
+        /// Restricts trading on `token`, or on every market if `token` is `None`.
+        /// Callable by a `GUARDIAN` or an `ADMIN`.
+        #[ink(message)]
+        pub fn pause(&mut self, token: Option<TokenId>, state: PauseState) -> Result<()> {
+            let caller = self.env().caller();
+            self.check_guardian(caller)?;
+
+            if state == PauseState::Active {
+                return Err(Error::InvalidParameter);
+            }
+
+            self.set_pause_state(token, state);
+
+            self.env().emit_event(Paused {
+                token,
+                state,
+                by: Some(caller),
+            });
+
+            Ok(())
+        }
+
+        /// Lifts the restriction on `token`, or the global one if `token` is `None`.
+        /// Only an `ADMIN` can unpause.
+        #[ink(message)]
+        pub fn unpause(&mut self, token: Option<TokenId>) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            self.set_pause_state(token, PauseState::Active);
+
+            self.env().emit_event(Unpaused { token });
+
+            Ok(())
+        }
+
+        /// Pauses `token` if its oracle price moved more than `max_price_move_bps`
+        /// within the current block. Only a `KEEPER` can trip the breaker by hand;
+        /// liquidations trip it on their own.
+        #[ink(message)]
+        pub fn trip_circuit_breaker(&mut self, token: TokenId) -> Result<()> {
+            self.check_role(KEEPER, self.env().caller())?;
+
+            if !self.trip_on_price_move(token) {
+                return Err(Error::InvalidParameter);
+            }
+
+            Ok(())
+        }
+
+        /// Stricter of the market's own state and the global state.
+        #[ink(message)]
+        pub fn get_pause_state(&self, token: TokenId) -> PauseState {
+            let market_state = self.market_pause_states.get(token).unwrap_or_default();
+            market_state.max(self.pause_state)
+        }
+
+        #[ink(message)]
+        pub fn get_global_pause_state(&self) -> PauseState {
+            self.pause_state
+        }
+
+        /// Sets the largest oracle price move within one block, in basis points,
+        /// that is tolerated before the circuit breaker trips. 0 disables it.
+        #[ink(message)]
+        pub fn set_max_price_move_bps(&mut self, bps: u32) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+            self.max_price_move_bps = bps;
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_max_price_move_bps(&self) -> u32 {
+            self.max_price_move_bps
+        }
+
+        #[ink(message)]
+        pub fn set_oracle(&mut self, oracle: AccountId) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+            self.request_address_update(AddressKind::Oracle, oracle)
+        }
+
+        #[ink(message)]
+        pub fn get_oracle(&self) -> AccountId {
+            self.oracle
+        }
+
+        #[ink(message)]
+        pub fn set_vault(&mut self, vault: AccountId) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+            self.request_address_update(AddressKind::Vault, vault)
+        }
+
+        #[ink(message)]
+        pub fn get_vault(&self) -> AccountId {
+            self.vault
+        }
+
+        /// Sets the contract allowed to trade for any `user`. It must only ever
+        /// pass its own caller as `user`.
+        #[ink(message)]
+        pub fn set_router(&mut self, router: AccountId) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+            self.request_address_update(AddressKind::Router, router)
+        }
+
+        #[ink(message)]
+        pub fn get_router(&self) -> Option<AccountId> {
+            self.router.get()
+        }
+
+        /// Applies a queued address change once its delay has passed. Anyone can
+        /// call this.
+        #[ink(message)]
+        pub fn apply_address_update(&mut self, kind: AddressKind) -> Result<()> {
+            let update = self.pending_address_updates.get(kind).ok_or(Error::NotFound)?;
+
+            if self.env().block_timestamp() < update.eta {
+                return Err(Error::UpdateNotReady);
+            }
+
+            self.pending_address_updates.remove(kind);
+            self.update_address(kind, update.address);
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn cancel_address_update(&mut self, kind: AddressKind) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            if self.pending_address_updates.take(kind).is_none() {
+                return Err(Error::NotFound);
+            }
+
+            self.env().emit_event(AddressUpdateCancelled { kind });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_pending_address_update(&self, kind: AddressKind) -> Option<PendingAddressUpdate> {
+            self.pending_address_updates.get(kind)
+        }
+
+        /// Sets how long address changes wait before they can be applied. With 0
+        /// the setters take effect immediately. Once a delay is in place, changing
+        /// it waits for that delay too.
+        #[ink(message)]
+        pub fn set_address_update_delay(&mut self, delay: Timestamp) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            let current = self.get_address_update_delay();
+
+            if current == 0 {
+                self.update_address_update_delay(delay);
+                return Ok(());
+            }
+
+            let eta = self
+                .env()
+                .block_timestamp()
+                .checked_add(current)
+                .ok_or(Error::Overflow)?;
+            self.pending_address_update_delay.set(&Some(PendingDelayUpdate { delay, eta }));
+
+            self.env().emit_event(AddressUpdateDelayQueued { delay, eta });
+
+            Ok(())
+        }
+
+        /// Applies a queued delay change once the current delay has passed. Anyone
+        /// can call this.
+        #[ink(message)]
+        pub fn apply_address_update_delay(&mut self) -> Result<()> {
+            let update = self.get_pending_address_update_delay().ok_or(Error::NotFound)?;
+
+            if self.env().block_timestamp() < update.eta {
+                return Err(Error::UpdateNotReady);
+            }
+
+            self.update_address_update_delay(update.delay);
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn cancel_address_update_delay(&mut self) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            let update = self.get_pending_address_update_delay().ok_or(Error::NotFound)?;
+            self.pending_address_update_delay.set(&None);
+
+            self.env().emit_event(AddressUpdateDelayCancelled { delay: update.delay });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_address_update_delay(&self) -> Timestamp {
+            self.address_update_delay.get().unwrap_or_default()
+        }
+
+        #[ink(message)]
+        pub fn get_pending_address_update_delay(&self) -> Option<PendingDelayUpdate> {
+            self.pending_address_update_delay.get().flatten()
+        }
+
+        #[ink(message)]
+        pub fn get_price(&self) -> u32 {
+            let price = build_call::<DefaultEnvironment>()
+                .call(self.oracle)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("get_price")))
+                )
+                .returns::<u32>()
+                .invoke();
+            price
+        }
+
+        #[ink(message)]
+        pub fn get_position(&self, user: AccountId, position_id: u128) -> Result<Position> {
+            self.positions.get(&(user, position_id)).ok_or(Error::NotFound)
+        }
+
+        #[ink(message)]
+        pub fn get_number_longs(&self) -> Result<Balance> {
+            Ok(self.long_total)
+        }
+
+        #[ink(message)]
+        pub fn get_number_shorts(&self) -> Result<Balance> {
+            Ok(self.short_total)
+        }
+
+        #[ink(message)]
+        pub fn set_max_utilization_bps(&mut self, bps: u32) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            if bps as Balance > BPS_DENOMINATOR {
+                return Err(Error::InvalidParameter);
+            }
+
+            self.max_utilization_bps = bps;
+            Ok(())
+        }
+
+        /// Sets the share of a position's notional (`amount * leverage`) that is
+        /// reserved in the vault while the position is open.
+        #[ink(message)]
+        pub fn set_reserve_factor_bps(&mut self, bps: u32) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+            self.reserve_factor_bps = bps;
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_max_utilization_bps(&self) -> u32 {
+            self.max_utilization_bps
+        }
+
+        #[ink(message)]
+        pub fn get_reserve_factor_bps(&self) -> u32 {
+            self.reserve_factor_bps
+        }
+
+        #[ink(message)]
+        pub fn calculate_funding_rate(&self) -> Result<u32> {
+            let spot_price: u32 = 100; // TODO: fetch from oracle
+            let contract_price: u32 = 100; // TODO: fetch from oracle
+            let long_short_sub = contract_price
+                .checked_sub(spot_price)
+                .ok_or(Error::Underflow)?;
+            let ff = long_short_sub
+                .checked_div(spot_price)
+                .ok_or(Error::Underflow)?;
+            let mut oii = 0;
+
+            if self.long_total > self.short_total {
+                oii = self
+                    .long_total
+                    .checked_sub(self.short_total)
+                    .ok_or(Error::Underflow)?;
+            } else {
+                oii = self
+                    .short_total
+                    .checked_sub(self.long_total)
+                    .ok_or(Error::Underflow)?;
+            }
+
+            let toi = self
+                .long_total
+                .checked_add(self.short_total)
+                .ok_or(Error::Underflow)?;
+
+            let funding_rate = ff
+                .wrapping_mul(oii as u32)
+                .checked_div(toi as u32)
+                .ok_or(Error::Underflow)?;
+
+            Ok(funding_rate)
+        }
+
+        /// Liquidity the vault has to hold back for a position: the configured
+        /// fraction of its notional, capped at the notional for shorts since that
+        /// is the most a short can ever make.
+        fn reserve_amount(
+            &self,
+            amount: Balance,
+            leverage: u32,
+            position_type: &PositionType,
+        ) -> Result<Balance> {
+            let notional = amount.checked_mul(leverage as Balance).ok_or(Error::Overflow)?;
+            let reserve = notional
+                .checked_mul(self.reserve_factor_bps as Balance)
+                .ok_or(Error::Overflow)?
+                .checked_div(BPS_DENOMINATOR)
+                .ok_or(Error::Underflow)?;
+
+            match position_type {
+                PositionType::LONG => Ok(reserve),
+                PositionType::SHORT => Ok(reserve.min(notional)),
+            }
+        }
+
+        /// Fails with `InsufficientPoolLiquidity` if reserving `amount` on top of
+        /// `reserved` would push utilization of the pool above `max_utilization_bps`.
+        fn check_utilization(&self, reserved: Balance, available: Balance, amount: Balance) -> Result<()> {
+            let total = reserved.checked_add(available).ok_or(Error::Overflow)?;
+            let reserved_after = reserved.checked_add(amount).ok_or(Error::Overflow)?;
+
+            let utilization = reserved_after.checked_mul(BPS_DENOMINATOR).ok_or(Error::Overflow)?;
+            let cap = total
+                .checked_mul(self.max_utilization_bps as Balance)
+                .ok_or(Error::Overflow)?;
+
+            if utilization > cap {
+                return Err(Error::InsufficientPoolLiquidity);
+            }
+
+            Ok(())
+        }
+
+        fn reserve_pool_liquidity(&mut self, amount: Balance) -> Result<()> {
+            let reserved = build_call::<DefaultEnvironment>()
+                .call(self.vault)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("get_reserved_liquidity")))
+                )
+                .returns::<Balance>()
+                .invoke();
+
+            let available = build_call::<DefaultEnvironment>()
+                .call(self.vault)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("get_available_liquidity")))
+                )
+                .returns::<Balance>()
+                .invoke();
+
+            self.check_utilization(reserved, available, amount)?;
+
+            let reserve = build_call::<DefaultEnvironment>()
+                .call(self.vault)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("reserve_liquidity")))
+                        .push_arg(amount),
+                )
+                .returns::<Result<()>>()
+                .invoke();
+
+            reserve.map_err(|_| Error::InsufficientPoolLiquidity)
+        }
+
+        fn release_pool_liquidity(&mut self, amount: Balance) {
+            if amount == 0 {
+                return;
+            }
+
+            let release = build_call::<DefaultEnvironment>()
+                .call(self.vault)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("release_liquidity")))
+                        .push_arg(amount),
+                )
+                .returns::<Result<()>>()
+                .invoke();
+        }
+
+        fn set_pause_state(&mut self, token: Option<TokenId>, state: PauseState) {
+            match token {
+                Some(token) => {
+                    self.market_pause_states.insert(token, &state);
+                }
+                None => {
+                    self.pause_state = state;
+                }
+            }
+        }
+
+        /// Fails with `Paused` if `token` is held to a stricter state than `allowed`.
+        fn check_market(&self, token: TokenId, allowed: PauseState) -> Result<()> {
+            if self.get_pause_state(token) > allowed {
+                return Err(Error::Paused);
+            }
+
+            Ok(())
+        }
+
+        fn exceeds_price_move(&self, price_move_bps: u32) -> bool {
+            self.max_price_move_bps > 0 && price_move_bps > self.max_price_move_bps
+        }
+
+        fn check_circuit_breaker(&self, token: TokenId) -> Result<()> {
+            if self.max_price_move_bps == 0 {
+                return Ok(());
+            }
+
+            if self.exceeds_price_move(self.get_token_price_move_bps(token)) {
+                return Err(Error::CircuitBreakerTripped);
+            }
+
+            Ok(())
+        }
+
+        /// Pauses `token` and returns `true` if its price moved more than
+        /// `max_price_move_bps` within the current block.
+        fn trip_on_price_move(&mut self, token: TokenId) -> bool {
+            if self.check_circuit_breaker(token).is_ok() {
+                return false;
+            }
+
+            self.set_pause_state(Some(token), PauseState::Paused);
+
+            self.env().emit_event(Paused {
+                token: Some(token),
+                state: PauseState::Paused,
+                by: None,
+            });
+
+            true
+        }
+
+        fn get_token_price(&self, token: TokenId) -> u32 {
+            build_call::<DefaultEnvironment>()
+                .call(self.oracle)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("get_token_price")))
+                        .push_arg(token),
+                )
+                .returns::<u32>()
+                .invoke()
+        }
+
+        fn get_token_price_move_bps(&self, token: TokenId) -> u32 {
+            build_call::<DefaultEnvironment>()
+                .call(self.oracle)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("get_token_price_move_bps")))
+                        .push_arg(token),
+                )
+                .returns::<u32>()
+                .invoke()
+        }
+
+        fn side_key(side: &PositionType) -> u8 {
+            match side {
+                PositionType::LONG => 0,
+                PositionType::SHORT => 1,
+            }
+        }
+
+        fn track_position(&mut self, user: AccountId, position_id: PositionId, side: &PositionType) {
+            let side = Self::side_key(side);
+            let count = self.open_position_counts.get(side).unwrap_or_default();
+
+            self.open_positions.insert((side, count), &(user, position_id));
+            self.open_position_slots.insert((user, position_id), &count);
+            self.open_position_counts.insert(side, &count.saturating_add(1));
+        }
+
+        /// Removes a position from its side by moving the side's last position into
+        /// the freed slot.
+        fn untrack_position(&mut self, user: AccountId, position_id: PositionId, side: &PositionType) {
+            let slot = match self.open_position_slots.take((user, position_id)) {
+                Some(slot) => slot,
+                None => return,
+            };
+            let side = Self::side_key(side);
+            let last = self.open_position_counts.get(side).unwrap_or_default().saturating_sub(1);
+
+            if slot != last {
+                if let Some(moved) = self.open_positions.get((side, last)) {
+                    self.open_positions.insert((side, slot), &moved);
+                    self.open_position_slots.insert(moved, &slot);
+                }
+            }
+
+            self.open_positions.remove((side, last));
+            self.open_position_counts.insert(side, &last);
+        }
+
+        /// Leverage that keeps the size of `position` once its collateral becomes
+        /// `new_amount`, rounded down but never below 1.
+        fn leverage_after_margin(position: &Position, new_amount: Balance) -> Result<u32> {
+            let size = position.amount.checked_mul(position.leverage as Balance).ok_or(Error::Overflow)?;
+            let leverage = size.checked_div(new_amount).ok_or(Error::ZeroAmount)?.max(1);
+            u32::try_from(leverage).map_err(|_| Error::Overflow)
+        }
+
+        fn entry_price(position: &Position) -> Result<Balance> {
+            position.position_value.checked_div(position.amount).ok_or(Error::ZeroAmount)
+        }
+
+        /// Unrealized profit and loss of a position at `price`, in collateral units.
+        /// Exactly one of the two is non-zero.
+        fn pnl(&self, position: &Position, price: Balance) -> Result<(Balance, Balance)> {
+            let entry_price = Self::entry_price(position)?;
+            let size = position.amount.checked_mul(position.leverage as Balance).ok_or(Error::Overflow)?;
+
+            let (gain, price_move) = match position.position_type {
+                PositionType::LONG => (price >= entry_price, price.abs_diff(entry_price)),
+                PositionType::SHORT => (price <= entry_price, price.abs_diff(entry_price)),
+            };
+
+            let value = size
+                .checked_mul(price_move)
+                .ok_or(Error::Overflow)?
+                .checked_div(entry_price)
+                .ok_or(Error::ZeroAmount)?;
+
+            if gain {
+                Ok((value, 0))
+            } else {
+                Ok((0, value))
+            }
+        }
+
+        fn loss(&self, position: &Position, price: Balance) -> Result<Balance> {
+            Ok(self.pnl(position, price)?.1)
+        }
+
+        /// ADL ranking score: return on collateral in bps multiplied by leverage.
+        fn adl_score(&self, position: &Position, price: Balance) -> Result<Balance> {
+            let (profit, _) = self.pnl(position, price)?;
+
+            profit
+                .checked_mul(BPS_DENOMINATOR)
+                .ok_or(Error::Overflow)?
+                .checked_div(position.amount)
+                .ok_or(Error::ZeroAmount)?
+                .checked_mul(position.leverage as Balance)
+                .ok_or(Error::Overflow)
+        }
+
+        /// Profitable positions on `side` of the `token` market among at most `limit`
+        /// slots from `start` on, wrapping around at the last slot, highest ADL score
+        /// first.
+        fn adl_candidates(
+            &self,
+            side: &PositionType,
+            token: TokenId,
+            price: Balance,
+            start: u32,
+            limit: u32,
+        ) -> Vec<(AccountId, PositionId, Balance)> {
+            let side_key = Self::side_key(side);
+            let count = self.open_position_counts.get(side_key).unwrap_or_default();
+
+            let mut candidates: Vec<(AccountId, PositionId, Balance)> = (0..limit.min(count))
+                .filter_map(|offset| {
+                    let slot = Self::wrap_slot(start, offset, count);
+                    let (user, position_id) = self.open_positions.get((side_key, slot))?;
+                    let position = self.positions.get((user, position_id))?;
+
+                    if position.token != token {
+                        return None;
+                    }
+
+                    match self.adl_score(&position, price) {
+                        Ok(score) if score > 0 => Some((user, position_id, score)),
+                        _ => None,
+                    }
+                })
+                .collect();
+
+            candidates.sort_by(|a, b| b.2.cmp(&a.2));
+            candidates
+        }
+
+        /// Slot `offset` places after `start` on a side with `count` slots.
+        fn wrap_slot(start: u32, offset: u32, count: u32) -> u32 {
+            ((start as u64 + offset as u64) % count as u64) as u32
+        }
+
+        fn adl_rank(&self, user: AccountId, position_id: PositionId, price: Balance) -> Result<u8> {
+            let position = self.get_position(user, position_id)?;
+            let candidates = self.adl_candidates(&position.position_type, position.token, price, 0, u32::MAX);
+
+            let index = match candidates
+                .iter()
+                .position(|(candidate, id, _)| *candidate == user && *id == position_id)
+            {
+                Some(index) => index,
+                None => return Ok(0),
+            };
+
+            let quantile = index
+                .checked_mul(ADL_QUANTILES as usize)
+                .ok_or(Error::Overflow)?
+                .checked_div(candidates.len())
+                .ok_or(Error::ZeroAmount)?;
+
+            Ok(ADL_QUANTILES.saturating_sub(quantile as u8))
+        }
+
+        fn cover_deficit(&mut self, bankrupt: &Position, deficit: Balance, price: Balance) -> Result<()> {
+            let insurance_fund = build_call::<DefaultEnvironment>()
+                .call(self.vault)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("get_insurance_fund")))
+                )
+                .returns::<Balance>()
+                .invoke();
+
+            let from_insurance = deficit.min(insurance_fund);
+
+            if from_insurance > 0 {
+                let use_insurance = build_call::<DefaultEnvironment>()
+                    .call(self.vault)
+                    .call_v1()
+                    .gas_limit(0)
+                    .exec_input(
+                        ExecutionInput::new(Selector::new(ink::selector_bytes!("use_insurance_fund")))
+                            .push_arg(from_insurance),
+                    )
+                    .returns::<Result<()>>()
+                    .invoke();
+            }
+
+            let remaining = deficit.checked_sub(from_insurance).ok_or(Error::Underflow)?;
+
+            if remaining > 0 {
+                let opposite_side = match bankrupt.position_type {
+                    PositionType::LONG => PositionType::SHORT,
+                    PositionType::SHORT => PositionType::LONG,
+                };
+                let uncovered = self.auto_deleverage(&opposite_side, bankrupt.token, remaining, price)?;
+
+                if uncovered > 0 {
+                    self.record_bad_debt(uncovered)?;
+                }
+            }
+
+            Ok(())
+        }
+
+        fn record_bad_debt(&mut self, amount: Balance) -> Result<()> {
+            let bad_debt = self.get_bad_debt().checked_add(amount).ok_or(Error::Overflow)?;
+            self.bad_debt.set(&bad_debt);
+
+            self.env().emit_event(BadDebtRecorded { amount, bad_debt });
+
+            Ok(())
+        }
+
+        /// Force-reduces the most profitable positions on `side` of the `token` market
+        /// until the profit they give up at `price` covers `deficit`. Like a normal
+        /// close, the reduction settles no PnL, so the profit stays with the LPs. Each
+        /// call only looks at the next `MAX_ADL_SCAN` open positions of the side, and
+        /// what they cannot cover is returned.
+        fn auto_deleverage(
+            &mut self,
+            side: &PositionType,
+            token: TokenId,
+            deficit: Balance,
+            price: Balance,
+        ) -> Result<Balance> {
+            let side_key = Self::side_key(side);
+            let count = self.open_position_counts.get(side_key).unwrap_or_default();
+
+            if count == 0 {
+                return Ok(deficit);
+            }
+
+            let start = self.adl_cursors.get(side_key).unwrap_or_default();
+            let candidates = self.adl_candidates(side, token, price, start, MAX_ADL_SCAN);
+            self.adl_cursors
+                .insert(side_key, &Self::wrap_slot(start, MAX_ADL_SCAN.min(count), count));
+
+            let mut remaining = deficit;
+
+            for (user, position_id, _) in candidates {
+                if remaining == 0 {
+                    break;
+                }
+
+                let position = self.get_position(user, position_id)?;
+                let profit = self.pnl(&position, price)?.0;
+
+                if profit == 0 {
+                    continue;
+                }
+
+                let (reduce_amount, covered) = if profit <= remaining {
+                    (position.amount, profit)
+                } else {
+                    // rounded up so the reduction never covers more than it gives up
+                    let reduce_amount = position
+                        .amount
+                        .checked_mul(remaining)
+                        .ok_or(Error::Overflow)?
+                        .div_ceil(profit);
+                    (reduce_amount, remaining)
+                };
+
+                self.force_reduce(user, position_id, position, reduce_amount)?;
+                remaining = remaining.checked_sub(covered).ok_or(Error::Underflow)?;
+
+                self.env().emit_event(AutoDeleveraged {
+                    from: Some(user),
+                    position_id,
+                    amount: reduce_amount,
+                    price,
+                    deficit_covered: covered,
+                });
+            }
+
+            Ok(remaining)
+        }
+
+        /// Closes `reduce_amount` of a position's collateral without fees, closing it
+        /// entirely when nothing is left. As with `close_position`, only the collateral
+        /// of the closed part is returned to the user and no PnL is settled.
+        fn force_reduce(
+            &mut self,
+            user: AccountId,
+            position_id: PositionId,
+            mut position: Position,
+            reduce_amount: Balance,
+        ) -> Result<()> {
+            let new_amount = position.amount.checked_sub(reduce_amount).ok_or(Error::Underflow)?;
+
+            if new_amount == 0 {
+                match position.position_type {
+                    PositionType::LONG => {
+                        self.long_total = self.long_total.checked_sub(1).ok_or(Error::Underflow)?;
+                    }
+                    PositionType::SHORT => {
+                        self.short_total = self.short_total.checked_sub(1).ok_or(Error::Underflow)?;
+                    }
+                }
+
+                self.positions.remove((user, position_id));
+                self.untrack_position(user, position_id, &position.position_type);
+                self.release_pool_liquidity(position.reserved);
+
+                let withdraw = build_call::<DefaultEnvironment>()
+                    .call(self.vault)
+                    .call_v1()
+                    .gas_limit(0)
+                    .exec_input(
+                        ExecutionInput::new(Selector::new(ink::selector_bytes!("remove_liquidity")))
+                            .push_arg(position.token)
+                            .push_arg(0 as Balance)
+                            .push_arg(user),
+                    )
+                    .returns::<bool>()
+                    .invoke();
+
+                return Ok(());
+            }
+
+            let entry_price = Self::entry_price(&position)?;
+            let reserved = self.reserve_amount(new_amount, position.leverage, &position.position_type)?;
+            self.release_pool_liquidity(position.reserved.saturating_sub(reserved));
+
+            position.amount = new_amount;
+            position.position_value = new_amount.checked_mul(entry_price).ok_or(Error::Overflow)?;
+            position.reserved = reserved;
+            self.positions.insert((user, position_id), &position);
+
+            let withdraw = build_call::<DefaultEnvironment>()
+                .call(self.vault)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("update_liquidity")))
+                        .push_arg(position.token)
+                        .push_arg(new_amount)
+                        .push_arg(0 as Balance)
+                        .push_arg(user),
+                )
+                .returns::<bool>()
+                .invoke();
+
+            Ok(())
+        }
+
+        fn calculate_fee(&self, amount: Balance, leverage: u32, fee_bps: u32) -> Result<Balance> {
+            amount
+                .checked_mul(leverage as Balance)
+                .ok_or(Error::Overflow)?
+                .checked_mul(fee_bps as Balance)
+                .ok_or(Error::Overflow)?
+                .checked_div(BPS_DENOMINATOR)
+                .ok_or(Error::Underflow)
+        }
+
+        fn tiered_fee_bps(&self, user: AccountId, fee_bps: u32) -> u32 {
+            fee_bps.saturating_sub(self.fee_tier(user).discount_bps)
+        }
+
+        /// Deducts the maintenance fee accrued since the last settlement from the
+        /// position's collateral and restarts the accrual from now.
+        fn record_maintenance_fee(&mut self, user: AccountId, position_id: PositionId) -> Result<(Position, Balance)> {
+            let mut position = self.get_position(user, position_id)?;
+            self.check_market(position.token, PauseState::CloseOnly)?;
+
+            if position.amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            let now: u128 = self.env().block_timestamp().into();
+            // a position whose fee exceeds its collateral is left for liquidation
+            let fee = self.accrued_maintenance_fee(&position, now)?.min(position.amount);
+
+            if fee == 0 {
+                return Ok((position, 0));
+            }
+
+            let amount = position.amount.checked_sub(fee).ok_or(Error::Underflow)?;
+            // the entry price stays the same
+            position.position_value = position
+                .position_value
+                .checked_mul(amount)
+                .ok_or(Error::Overflow)?
+                .checked_div(position.amount)
+                .ok_or(Error::ZeroAmount)?;
+            position.amount = amount;
+            position.last_fee_time = now;
+            self.positions.insert((user, position_id), &position);
+
+            Ok((position, fee))
+        }
+
+        /// Settles the accrued maintenance fee of a position and has the vault
+        /// collect it.
+        fn charge_maintenance_fee(&mut self, user: AccountId, position_id: PositionId) -> Result<Balance> {
+            let (position, fee) = self.record_maintenance_fee(user, position_id)?;
+
+            if fee == 0 {
+                return Ok(0);
+            }
+
+            let collect_fee = build_call::<DefaultEnvironment>()
+                .call(self.vault)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("collect_fee")))
+                        .push_arg(position.token)
+                        .push_arg(fee)
+                        .push_arg(user),
+                )
+                .returns::<bool>()
+                .invoke();
+
+            self.env().emit_event(MaintenanceFeeCharged {
+                from: Some(user),
+                position_id,
+                fee,
+            });
+
+            Ok(fee)
+        }
+
+        /// Adds `notional` to today's volume of `user` and drops the days that
+        /// left the window.
+        fn record_volume(&mut self, user: AccountId, notional: Balance) -> Result<()> {
+            let today = self.today();
+            let mut volumes = self.trader_volumes.get(user).unwrap_or_default();
+            volumes.retain(|(day, _)| Self::in_volume_window(*day, today));
+
+            match volumes.last_mut() {
+                Some((day, volume)) if *day == today => {
+                    *volume = volume.checked_add(notional).ok_or(Error::Overflow)?;
+                }
+                _ => volumes.push((today, notional)),
+            }
+
+            self.trader_volumes.insert(user, &volumes);
+
+            Ok(())
+        }
+
+        fn today(&self) -> Timestamp {
+            self.env().block_timestamp() / MILLIS_PER_DAY
+        }
+
+        fn in_volume_window(day: Timestamp, today: Timestamp) -> bool {
+            day.saturating_add(VOLUME_WINDOW_DAYS) > today
+        }
+
+        /// Applies the referral discount of `user` to `fee`. Returns the fee to
+        /// charge and the referrer with its rebate, paid out of the charged fee.
+        fn apply_referral(&self, user: AccountId, fee: Balance) -> Result<(Balance, Option<(AccountId, Balance)>)> {
+            let referrer = match self
+                .trader_referral_codes
+                .get(user)
+                .and_then(|code| self.referral_code_owners.get(code))
+            {
+                Some(referrer) => referrer,
+                None => return Ok((fee, None)),
+            };
+
+            let rates = self.get_referral_tier(self.get_referrer_tier(referrer));
+            let discount = Self::bps_of(fee, rates.discount_bps)?;
+            let rebate = Self::bps_of(fee, rates.rebate_bps)?;
+            let fee = fee.checked_sub(discount).ok_or(Error::Underflow)?;
+
+            if rebate == 0 {
+                return Ok((fee, None));
+            }
+
+            Ok((fee, Some((referrer, rebate))))
+        }
+
+        /// Moves the referrer's rebate out of the fees the vault just collected.
+        fn pay_rebate(&self, user: AccountId, rebate: Option<(AccountId, Balance)>) {
+            let (referrer, rebate) = match rebate {
+                Some(rebate) => rebate,
+                None => return,
+            };
+
+            let credit = build_call::<DefaultEnvironment>()
+                .call(self.vault)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("credit_rebate")))
+                        .push_arg(referrer)
+                        .push_arg(rebate),
+                )
+                .returns::<bool>()
+                .invoke();
+
+            self.env().emit_event(ReferralRebate {
+                from: Some(user),
+                referrer,
+                rebate,
+            });
+        }
+
+        fn bps_of(amount: Balance, bps: u32) -> Result<Balance> {
+            amount
+                .checked_mul(bps as Balance)
+                .ok_or(Error::Overflow)?
+                .checked_div(BPS_DENOMINATOR)
+                .ok_or(Error::Underflow)
+        }
+
+        /// Maintenance fee for the time elapsed between the position's last
+        /// settlement and `now`, pro rata per millisecond of the hourly rate.
+        fn accrued_maintenance_fee(&self, position: &Position, now: u128) -> Result<Balance> {
+            let elapsed = now.saturating_sub(position.last_fee_time);
+            let hourly_rate = self.get_fee_schedule(position.token).maintenance_fee_bps_per_hour;
+
+            position
+                .amount
+                .checked_mul(position.leverage as Balance)
+                .ok_or(Error::Overflow)?
+                .checked_mul(hourly_rate as Balance)
+                .ok_or(Error::Overflow)?
+                .checked_mul(elapsed)
+                .ok_or(Error::Overflow)?
+                .checked_div(BPS_DENOMINATOR.checked_mul(MILLIS_PER_HOUR).ok_or(Error::Overflow)?)
+                .ok_or(Error::Underflow)
+        }
+
+        fn validate_fee_schedule(schedule: &FeeSchedule) -> Result<()> {
+            if schedule.open_fee_bps as Balance > BPS_DENOMINATOR
+                || schedule.close_fee_bps as Balance > BPS_DENOMINATOR
+                || schedule.maintenance_fee_bps_per_hour as Balance > BPS_DENOMINATOR
+            {
+                return Err(Error::InvalidParameter);
+            }
+            Ok(())
+        }
+
+        fn check_owner(&self, account: AccountId) -> Result<()> {
+            if !self.ownership.is_owner(account) {
+                return Err(Error::NotOwner);
+            }
+            Ok(())
+        }
+
+        fn check_guardian(&self, account: AccountId) -> Result<()> {
+            if !self.access_control.has_role(GUARDIAN, account) {
+                return self.check_role(ADMIN, account);
+            }
+            Ok(())
+        }
+
+        fn check_role(&self, role: RoleType, account: AccountId) -> Result<()> {
+            if !self.access_control.has_role(role, account) {
+                return Err(Error::MissingRole);
+            }
+            Ok(())
+        }
+
+        /// Lets `user` itself, the router or an operator approved by `user` for
+        /// `action` act on the positions of `user`.
+        fn check_user(&self, user: AccountId, action: TradeAction) -> Result<()> {
+            let caller = self.env().caller();
+
+            if caller == user || self.get_router() == Some(caller) {
+                return Ok(());
+            }
+
+            match self.operators.get((user, caller)) {
+                Some(approval)
+                    if approval.permissions.allows(action)
+                        && self.env().block_timestamp() < approval.expiry =>
+                {
+                    Ok(())
+                }
+                _ => Err(Error::Unauthorized),
+            }
+        }
+
+        fn request_address_update(&mut self, kind: AddressKind, address: AccountId) -> Result<()> {
+            let delay = self.get_address_update_delay();
+
+            if delay == 0 {
+                self.update_address(kind, address);
+                return Ok(());
+            }
+
+            let eta = self
+                .env()
+                .block_timestamp()
+                .checked_add(delay)
+                .ok_or(Error::Overflow)?;
+            self.pending_address_updates.insert(kind, &PendingAddressUpdate { address, eta });
+
+            self.env().emit_event(AddressUpdateQueued { kind, address, eta });
+
+            Ok(())
+        }
+
+        fn update_address_update_delay(&mut self, delay: Timestamp) {
+            let old = self.get_address_update_delay();
+            self.address_update_delay.set(&delay);
+            self.pending_address_update_delay.set(&None);
+
+            self.env().emit_event(AddressUpdateDelayUpdated { old, new: delay });
+        }
+
+        fn update_address(&mut self, kind: AddressKind, address: AccountId) {
+            match kind {
+                AddressKind::Oracle => {
+                    let old = core::mem::replace(&mut self.oracle, address);
+                    self.env().emit_event(OracleUpdated { old, new: address });
+                }
+                AddressKind::Vault => {
+                    let old = core::mem::replace(&mut self.vault, address);
+                    self.env().emit_event(VaultUpdated { old, new: address });
+                }
+                AddressKind::Router => {
+                    let old = self.router.get();
+                    self.router.set(&address);
+                    self.env().emit_event(RouterUpdated { old, new: address });
+                }
+            }
+        }
+    }
+
+    impl AccessControlled for Manager {
+        #[ink(message)]
+        fn grant_role(&mut self, role: RoleType, account: AccountId) -> RoleResult {
+            self.access_control.grant_role(self.env().caller(), role, account)
+        }
+
+        #[ink(message)]
+        fn revoke_role(&mut self, role: RoleType, account: AccountId) -> RoleResult {
+            self.access_control.revoke_role(self.env().caller(), role, account)
+        }
+
+        #[ink(message)]
+        fn renounce_role(&mut self, role: RoleType) -> RoleResult {
+            self.access_control.renounce_role(self.env().caller(), role)
+        }
+
+        #[ink(message)]
+        fn has_role(&self, role: RoleType, account: AccountId) -> bool {
+            self.access_control.has_role(role, account)
+        }
+    }
+
+    // CROSS CONTRACT CALLS ARE NOT INCLUDED IN TESTS
+    // COMMENT CROSS CONTRACT CALLS BEFORE TESTING
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use access_control::RoleError;
+
+        #[ink::test]
+        pub fn open_position_works() {
+            let token = 1;
+            let position_id = 0;
+            let amount = 100;
+            let leverage = 10;
+            let fee = 10;
+            let vault = AccountId::from([0x1; 32]);
+            let mut manager = Manager::new(vault, AccountId::from([0x2; 32]));
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+
+            assert_eq!(
+                manager.open_position(token, amount, PositionType::LONG, leverage, accounts.alice),
+                Ok(())
+            );
+
+            let position = manager.get_position(accounts.alice, position_id).unwrap();
+
+            assert_eq!(position.token, token);
+            assert_eq!(position.amount, amount);
+            assert_eq!(position.leverage, leverage);
+            assert_eq!(position.position_value, amount * 1000);
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 1);
+        }
+
+        #[ink::test]
+        pub fn open_position_fails() {
+            let token = 1;
+            let position_id = 0;
+            let amount = 100;
+            let leverage = 10;
+            let fee = 10;
+            let vault = AccountId::from([0x1; 32]);
+            let mut manager = Manager::new(vault, AccountId::from([0x2; 32]));
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+
+            manager.open_position(token, amount, PositionType::LONG, leverage, accounts.alice);
+
+            assert_eq!(
+                manager.open_position(token, amount, PositionType::LONG, leverage, accounts.alice),
+                Err(Error::NonZeroAmount)
+            );
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 1);
+        }
+
+        #[ink::test]
+        pub fn update_position_works() {
+            let token = 1;
+            let position_id = 0;
+            let amount = 70;
+            let new_amount_1 = 100;
+            let leverage = 10;
+            let vault = AccountId::from([0x1; 32]);
+            let mut manager = Manager::new(vault, AccountId::from([0x2; 32]));
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            manager.open_position(token, amount, PositionType::LONG, leverage, accounts.alice);
+
+            assert_eq!(
+                manager.update_position(new_amount_1, position_id, accounts.alice),
+                Ok(())
+            );
+
+            let position = manager.get_position(accounts.alice, position_id).unwrap();
+
+            assert_eq!(position.position_value, amount * 1000 + 30 * 2000);
+
+            assert_eq!(position.amount, new_amount_1);
+
+            assert_eq!(
+                manager.update_position(amount, position_id, accounts.alice),
+                Ok(())
+            );
+
+            let position = manager.get_position(accounts.alice, position_id).unwrap();
+            assert_eq!(position.position_value, amount * 1000 + 30 * 2000 - 30 * 2000);
+
+
+            assert_eq!(position.amount, amount);
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 3);
+        }
+
+        #[ink::test]
+        pub fn update_position_fails() {
+            let token = 1;
+            let position_id = 0;
+            let amount = 100;
+            let leverage = 10;
+            let vault = AccountId::from([0x1; 32]);
+            let mut manager = Manager::new(vault, AccountId::from([0x2; 32]));
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+
+            assert_eq!(
+                manager.update_position(amount, position_id, accounts.alice),
+                Err(Error::NotFound)
+            );
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 0);
+        }
+
+        #[ink::test]
+        pub fn close_position_fails() {
+            let token = 1;
+            let position_id = 0;
+            let amount = 100;
+            let leverage = 10;
+            let vault = AccountId::from([0x1; 32]);
+            let mut manager = Manager::new(vault, AccountId::from([0x2; 32]));
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(
+                manager.close_position(position_id, accounts.alice),
+                Err(Error::NotFound)
+            );
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 0);
+
+            let position = manager.get_position(accounts.alice, position_id);
+
+            assert_eq!(position.unwrap_err(), Error::NotFound);
+        }
+
+        #[ink::test]
+        pub fn close_position_works() {
+            let token = 1;
+            let position_id = 0;
+            let amount = 100;
+            let leverage = 10;
+            let vault = AccountId::from([0x1; 32]);
+            let mut manager = Manager::new(vault, AccountId::from([0x2; 32]));
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let res =
+                manager.open_position(token, amount, PositionType::LONG, leverage, accounts.alice);
+
+            let res = manager.close_position(position_id, accounts.alice);
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 2);
+
+            let position = manager.get_position(accounts.alice, position_id);
+
+            assert_eq!(position.unwrap_err(), Error::NotFound);
+        }
+
+        #[ink::test]
+        pub fn contract_creation_works() {
+            let position_id = 0;
+            let vault = AccountId::from([0x1; 32]);
+            let long_total = 0;
+            let short_total = 0;
+            let mut manager = Manager::new(vault, AccountId::from([0x2; 32]));
+
+            assert_eq!(manager.position_id, position_id);
+            assert_eq!(manager.long_total, long_total);
+            assert_eq!(manager.short_total, short_total);
+            assert_eq!(manager.vault, vault);
+        }
+
+        #[ink::test]
+        pub fn reserve_amount_works() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let mut manager = Manager::new(vault, oracle);
+
+            assert_eq!(manager.reserve_amount(100, 10, &PositionType::LONG), Ok(1000));
+            assert_eq!(manager.reserve_amount(100, 10, &PositionType::SHORT), Ok(1000));
+
+            manager.reserve_factor_bps = 20_000;
+            assert_eq!(manager.reserve_amount(100, 10, &PositionType::LONG), Ok(2000));
+            assert_eq!(manager.reserve_amount(100, 10, &PositionType::SHORT), Ok(1000));
+
+            manager.reserve_factor_bps = 5_000;
+            assert_eq!(manager.reserve_amount(100, 10, &PositionType::LONG), Ok(500));
+        }
+
+        #[ink::test]
+        pub fn check_utilization_works() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let manager = Manager::new(vault, oracle);
+
+            // 80% cap on a pool of 10_000
+            assert_eq!(manager.check_utilization(0, 10_000, 8_000), Ok(()));
+            assert_eq!(manager.check_utilization(7_000, 3_000, 1_000), Ok(()));
+            assert_eq!(
+                manager.check_utilization(7_000, 3_000, 1_001),
+                Err(Error::InsufficientPoolLiquidity)
+            );
+            assert_eq!(
+                manager.check_utilization(0, 0, 1),
+                Err(Error::InsufficientPoolLiquidity)
+            );
+        }
+
+        #[ink::test]
+        pub fn fee_schedule_works() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let token = 1;
+            let schedule = FeeSchedule {
+                open_fee_bps: 10,
+                close_fee_bps: 20,
+                maintenance_fee_bps_per_hour: 1,
+            };
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
+
+            assert_eq!(manager.get_fee_schedule(token), FeeSchedule::default());
+            assert_eq!(manager.set_fee_schedule(token, schedule), Ok(()));
+            assert_eq!(manager.get_fee_schedule(token), schedule);
+            assert_eq!(manager.get_fee_schedule(token + 1), FeeSchedule::default());
+
+            assert_eq!(manager.set_default_fee_schedule(schedule), Ok(()));
+            assert_eq!(manager.get_fee_schedule(token + 1), schedule);
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 2);
+        }
+
+        #[ink::test]
+        pub fn fee_schedule_fails() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let schedule = FeeSchedule {
+                open_fee_bps: 10_001,
+                close_fee_bps: 0,
+                maintenance_fee_bps_per_hour: 0,
+            };
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
+
+            assert_eq!(manager.set_fee_schedule(1, schedule), Err(Error::InvalidParameter));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(
+                manager.set_fee_schedule(1, FeeSchedule::default()),
+                Err(Error::MissingRole)
+            );
+        }
+
+        #[ink::test]
+        pub fn calculate_fee_works() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let manager = Manager::new(vault, oracle);
+
+            // 10 bps of a 100 * 10 notional
+            assert_eq!(manager.calculate_fee(100, 10, 10), Ok(1));
+            assert_eq!(manager.calculate_fee(1_000_000, 5, 25), Ok(12_500));
+            assert_eq!(manager.calculate_fee(1_000_000, 5, 0), Ok(0));
+        }
+
+        #[ink::test]
+        pub fn volume_window_works() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut manager = Manager::new(vault, oracle);
+
+            assert_eq!(manager.record_volume(accounts.bob, 1000), Ok(()));
+            assert_eq!(manager.record_volume(accounts.bob, 500), Ok(()));
+            assert_eq!(manager.get_volume(accounts.bob), 1500);
+            assert_eq!(manager.get_volume(accounts.charlie), 0);
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(10 * MILLIS_PER_DAY);
+            assert_eq!(manager.record_volume(accounts.bob, 200), Ok(()));
+            assert_eq!(manager.get_volume(accounts.bob), 1700);
+
+            // day 0 leaves the window 30 days later
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(30 * MILLIS_PER_DAY - 1);
+            assert_eq!(manager.get_volume(accounts.bob), 1700);
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(30 * MILLIS_PER_DAY);
+            assert_eq!(manager.get_volume(accounts.bob), 200);
+
+            assert_eq!(manager.record_volume(accounts.bob, 100), Ok(()));
+            assert_eq!(manager.trader_volumes.get(accounts.bob).unwrap().len(), 2);
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(60 * MILLIS_PER_DAY);
+            assert_eq!(manager.get_volume(accounts.bob), 0);
+        }
+
+        #[ink::test]
+        pub fn fee_tiers_work() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let tiers = vec![
+                FeeTier {
+                    min_volume: 1_000,
+                    discount_bps: 2,
+                },
+                FeeTier {
+                    min_volume: 10_000,
+                    discount_bps: 5,
+                },
+            ];
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
+            assert_eq!(manager.set_fee_tiers(tiers.clone()), Ok(()));
+            assert_eq!(manager.get_fee_tiers(), tiers);
+
+            assert_eq!(manager.fee_tier(accounts.bob), FeeTier::default());
+            assert_eq!(manager.tiered_fee_bps(accounts.bob, 10), 10);
+
+            assert_eq!(manager.record_volume(accounts.bob, 1_000), Ok(()));
+            assert_eq!(manager.fee_tier(accounts.bob), tiers[0]);
+            assert_eq!(manager.tiered_fee_bps(accounts.bob, 10), 8);
+
+            assert_eq!(manager.record_volume(accounts.bob, 9_000), Ok(()));
+            assert_eq!(manager.fee_tier(accounts.bob), tiers[1]);
+            assert_eq!(manager.tiered_fee_bps(accounts.bob, 10), 5);
+            assert_eq!(manager.tiered_fee_bps(accounts.bob, 3), 0);
+        }
+
+        #[ink::test]
+        pub fn fee_tiers_fail() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let tier = FeeTier {
+                min_volume: 1_000,
+                discount_bps: 2,
+            };
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
+            assert_eq!(manager.set_fee_tiers(vec![tier, tier]), Err(Error::InvalidParameter));
+            assert_eq!(
+                manager.set_fee_tiers(vec![FeeTier {
+                    min_volume: 0,
+                    discount_bps: 10_001,
+                }]),
+                Err(Error::InvalidParameter)
+            );
+            assert_eq!(
+                manager.set_fee_tiers(vec![FeeTier::default(); MAX_FEE_TIERS + 1]),
+                Err(Error::InvalidParameter)
+            );
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(manager.set_fee_tiers(vec![tier]), Err(Error::MissingRole));
+            assert!(manager.get_fee_tiers().is_empty());
+        }
+
+        #[ink::test]
+        pub fn referral_codes_work() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let code = [0x7; 32];
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
+
+            assert_eq!(manager.register_code([0; 32]), Err(Error::InvalidParameter));
+            assert_eq!(manager.set_referral_code(code), Err(Error::CodeNotFound));
+            assert_eq!(manager.register_code(code), Ok(()));
+            assert_eq!(manager.get_code_owner(code), Some(accounts.alice));
+            assert_eq!(manager.set_referral_code(code), Err(Error::InvalidParameter));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(manager.register_code(code), Err(Error::CodeTaken));
+            assert_eq!(manager.set_referral_code(code), Ok(()));
+            assert_eq!(manager.get_referral_code(accounts.bob), Some(code));
+            assert_eq!(manager.get_referral_code(accounts.charlie), None);
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 2);
+        }
+
+        #[ink::test]
+        pub fn referral_discount_works() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let code = [0x7; 32];
+            let gold = ReferralTier {
+                discount_bps: 1_000,
+                rebate_bps: 2_000,
+            };
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
+            assert_eq!(manager.register_code(code), Ok(()));
+
+            // traders without a code pay the full fee
+            assert_eq!(manager.apply_referral(accounts.bob, 1000), Ok((1000, None)));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(manager.set_referral_code(code), Ok(()));
+            assert_eq!(manager.apply_referral(accounts.bob, 1000), Ok((1000, None)));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(
+                manager.set_referral_tier(0, ReferralTier { discount_bps: 500, rebate_bps: 1_000 }),
+                Ok(())
+            );
+            assert_eq!(manager.set_referral_tier(1, gold), Ok(()));
+            assert_eq!(
+                manager.apply_referral(accounts.bob, 1000),
+                Ok((950, Some((accounts.alice, 100))))
+            );
+
+            assert_eq!(manager.set_referrer_tier(accounts.alice, 1), Ok(()));
+            assert_eq!(manager.get_referrer_tier(accounts.alice), 1);
+            assert_eq!(
+                manager.apply_referral(accounts.bob, 1000),
+                Ok((900, Some((accounts.alice, 200))))
+            );
+            assert_eq!(manager.apply_referral(accounts.bob, 1), Ok((1, None)));
+        }
+
+        #[ink::test]
+        pub fn referral_settings_fail() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let tier = ReferralTier {
+                discount_bps: 6_000,
+                rebate_bps: 5_000,
+            };
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
+            assert_eq!(manager.set_referral_tier(0, tier), Err(Error::InvalidParameter));
+            assert_eq!(manager.get_referral_tier(0), ReferralTier::default());
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(manager.set_referral_tier(0, ReferralTier::default()), Err(Error::MissingRole));
+            assert_eq!(manager.set_referrer_tier(accounts.bob, 1), Err(Error::MissingRole));
+        }
+
+        fn insert_position(
+            manager: &mut Manager,
+            user: AccountId,
+            position_id: PositionId,
+            position_type: PositionType,
+            amount: Balance,
+            leverage: u32,
+        ) {
+            let now: u128 = ink::env::block_timestamp::<ink::env::DefaultEnvironment>().into();
+            manager.track_position(user, position_id, &position_type);
+            let position = Position {
+                state: true,
+                token: 1,
+                amount,
+                position_type,
+                leverage,
+                position_value: amount * 1000,
+                creation_time: now,
+                reserved: 0,
+                last_fee_time: now,
+            };
+            manager.positions.insert((user, position_id), &position);
+        }
 
-            let funding_rate = ff
-                .wrapping_mul(oii as u32)
-                .checked_div(toi as u32)
-                .ok_or(Error::Underflow)?;
+        #[ink::test]
+        pub fn maintenance_fee_accrues_over_time() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
 
-            Ok(funding_rate)
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
+            manager
+                .set_default_fee_schedule(FeeSchedule {
+                    open_fee_bps: 0,
+                    close_fee_bps: 0,
+                    maintenance_fee_bps_per_hour: 100,
+                })
+                .unwrap();
+
+            insert_position(&mut manager, accounts.alice, 0, PositionType::LONG, 1_000_000_000, 10);
+            assert_eq!(manager.get_accrued_maintenance_fee(accounts.alice, 0), Ok(0));
+
+            // one block of 6 ms on a notional of 1e10 at 1% per hour
+            ink::env::test::advance_block::<ink::env::DefaultEnvironment>();
+            assert_eq!(manager.get_accrued_maintenance_fee(accounts.alice, 0), Ok(166));
+
+            ink::env::test::advance_block::<ink::env::DefaultEnvironment>();
+            assert_eq!(manager.get_accrued_maintenance_fee(accounts.alice, 0), Ok(333));
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(MILLIS_PER_HOUR as u64);
+            assert_eq!(manager.get_accrued_maintenance_fee(accounts.alice, 0), Ok(100_000_000));
         }
-    }
-
-    // CROSS CONTRACT CALLS ARE NOT INCLUDED IN TESTS
-    // COMMENT CROSS CONTRACT CALLS BEFORE TESTING
-    #[cfg(test)]
-    mod tests {
-        use super::*;
 
         #[ink::test]
-        pub fn open_position_works() {
-            let token = 1;
-            let position_id = 0;
-            let amount = 100;
-            let leverage = 10;
-            let fee = 10;
+        pub fn settle_maintenance_fee_is_idempotent() {
             let vault = AccountId::from([0x1; 32]);
-            let mut manager = Manager::new(vault);
+            let oracle = AccountId::from([0x2; 32]);
             let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
 
             ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
+            manager.access_control.grant(KEEPER, accounts.alice);
+            manager
+                .set_default_fee_schedule(FeeSchedule {
+                    open_fee_bps: 0,
+                    close_fee_bps: 0,
+                    maintenance_fee_bps_per_hour: 100,
+                })
+                .unwrap();
 
-            assert_eq!(
-                manager.open_position(token, amount, PositionType::LONG, leverage, accounts.alice),
-                Ok(())
-            );
+            insert_position(&mut manager, accounts.alice, 0, PositionType::LONG, 1_000_000_000, 10);
+            ink::env::test::advance_block::<ink::env::DefaultEnvironment>();
 
-            let position = manager.get_position(accounts.alice, position_id).unwrap();
+            // CROSS CONTRACT CALLS ARE NOT INCLUDED IN TESTS
+            let (_, fee) = manager.record_maintenance_fee(accounts.alice, 0).unwrap();
+            assert_eq!(fee, 166);
 
-            assert_eq!(position.token, token);
-            assert_eq!(position.amount, amount);
-            assert_eq!(position.leverage, leverage);
-            assert_eq!(position.position_value, amount * 1000);
+            assert_eq!(manager.settle_maintenance_fee(0, accounts.alice), Ok(0));
+            assert_eq!(manager.get_accrued_maintenance_fee(accounts.alice, 0), Ok(0));
+
+            let position = manager.get_position(accounts.alice, 0).unwrap();
+            assert_eq!(position.amount, 1_000_000_000 - 166);
 
             let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
             assert_eq!(emitted_events.len(), 1);
         }
 
         #[ink::test]
-        pub fn open_position_fails() {
-            let token = 1;
-            let position_id = 0;
-            let amount = 100;
-            let leverage = 10;
-            let fee = 10;
+        pub fn maintenance_fee_keeps_entry_price() {
             let vault = AccountId::from([0x1; 32]);
-            let mut manager = Manager::new(vault);
+            let oracle = AccountId::from([0x2; 32]);
             let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
 
             ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
+            manager
+                .set_default_fee_schedule(FeeSchedule {
+                    open_fee_bps: 0,
+                    close_fee_bps: 0,
+                    maintenance_fee_bps_per_hour: 100,
+                })
+                .unwrap();
+
+            insert_position(&mut manager, accounts.alice, 0, PositionType::LONG, 100, 10);
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(MILLIS_PER_HOUR as u64);
+
+            // CROSS CONTRACT CALLS ARE NOT INCLUDED IN TESTS
+            let (position, fee) = manager.record_maintenance_fee(accounts.alice, 0).unwrap();
+            assert_eq!(fee, 10);
+            assert_eq!(position.amount, 90);
+            assert_eq!(position.position_value, 90 * 1000);
+            assert_eq!(Manager::entry_price(&position), Ok(1000));
+            assert_eq!(position.leverage, 10);
+        }
 
-            manager.open_position(token, amount, PositionType::LONG, leverage, accounts.alice);
+        #[ink::test]
+        pub fn settle_maintenance_fee_fails() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut manager = Manager::new(vault, oracle);
+            manager.access_control.grant(KEEPER, accounts.alice);
 
             assert_eq!(
-                manager.open_position(token, amount, PositionType::LONG, leverage, accounts.alice),
-                Err(Error::NonZeroAmount)
+                manager.settle_maintenance_fee(0, accounts.alice),
+                Err(Error::NotFound)
             );
+        }
 
-            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
-            assert_eq!(emitted_events.len(), 1);
+        #[ink::test]
+        pub fn leverage_after_margin_works() {
+            let position = Position {
+                state: true,
+                token: 1,
+                amount: 100,
+                position_type: PositionType::LONG,
+                leverage: 10,
+                position_value: 100 * 1000,
+                creation_time: 0,
+                reserved: 0,
+                last_fee_time: 0,
+            };
+
+            assert_eq!(Manager::leverage_after_margin(&position, 200), Ok(5));
+            assert_eq!(Manager::leverage_after_margin(&position, 300), Ok(3));
+            assert_eq!(Manager::leverage_after_margin(&position, 5000), Ok(1));
         }
 
         #[ink::test]
-        pub fn update_position_works() {
-            let token = 1;
-            let position_id = 0;
-            let amount = 70;
-            let new_amount_1 = 100;
-            let leverage = 10;
+        pub fn add_margin_fails() {
             let vault = AccountId::from([0x1; 32]);
-            let mut manager = Manager::new(vault);
+            let oracle = AccountId::from([0x2; 32]);
             let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
 
             ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
-            manager.open_position(token, amount, PositionType::LONG, leverage, accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
+            insert_position(&mut manager, accounts.alice, 0, PositionType::LONG, 100, 10);
+
+            assert_eq!(manager.add_margin(0, 0, accounts.alice), Err(Error::ZeroAmount));
+            assert_eq!(manager.add_margin(1, 50, accounts.alice), Err(Error::NotFound));
+            assert_eq!(manager.add_margin(0, 50, accounts.bob), Err(Error::Unauthorized));
+        }
+
+        #[ink::test]
+        pub fn trading_only_user_or_router() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let token = 1;
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
+            insert_position(&mut manager, accounts.alice, 0, PositionType::LONG, 100, 10);
+            assert_eq!(manager.set_router(accounts.charlie), Ok(()));
+            assert_eq!(manager.get_router(), Some(accounts.charlie));
 
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(manager.set_router(accounts.bob), Err(Error::MissingRole));
             assert_eq!(
-                manager.update_position(new_amount_1, position_id, accounts.alice),
-                Ok(())
+                manager.open_position(token, 100, PositionType::LONG, 10, accounts.alice),
+                Err(Error::Unauthorized)
             );
+            assert_eq!(manager.update_position(50, 0, accounts.alice), Err(Error::Unauthorized));
+            assert_eq!(manager.close_position(0, accounts.alice), Err(Error::Unauthorized));
 
-            let position = manager.get_position(accounts.alice, position_id).unwrap();
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.charlie);
+            assert_eq!(manager.check_user(accounts.alice, TradeAction::Open), Ok(()));
+        }
 
-            assert_eq!(position.position_value, amount * 1000 + 30 * 2000);
+        #[ink::test]
+        pub fn operators_work() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let permissions = OperatorPermissions {
+                open: false,
+                close: true,
+                modify: true,
+            };
 
-            assert_eq!(position.amount, new_amount_1);
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
+            insert_position(&mut manager, accounts.alice, 0, PositionType::LONG, 100, 10);
+            assert_eq!(manager.approve_operator(accounts.bob, permissions, 1000), Ok(()));
+            assert_eq!(
+                manager.get_operator(accounts.alice, accounts.bob),
+                Some(OperatorApproval {
+                    permissions,
+                    expiry: 1000,
+                })
+            );
 
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(manager.check_user(accounts.alice, TradeAction::Close), Ok(()));
+            assert_eq!(manager.check_user(accounts.alice, TradeAction::Modify), Ok(()));
             assert_eq!(
-                manager.update_position(amount, position_id, accounts.alice),
-                Ok(())
+                manager.open_position(1, 100, PositionType::LONG, 10, accounts.alice),
+                Err(Error::Unauthorized)
             );
+            assert_eq!(manager.add_margin(0, 0, accounts.alice), Err(Error::ZeroAmount));
 
-            let position = manager.get_position(accounts.alice, position_id).unwrap();
-            assert_eq!(position.position_value, amount * 1000 + 30 * 2000 - 30 * 2000);
+            // approvals are per account and expire
+            assert_eq!(manager.check_user(accounts.charlie, TradeAction::Close), Err(Error::Unauthorized));
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(1000);
+            assert_eq!(manager.close_position(0, accounts.alice), Err(Error::Unauthorized));
 
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(manager.approve_operator(accounts.bob, permissions, 2000), Ok(()));
+            assert_eq!(manager.revoke_operator(accounts.bob), Ok(()));
+            assert_eq!(manager.revoke_operator(accounts.bob), Err(Error::NotFound));
 
-            assert_eq!(position.amount, amount);
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(manager.check_user(accounts.alice, TradeAction::Close), Err(Error::Unauthorized));
 
             let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
             assert_eq!(emitted_events.len(), 3);
         }
 
         #[ink::test]
-        pub fn update_position_fails() {
-            let token = 1;
-            let position_id = 0;
-            let amount = 100;
-            let leverage = 10;
+        pub fn approve_operator_fails() {
             let vault = AccountId::from([0x1; 32]);
-            let mut manager = Manager::new(vault);
+            let oracle = AccountId::from([0x2; 32]);
             let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
 
             ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
 
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(1000);
             assert_eq!(
-                manager.update_position(amount, position_id, accounts.alice),
+                manager.approve_operator(accounts.bob, OperatorPermissions::default(), 1000),
+                Err(Error::InvalidParameter)
+            );
+            assert_eq!(
+                manager.approve_operator(accounts.alice, OperatorPermissions::default(), 2000),
+                Err(Error::InvalidParameter)
+            );
+            assert_eq!(manager.get_operator(accounts.alice, accounts.bob), None);
+        }
+
+        #[ink::test]
+        pub fn multicall_fails() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let token = 1;
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
+            insert_position(&mut manager, accounts.alice, 0, PositionType::LONG, 100, 10);
+
+            assert_eq!(manager.multicall(Vec::new()), Ok(Vec::new()));
+            assert_eq!(
+                manager.multicall(vec![
+                    Call::AddMargin {
+                        position_id: 0,
+                        amount: 0,
+                    },
+                    Call::ClosePosition { position_id: 0 },
+                ]),
+                Err(Error::ZeroAmount)
+            );
+            // the batch stopped at the first failure
+            assert!(manager.get_position(accounts.alice, 0).is_ok());
+
+            assert_eq!(manager.pause(Some(token), PauseState::OpeningPaused), Ok(()));
+            assert_eq!(
+                manager.multicall(vec![Call::OpenPosition {
+                    token,
+                    amount: 100,
+                    position_type: PositionType::SHORT,
+                    leverage: 10,
+                }]),
+                Err(Error::Paused)
+            );
+
+            // calls act on the caller's own positions
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(
+                manager.multicall(vec![Call::ClosePosition { position_id: 0 }]),
                 Err(Error::NotFound)
             );
+        }
+
+        #[ink::test]
+        pub fn pnl_works() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut manager = Manager::new(vault, oracle);
+
+            insert_position(&mut manager, accounts.alice, 0, PositionType::LONG, 100, 10);
+            insert_position(&mut manager, accounts.bob, 0, PositionType::SHORT, 100, 10);
+            let long = manager.get_position(accounts.alice, 0).unwrap();
+            let short = manager.get_position(accounts.bob, 0).unwrap();
+
+            // size 1000 at entry price 1000
+            assert_eq!(manager.pnl(&long, 1100), Ok((100, 0)));
+            assert_eq!(manager.pnl(&long, 850), Ok((0, 150)));
+            assert_eq!(manager.pnl(&short, 1100), Ok((0, 100)));
+            assert_eq!(manager.pnl(&short, 850), Ok((150, 0)));
+
+            assert_eq!(manager.loss(&long, 900), Ok(long.amount));
+        }
+
+        #[ink::test]
+        pub fn adl_rank_works() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut manager = Manager::new(vault, oracle);
+
+            insert_position(&mut manager, accounts.alice, 0, PositionType::LONG, 100, 2);
+            insert_position(&mut manager, accounts.bob, 0, PositionType::LONG, 100, 10);
+            insert_position(&mut manager, accounts.charlie, 0, PositionType::LONG, 100, 5);
+            insert_position(&mut manager, accounts.django, 0, PositionType::SHORT, 100, 20);
+
+            let candidates = manager.adl_candidates(&PositionType::LONG, 1, 1100, 0, MAX_ADL_SCAN);
+            assert!(manager.adl_candidates(&PositionType::LONG, 2, 1100, 0, MAX_ADL_SCAN).is_empty());
+            let order: Vec<AccountId> = candidates.iter().map(|(user, _, _)| *user).collect();
+            assert_eq!(order, vec![accounts.bob, accounts.charlie, accounts.alice]);
+
+            // a bounded scan wraps around at the last slot
+            let candidates = manager.adl_candidates(&PositionType::LONG, 1, 1100, 2, 2);
+            let order: Vec<AccountId> = candidates.iter().map(|(user, _, _)| *user).collect();
+            assert_eq!(order, vec![accounts.charlie, accounts.alice]);
+
+            assert_eq!(manager.adl_rank(accounts.bob, 0, 1100), Ok(5));
+            assert_eq!(manager.adl_rank(accounts.charlie, 0, 1100), Ok(4));
+            assert_eq!(manager.adl_rank(accounts.alice, 0, 1100), Ok(2));
+            // losing positions are never deleveraged
+            assert_eq!(manager.adl_rank(accounts.django, 0, 1100), Ok(0));
+            assert_eq!(manager.adl_rank(accounts.bob, 0, 900), Ok(0));
+            assert_eq!(manager.adl_rank(accounts.eve, 0, 1100), Err(Error::NotFound));
+        }
+
+        #[ink::test]
+        pub fn uncovered_deficit_is_recorded_as_bad_debt() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut manager = Manager::new(vault, oracle);
+
+            // no profitable short is left to deleverage
+            insert_position(&mut manager, accounts.alice, 0, PositionType::SHORT, 100, 10);
+            assert_eq!(manager.auto_deleverage(&PositionType::SHORT, 1, 50, 1100), Ok(50));
+
+            assert_eq!(manager.record_bad_debt(50), Ok(()));
+            assert_eq!(manager.record_bad_debt(20), Ok(()));
+            assert_eq!(manager.get_bad_debt(), 70);
 
             let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
-            assert_eq!(emitted_events.len(), 0);
+            assert_eq!(emitted_events.len(), 2);
         }
 
         #[ink::test]
-        pub fn close_position_fails() {
-            let token = 1;
-            let position_id = 0;
-            let amount = 100;
-            let leverage = 10;
+        pub fn open_positions_index_works() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut manager = Manager::new(vault, oracle);
+
+            insert_position(&mut manager, accounts.alice, 0, PositionType::LONG, 100, 2);
+            insert_position(&mut manager, accounts.alice, 1, PositionType::LONG, 100, 2);
+            insert_position(&mut manager, accounts.bob, 0, PositionType::LONG, 100, 2);
+            insert_position(&mut manager, accounts.bob, 1, PositionType::SHORT, 100, 2);
+            manager.untrack_position(accounts.alice, 0, &PositionType::LONG);
+            manager.untrack_position(accounts.alice, 0, &PositionType::LONG);
+
+            let long = Manager::side_key(&PositionType::LONG);
+            let short = Manager::side_key(&PositionType::SHORT);
+            assert_eq!(manager.open_position_counts.get(long), Some(2));
+            assert_eq!(manager.open_positions.get((long, 0)), Some((accounts.bob, 0)));
+            assert_eq!(manager.open_positions.get((long, 1)), Some((accounts.alice, 1)));
+            assert_eq!(manager.open_positions.get((long, 2)), None);
+            assert_eq!(manager.open_position_slots.get((accounts.bob, 0)), Some(0));
+            assert_eq!(manager.open_position_counts.get(short), Some(1));
+        }
+
+        #[ink::test]
+        pub fn pause_works() {
             let vault = AccountId::from([0x1; 32]);
-            let mut manager = Manager::new(vault);
+            let oracle = AccountId::from([0x2; 32]);
             let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let token = 1;
 
             ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
+            manager.access_control.grant(GUARDIAN, accounts.bob);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(manager.pause(Some(token), PauseState::OpeningPaused), Ok(()));
+            assert_eq!(manager.get_pause_state(token), PauseState::OpeningPaused);
+            assert_eq!(manager.get_pause_state(2), PauseState::Active);
+            assert_eq!(manager.check_market(token, PauseState::Active), Err(Error::Paused));
+            assert_eq!(manager.check_market(token, PauseState::CloseOnly), Ok(()));
             assert_eq!(
-                manager.close_position(position_id, accounts.alice),
-                Err(Error::NotFound)
+                manager.open_position(token, 100, PositionType::LONG, 10, accounts.bob),
+                Err(Error::Paused)
             );
 
+            // the stricter of the global and the market state applies
+            assert_eq!(manager.pause(None, PauseState::Paused), Ok(()));
+            assert_eq!(manager.get_pause_state(token), PauseState::Paused);
+            assert_eq!(manager.get_pause_state(2), PauseState::Paused);
+            assert_eq!(manager.check_market(2, PauseState::CloseOnly), Err(Error::Paused));
+
+            assert_eq!(manager.unpause(None), Err(Error::MissingRole));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(manager.unpause(None), Ok(()));
+            assert_eq!(manager.get_pause_state(token), PauseState::OpeningPaused);
+            assert_eq!(manager.unpause(Some(token)), Ok(()));
+            assert_eq!(manager.get_pause_state(token), PauseState::Active);
+
             let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
-            assert_eq!(emitted_events.len(), 0);
+            assert_eq!(emitted_events.len(), 4);
+        }
 
-            let position = manager.get_position(accounts.alice, position_id);
+        #[ink::test]
+        pub fn pause_fails() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
 
-            assert_eq!(position.unwrap_err(), Error::NotFound);
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
+
+            assert_eq!(manager.pause(None, PauseState::Active), Err(Error::InvalidParameter));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.charlie);
+            assert_eq!(manager.pause(None, PauseState::Paused), Err(Error::MissingRole));
+            assert_eq!(manager.grant_role(GUARDIAN, accounts.charlie), Err(RoleError::MissingRole));
+            assert_eq!(manager.set_max_price_move_bps(500), Err(Error::MissingRole));
+            assert_eq!(manager.trip_circuit_breaker(1), Err(Error::MissingRole));
+            assert_eq!(manager.liquidation(0, accounts.alice), Err(Error::MissingRole));
         }
 
         #[ink::test]
-        pub fn close_position_works() {
-            let token = 1;
-            let position_id = 0;
-            let amount = 100;
-            let leverage = 10;
+        pub fn exceeds_price_move_works() {
+            let vault = AccountId::from([0x1; 32]);
+            let oracle = AccountId::from([0x2; 32]);
+            let mut manager = Manager::new(vault, oracle);
+
+            // disabled by default
+            assert!(!manager.exceeds_price_move(u32::MAX));
+            assert_eq!(manager.check_circuit_breaker(1), Ok(()));
+
+            manager.set_max_price_move_bps(500).unwrap();
+            assert!(!manager.exceeds_price_move(500));
+            assert!(manager.exceeds_price_move(501));
+        }
+
+        #[ink::test]
+        pub fn utilization_settings_only_owner() {
             let vault = AccountId::from([0x1; 32]);
-            let mut manager = Manager::new(vault);
+            let oracle = AccountId::from([0x2; 32]);
             let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
 
             ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
-            let res =
-                manager.open_position(token, amount, PositionType::LONG, leverage, accounts.alice);
+            let mut manager = Manager::new(vault, oracle);
 
-            let res = manager.close_position(position_id, accounts.alice);
+            assert_eq!(manager.set_max_utilization_bps(9_000), Ok(()));
+            assert_eq!(manager.get_max_utilization_bps(), 9_000);
+            assert_eq!(manager.set_max_utilization_bps(10_001), Err(Error::InvalidParameter));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(manager.set_reserve_factor_bps(1), Err(Error::MissingRole));
+            assert_eq!(manager.get_reserve_factor_bps(), DEFAULT_RESERVE_FACTOR_BPS);
+        }
+
+        #[ink::test]
+        pub fn upgrade_only_owner() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(AccountId::from([0x1; 32]), AccountId::from([0x2; 32]));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(manager.upgrade(Hash::from([0x42; 32])), Err(Error::NotOwner));
+            assert_eq!(manager.migrate(), Err(Error::NotOwner));
+        }
+
+        #[ink::test]
+        pub fn migrate_current_version_fails() {
+            let mut manager = Manager::new(AccountId::from([0x1; 32]), AccountId::from([0x2; 32]));
+            assert_eq!(manager.get_version(), STORAGE_VERSION);
+            assert_eq!(manager.migrate(), Err(Error::AlreadyMigrated));
+        }
+
+        mod v2 {
+            use access_control::{AccessControl, Ownership};
+            use ink::prelude::vec::Vec;
+            use ink::primitives::AccountId;
+            use ink::storage::{Lazy, Mapping};
+
+            use super::{
+                AddressKind, FeeSchedule, FeeTier, OperatorApproval, PauseState,
+                PendingAddressUpdate, PendingDelayUpdate, Position, PositionId, ReferralCode,
+                ReferralTier, TokenId,
+            };
+
+            type Balance = u128;
+            type Timestamp = u64;
+
+            /// Manager layout of a later version: the v1 fields in the same order
+            /// with a `Lazy` one appended.
+            #[ink::storage_item]
+            pub struct Manager {
+                pub positions: Mapping<(AccountId, PositionId), Position>,
+                pub position_id: PositionId,
+                pub oracle: AccountId,
+                pub vault: AccountId,
+                pub long_total: Balance,
+                pub short_total: Balance,
+                pub ownership: Ownership,
+                pub max_utilization_bps: u32,
+                pub reserve_factor_bps: u32,
+                pub fee_schedules: Mapping<TokenId, FeeSchedule>,
+                pub default_fee_schedule: FeeSchedule,
+                pub open_positions: Mapping<(u8, u32), (AccountId, PositionId)>,
+                pub open_position_counts: Mapping<u8, u32>,
+                pub open_position_slots: Mapping<(AccountId, PositionId), u32>,
+                pub pause_state: PauseState,
+                pub market_pause_states: Mapping<TokenId, PauseState>,
+                pub max_price_move_bps: u32,
+                pub version: u32,
+                pub access_control: AccessControl,
+                pub address_update_delay: Lazy<Timestamp>,
+                pub pending_address_updates: Mapping<AddressKind, PendingAddressUpdate>,
+                pub referral_code_owners: Mapping<ReferralCode, AccountId>,
+                pub trader_referral_codes: Mapping<AccountId, ReferralCode>,
+                pub referral_tiers: Mapping<u8, ReferralTier>,
+                pub referrer_tiers: Mapping<AccountId, u8>,
+                pub fee_tiers: Lazy<Vec<FeeTier>>,
+                pub trader_volumes: Mapping<AccountId, Vec<(Timestamp, Balance)>>,
+                pub operators: Mapping<(AccountId, AccountId), OperatorApproval>,
+                pub bad_debt: Lazy<Balance>,
+                pub pending_address_update_delay: Lazy<Option<PendingDelayUpdate>>,
+                pub router: Lazy<AccountId>,
+                pub adl_cursors: Mapping<u8, u32>,
+                pub max_open_interest: Lazy<Balance>,
+            }
+        }
+
+        #[ink::test]
+        pub fn storage_survives_upgrade() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let root_key = <Manager as ink::storage::traits::StorageKey>::KEY;
+            let tiers = vec![FeeTier {
+                min_volume: 1_000,
+                discount_bps: 10,
+            }];
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(AccountId::from([0x1; 32]), AccountId::from([0x2; 32]));
+            insert_position(&mut manager, accounts.bob, 0, PositionType::LONG, 1000, 2);
+            manager.position_id = 1;
+            manager.long_total = 1000;
+            assert_eq!(manager.set_fee_tiers(tiers.clone()), Ok(()));
+            assert_eq!(manager.record_bad_debt(5), Ok(()));
+            ink::env::set_contract_storage(&root_key, &manager);
+
+            let upgraded = ink::env::get_contract_storage::<_, v2::Manager>(&root_key)
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(upgraded.vault, AccountId::from([0x1; 32]));
+            assert_eq!(upgraded.oracle, AccountId::from([0x2; 32]));
+            assert_eq!(upgraded.position_id, 1);
+            assert_eq!(upgraded.long_total, 1000);
+            assert_eq!(upgraded.positions.get((accounts.bob, 0)).map(|p| p.amount), Some(1000));
+            assert_eq!(upgraded.open_positions.get((0, 0)), Some((accounts.bob, 0)));
+            assert_eq!(upgraded.fee_tiers.get(), Some(tiers));
+            assert_eq!(upgraded.bad_debt.get(), Some(5));
+            assert_eq!(upgraded.ownership.owner(), Some(accounts.alice));
+            assert_eq!(upgraded.version, STORAGE_VERSION);
+            assert!(upgraded.access_control.has_role(ADMIN, accounts.alice));
+            assert_eq!(upgraded.max_open_interest.get(), None);
+        }
+
+        #[ink::test]
+        pub fn roles_work() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(AccountId::from([0x1; 32]), AccountId::from([0x2; 32]));
+            assert!(manager.has_role(ADMIN, accounts.alice));
+            assert!(!manager.has_role(KEEPER, accounts.bob));
+
+            assert_eq!(manager.grant_role(KEEPER, accounts.bob), Ok(()));
+            assert!(manager.has_role(KEEPER, accounts.bob));
+            assert_eq!(manager.revoke_role(KEEPER, accounts.bob), Ok(()));
+            assert!(!manager.has_role(KEEPER, accounts.bob));
+
+            assert_eq!(manager.grant_role(KEEPER, accounts.bob), Ok(()));
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(manager.grant_role(ADMIN, accounts.bob), Err(RoleError::MissingRole));
+            assert_eq!(manager.revoke_role(KEEPER, accounts.bob), Err(RoleError::MissingRole));
+            assert_eq!(manager.renounce_role(KEEPER), Ok(()));
+            assert!(!manager.has_role(KEEPER, accounts.bob));
+            assert_eq!(manager.renounce_role(KEEPER), Err(RoleError::MissingRole));
 
             let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
-            assert_eq!(emitted_events.len(), 2);
+            assert_eq!(emitted_events.len(), 4);
+        }
 
-            let position = manager.get_position(accounts.alice, position_id);
+        #[ink::test]
+        pub fn ownership_transfer_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
 
-            assert_eq!(position.unwrap_err(), Error::NotFound);
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(AccountId::from([0x1; 32]), AccountId::from([0x2; 32]));
+            assert_eq!(manager.transfer_ownership(accounts.bob), Ok(()));
+            assert_eq!(manager.owner(), Some(accounts.alice));
+            assert_eq!(manager.pending_owner(), Some(accounts.bob));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.charlie);
+            assert_eq!(manager.accept_ownership(), Err(Error::NotPendingOwner));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(manager.accept_ownership(), Ok(()));
+            assert_eq!(manager.owner(), Some(accounts.bob));
+            assert_eq!(manager.pending_owner(), None);
+            assert!(!manager.has_role(ADMIN, accounts.alice));
+            assert!(manager.has_role(ADMIN, accounts.bob));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(manager.transfer_ownership(accounts.alice), Err(Error::NotOwner));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(manager.renounce_ownership(), Ok(()));
+            assert_eq!(manager.owner(), None);
+            assert!(!manager.has_role(ADMIN, accounts.bob));
+            assert_eq!(manager.migrate(), Err(Error::NotOwner));
         }
 
         #[ink::test]
-        pub fn contract_creation_works() {
-            let position_id = 0;
-            let vault = AccountId::from([0x1; 32]);
-            let long_total = 0;
-            let short_total = 0;
-            let mut manager = Manager::new(vault);
+        pub fn address_update_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let new_address = AccountId::from([0x9; 32]);
 
-            assert_eq!(manager.position_id, position_id);
-            assert_eq!(manager.long_total, long_total);
-            assert_eq!(manager.short_total, short_total);
-            assert_eq!(manager.vault, vault);
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(AccountId::from([0x1; 32]), AccountId::from([0x2; 32]));
+
+            assert_eq!(manager.set_oracle(new_address), Ok(()));
+            assert_eq!(manager.get_oracle(), new_address);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(manager.set_oracle(accounts.bob), Err(Error::MissingRole));
+            assert_eq!(manager.set_address_update_delay(0), Err(Error::MissingRole));
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 1);
+        }
+
+        #[ink::test]
+        pub fn delayed_address_update_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let old_address = AccountId::from([0x8; 32]);
+            let new_address = AccountId::from([0x9; 32]);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(AccountId::from([0x1; 32]), AccountId::from([0x2; 32]));
+            manager.set_oracle(old_address).unwrap();
+            manager.set_address_update_delay(1000).unwrap();
+
+            assert_eq!(manager.set_oracle(new_address), Ok(()));
+            assert_eq!(manager.get_oracle(), old_address);
+            assert_eq!(
+                manager.get_pending_address_update(AddressKind::Oracle),
+                Some(PendingAddressUpdate { address: new_address, eta: 1000 })
+            );
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(manager.apply_address_update(AddressKind::Oracle), Err(Error::UpdateNotReady));
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(1000);
+            assert_eq!(manager.apply_address_update(AddressKind::Oracle), Ok(()));
+            assert_eq!(manager.get_oracle(), new_address);
+            assert_eq!(manager.apply_address_update(AddressKind::Oracle), Err(Error::NotFound));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(manager.set_oracle(old_address), Ok(()));
+            assert_eq!(manager.cancel_address_update(AddressKind::Oracle), Ok(()));
+            assert_eq!(manager.get_pending_address_update(AddressKind::Oracle), None);
+            assert_eq!(manager.get_oracle(), new_address);
+        }
+
+        #[ink::test]
+        pub fn address_update_delay_change_waits_for_delay() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut manager = Manager::new(AccountId::from([0x1; 32]), AccountId::from([0x2; 32]));
+            assert_eq!(manager.set_address_update_delay(1000), Ok(()));
+            assert_eq!(manager.set_address_update_delay(0), Ok(()));
+            assert_eq!(manager.get_address_update_delay(), 1000);
+            assert_eq!(
+                manager.get_pending_address_update_delay(),
+                Some(PendingDelayUpdate { delay: 0, eta: 1000 })
+            );
+            assert_eq!(manager.apply_address_update_delay(), Err(Error::UpdateNotReady));
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(1000);
+            assert_eq!(manager.apply_address_update_delay(), Ok(()));
+            assert_eq!(manager.get_address_update_delay(), 0);
+            assert_eq!(manager.get_pending_address_update_delay(), None);
         }
     }
 }
@@ -0,0 +1,535 @@
+#![cfg_attr(not(feature = "std"), no_std, no_main)]
+
+pub use self::merkle_distributor::{MerkleDistributor, MerkleDistributorRef};
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, PartialEq, Eq)]
+#[ink::scale_derive(Encode, Decode, TypeInfo)]
+pub enum Error {
+    Overflow,
+    ZeroAmount,
+    InvalidParameter,
+    CampaignNotFound,
+    CampaignExpired,
+    AlreadyClaimed,
+    AlreadyReclaimed,
+    InvalidProof,
+    InsufficientBalance,
+    MissingRole,
+}
+
+/// Pays out allocations computed off-chain, e.g. for trading competitions or
+/// referral rewards. For every campaign an `ADMIN` funds the contract and posts
+/// the root of a merkle tree whose leaves are `leaf_hash(index, account, amount)`.
+/// Inner nodes hash the two children ordered by value, so proofs don't need to
+/// say which side each sibling is on.
+///
+/// Anyone can submit a claim for `account` with the leaf and its proof, the
+/// tokens always go to `account`. Once the campaign expires the leftovers can be
+/// reclaimed by an `ADMIN`.
+#[ink::contract]
+mod merkle_distributor {
+    use super::*;
+    use access_control::{AccessControl, AccessControlled, RoleResult, RoleType, ADMIN};
+    use ink::env::call::{build_call, ExecutionInput, Selector};
+    use ink::env::hash::{Blake2x256, HashOutput};
+    use ink::env::DefaultEnvironment;
+    use ink::prelude::vec::Vec;
+    use ink::storage::Mapping;
+
+    pub type CampaignId = u32;
+
+    /// Claims are tracked in words of this many bits.
+    const BITMAP_WORD_BITS: u32 = 128;
+
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct Campaign {
+        pub token: AccountId,
+        pub root: Hash,
+        /// Amount funded, the sum of all leaves.
+        pub total: Balance,
+        pub claimed: Balance,
+        /// No claims are accepted from this time on.
+        pub expiry: Timestamp,
+        pub reclaimed: bool,
+    }
+
+    #[ink(event)]
+    pub struct CampaignCreated {
+        #[ink(topic)]
+        campaign_id: CampaignId,
+        #[ink(topic)]
+        token: AccountId,
+        root: Hash,
+        total: Balance,
+        expiry: Timestamp,
+    }
+
+    #[ink(event)]
+    pub struct Claimed {
+        #[ink(topic)]
+        campaign_id: CampaignId,
+        index: u32,
+        #[ink(topic)]
+        account: AccountId,
+        amount: Balance,
+    }
+
+    #[ink(event)]
+    pub struct CampaignExpired {
+        #[ink(topic)]
+        campaign_id: CampaignId,
+        #[ink(topic)]
+        to: AccountId,
+        leftover: Balance,
+    }
+
+    #[ink(storage)]
+    pub struct MerkleDistributor {
+        campaigns: Mapping<CampaignId, Campaign>,
+        next_campaign_id: CampaignId,
+        /// Bit `index % BITMAP_WORD_BITS` of word `index / BITMAP_WORD_BITS` is set
+        /// once leaf `index` of the campaign has been claimed.
+        claimed_bitmap: Mapping<(CampaignId, u32), u128>,
+        access_control: AccessControl,
+    }
+
+    impl MerkleDistributor {
+        #[ink(constructor)]
+        pub fn new() -> Self {
+            let admin = Self::env().caller();
+            Self {
+                campaigns: Mapping::default(),
+                next_campaign_id: 0,
+                claimed_bitmap: Mapping::default(),
+                access_control: AccessControl::new(admin),
+            }
+        }
+
+        /// Starts a campaign paying out `total` of `token` to the leaves of `root`
+        /// until `expiry`. `total` is pulled from the caller, who has to approve
+        /// the distributor first.
+        #[ink(message)]
+        pub fn create_campaign(
+            &mut self,
+            token: AccountId,
+            root: Hash,
+            total: Balance,
+            expiry: Timestamp,
+        ) -> Result<CampaignId> {
+            let caller = self.env().caller();
+            self.check_role(ADMIN, caller)?;
+
+            let campaign_id = self.record_campaign(token, root, total, expiry)?;
+
+            let deposit = build_call::<DefaultEnvironment>()
+                .call(token)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("transfer_from")))
+                        .push_arg(caller)
+                        .push_arg(self.env().account_id())
+                        .push_arg(total),
+                )
+                .returns::<bool>()
+                .invoke();
+
+            self.env().emit_event(CampaignCreated {
+                campaign_id,
+                token,
+                root,
+                total,
+                expiry,
+            });
+
+            Ok(campaign_id)
+        }
+
+        /// Pays `amount` to `account` if leaf `index` with these values is in the
+        /// campaign tree and has not been claimed yet.
+        #[ink(message)]
+        pub fn claim(
+            &mut self,
+            campaign_id: CampaignId,
+            index: u32,
+            account: AccountId,
+            amount: Balance,
+            proof: Vec<Hash>,
+        ) -> Result<()> {
+            let campaign = self.record_claim(campaign_id, index, account, amount, &proof)?;
+
+            self.transfer(campaign.token, account, amount);
+
+            self.env().emit_event(Claimed {
+                campaign_id,
+                index,
+                account,
+                amount,
+            });
+
+            Ok(())
+        }
+
+        /// Ends the campaign now if it is still running and sends what was not
+        /// claimed to `to`. Returns the amount sent.
+        #[ink(message)]
+        pub fn expire(&mut self, campaign_id: CampaignId, to: AccountId) -> Result<Balance> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            let (campaign, leftover) = self.record_expiry(campaign_id)?;
+
+            if leftover > 0 {
+                self.transfer(campaign.token, to, leftover);
+            }
+
+            self.env().emit_event(CampaignExpired {
+                campaign_id,
+                to,
+                leftover,
+            });
+
+            Ok(leftover)
+        }
+
+        #[ink(message)]
+        pub fn get_campaign(&self, campaign_id: CampaignId) -> Option<Campaign> {
+            self.campaigns.get(campaign_id)
+        }
+
+        #[ink(message)]
+        pub fn get_campaign_count(&self) -> CampaignId {
+            self.next_campaign_id
+        }
+
+        #[ink(message)]
+        pub fn is_claimed(&self, campaign_id: CampaignId, index: u32) -> bool {
+            let word = self
+                .claimed_bitmap
+                .get((campaign_id, index / BITMAP_WORD_BITS))
+                .unwrap_or_default();
+            word & (1 << (index % BITMAP_WORD_BITS)) != 0
+        }
+
+        /// Leaf of the campaign tree for `amount` owed to `account`.
+        #[ink(message)]
+        pub fn leaf_hash(&self, index: u32, account: AccountId, amount: Balance) -> Hash {
+            Self::hash(&(index, account, amount))
+        }
+
+        fn record_campaign(
+            &mut self,
+            token: AccountId,
+            root: Hash,
+            total: Balance,
+            expiry: Timestamp,
+        ) -> Result<CampaignId> {
+            if total == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            if expiry <= self.env().block_timestamp() {
+                return Err(Error::InvalidParameter);
+            }
+
+            let campaign_id = self.next_campaign_id;
+            self.next_campaign_id = campaign_id.checked_add(1).ok_or(Error::Overflow)?;
+
+            self.campaigns.insert(
+                campaign_id,
+                &Campaign {
+                    token,
+                    root,
+                    total,
+                    claimed: 0,
+                    expiry,
+                    reclaimed: false,
+                },
+            );
+
+            Ok(campaign_id)
+        }
+
+        fn record_claim(
+            &mut self,
+            campaign_id: CampaignId,
+            index: u32,
+            account: AccountId,
+            amount: Balance,
+            proof: &[Hash],
+        ) -> Result<Campaign> {
+            let mut campaign = self.campaigns.get(campaign_id).ok_or(Error::CampaignNotFound)?;
+
+            if self.env().block_timestamp() >= campaign.expiry {
+                return Err(Error::CampaignExpired);
+            }
+
+            if self.is_claimed(campaign_id, index) {
+                return Err(Error::AlreadyClaimed);
+            }
+
+            let leaf = self.leaf_hash(index, account, amount);
+
+            if Self::root_from_proof(leaf, proof) != campaign.root {
+                return Err(Error::InvalidProof);
+            }
+
+            // guards the funds of other campaigns against a bad tree
+            campaign.claimed = campaign.claimed.checked_add(amount).ok_or(Error::Overflow)?;
+            if campaign.claimed > campaign.total {
+                return Err(Error::InsufficientBalance);
+            }
+
+            let key = (campaign_id, index / BITMAP_WORD_BITS);
+            let word = self.claimed_bitmap.get(key).unwrap_or_default();
+            self.claimed_bitmap.insert(key, &(word | (1 << (index % BITMAP_WORD_BITS))));
+            self.campaigns.insert(campaign_id, &campaign);
+
+            Ok(campaign)
+        }
+
+        fn record_expiry(&mut self, campaign_id: CampaignId) -> Result<(Campaign, Balance)> {
+            let mut campaign = self.campaigns.get(campaign_id).ok_or(Error::CampaignNotFound)?;
+
+            if campaign.reclaimed {
+                return Err(Error::AlreadyReclaimed);
+            }
+
+            campaign.expiry = campaign.expiry.min(self.env().block_timestamp());
+            campaign.reclaimed = true;
+            self.campaigns.insert(campaign_id, &campaign);
+
+            let leftover = campaign.total.saturating_sub(campaign.claimed);
+
+            Ok((campaign, leftover))
+        }
+
+        fn root_from_proof(leaf: Hash, proof: &[Hash]) -> Hash {
+            proof.iter().fold(leaf, |node, sibling| {
+                if node <= *sibling {
+                    Self::hash(&(node, *sibling))
+                } else {
+                    Self::hash(&(*sibling, node))
+                }
+            })
+        }
+
+        fn hash<T: ink::scale::Encode>(input: &T) -> Hash {
+            let mut output = <Blake2x256 as HashOutput>::Type::default();
+            ink::env::hash_encoded::<Blake2x256, _>(input, &mut output);
+            Hash::from(output)
+        }
+
+        fn transfer(&self, token: AccountId, to: AccountId, amount: Balance) {
+            let transfer = build_call::<DefaultEnvironment>()
+                .call(token)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("transfer")))
+                        .push_arg(to)
+                        .push_arg(amount),
+                )
+                .returns::<bool>()
+                .invoke();
+        }
+
+        fn check_role(&self, role: RoleType, account: AccountId) -> Result<()> {
+            if !self.access_control.has_role(role, account) {
+                return Err(Error::MissingRole);
+            }
+            Ok(())
+        }
+    }
+
+    impl AccessControlled for MerkleDistributor {
+        #[ink(message)]
+        fn grant_role(&mut self, role: RoleType, account: AccountId) -> RoleResult {
+            self.access_control.grant_role(self.env().caller(), role, account)
+        }
+
+        #[ink(message)]
+        fn revoke_role(&mut self, role: RoleType, account: AccountId) -> RoleResult {
+            self.access_control.revoke_role(self.env().caller(), role, account)
+        }
+
+        #[ink(message)]
+        fn renounce_role(&mut self, role: RoleType) -> RoleResult {
+            self.access_control.renounce_role(self.env().caller(), role)
+        }
+
+        #[ink(message)]
+        fn has_role(&self, role: RoleType, account: AccountId) -> bool {
+            self.access_control.has_role(role, account)
+        }
+    }
+
+    // CROSS CONTRACT CALLS ARE NOT INCLUDED IN TESTS
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const EXPIRY: Timestamp = 1000;
+
+        fn token() -> AccountId {
+            AccountId::from([0x1; 32])
+        }
+
+        fn node(a: Hash, b: Hash) -> Hash {
+            if a <= b {
+                MerkleDistributor::hash(&(a, b))
+            } else {
+                MerkleDistributor::hash(&(b, a))
+            }
+        }
+
+        /// Campaign over bob 100, charlie 200 and django 300, and the proof of
+        /// each leaf.
+        fn new_campaign(distributor: &mut MerkleDistributor) -> (CampaignId, Vec<Vec<Hash>>) {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let leaves = [
+                distributor.leaf_hash(0, accounts.bob, 100),
+                distributor.leaf_hash(1, accounts.charlie, 200),
+                distributor.leaf_hash(2, accounts.django, 300),
+            ];
+            let left = node(leaves[0], leaves[1]);
+            let root = node(left, leaves[2]);
+
+            let campaign_id = distributor.record_campaign(token(), root, 600, EXPIRY).unwrap();
+            let proofs = vec![
+                vec![leaves[1], leaves[2]],
+                vec![leaves[0], leaves[2]],
+                vec![left],
+            ];
+
+            (campaign_id, proofs)
+        }
+
+        #[ink::test]
+        fn claim_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut distributor = MerkleDistributor::new();
+            let (campaign_id, proofs) = new_campaign(&mut distributor);
+
+            assert!(!distributor.is_claimed(campaign_id, 1));
+            assert!(distributor
+                .record_claim(campaign_id, 1, accounts.charlie, 200, &proofs[1])
+                .is_ok());
+            assert!(distributor.is_claimed(campaign_id, 1));
+            assert!(!distributor.is_claimed(campaign_id, 0));
+
+            assert!(distributor
+                .record_claim(campaign_id, 2, accounts.django, 300, &proofs[2])
+                .is_ok());
+            assert_eq!(distributor.get_campaign(campaign_id).unwrap().claimed, 500);
+        }
+
+        #[ink::test]
+        fn claim_fails() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut distributor = MerkleDistributor::new();
+            let (campaign_id, proofs) = new_campaign(&mut distributor);
+
+            // any change to the leaf breaks the proof
+            assert_eq!(
+                distributor.record_claim(campaign_id, 0, accounts.bob, 101, &proofs[0]),
+                Err(Error::InvalidProof)
+            );
+            assert_eq!(
+                distributor.record_claim(campaign_id, 0, accounts.eve, 100, &proofs[0]),
+                Err(Error::InvalidProof)
+            );
+            assert_eq!(
+                distributor.record_claim(campaign_id, 1, accounts.bob, 100, &proofs[0]),
+                Err(Error::InvalidProof)
+            );
+            assert_eq!(
+                distributor.record_claim(campaign_id + 1, 0, accounts.bob, 100, &proofs[0]),
+                Err(Error::CampaignNotFound)
+            );
+
+            assert!(distributor
+                .record_claim(campaign_id, 0, accounts.bob, 100, &proofs[0])
+                .is_ok());
+            assert_eq!(
+                distributor.record_claim(campaign_id, 0, accounts.bob, 100, &proofs[0]),
+                Err(Error::AlreadyClaimed)
+            );
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(EXPIRY);
+            assert_eq!(
+                distributor.record_claim(campaign_id, 1, accounts.charlie, 200, &proofs[1]),
+                Err(Error::CampaignExpired)
+            );
+        }
+
+        #[ink::test]
+        fn claim_bitmap_works() {
+            let mut distributor = MerkleDistributor::new();
+            let (campaign_id, _) = new_campaign(&mut distributor);
+
+            for index in [0, 127, 128, 1000] {
+                let key = (campaign_id, index / BITMAP_WORD_BITS);
+                let word = distributor.claimed_bitmap.get(key).unwrap_or_default();
+                distributor
+                    .claimed_bitmap
+                    .insert(key, &(word | (1 << (index % BITMAP_WORD_BITS))));
+            }
+
+            assert!(distributor.is_claimed(campaign_id, 127));
+            assert!(distributor.is_claimed(campaign_id, 128));
+            assert!(!distributor.is_claimed(campaign_id, 129));
+            assert!(!distributor.is_claimed(campaign_id + 1, 0));
+        }
+
+        #[ink::test]
+        fn expire_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut distributor = MerkleDistributor::new();
+            let (campaign_id, proofs) = new_campaign(&mut distributor);
+
+            assert!(distributor
+                .record_claim(campaign_id, 0, accounts.bob, 100, &proofs[0])
+                .is_ok());
+
+            // expiring early ends the campaign right away
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(500);
+            let (campaign, leftover) = distributor.record_expiry(campaign_id).unwrap();
+            assert_eq!(leftover, 500);
+            assert_eq!(campaign.expiry, 500);
+            assert_eq!(
+                distributor.record_claim(campaign_id, 1, accounts.charlie, 200, &proofs[1]),
+                Err(Error::CampaignExpired)
+            );
+            assert_eq!(distributor.record_expiry(campaign_id), Err(Error::AlreadyReclaimed));
+        }
+
+        #[ink::test]
+        fn create_campaign_fails() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut distributor = MerkleDistributor::new();
+            let root = Hash::from([0x2; 32]);
+
+            assert_eq!(
+                distributor.create_campaign(token(), root, 0, EXPIRY),
+                Err(Error::ZeroAmount)
+            );
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(EXPIRY);
+            assert_eq!(
+                distributor.create_campaign(token(), root, 100, EXPIRY),
+                Err(Error::InvalidParameter)
+            );
+            assert_eq!(distributor.get_campaign_count(), 0);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(
+                distributor.create_campaign(token(), root, 100, EXPIRY + 1),
+                Err(Error::MissingRole)
+            );
+            assert_eq!(distributor.expire(0, accounts.bob), Err(Error::MissingRole));
+        }
+    }
+}
@@ -1,32 +1,336 @@
 #![cfg_attr(not(feature = "std"), no_std, no_main)]
 
+pub use self::oracle::{Oracle, OracleRef};
+
 #[ink::contract]
 mod oracle {
+    use access_control::{
+        AccessControl, AccessControlled, Ownership, OwnershipTransferStarted,
+        OwnershipTransferred, RoleGranted, RoleResult, RoleRevoked, RoleType, ADMIN, FEEDER,
+    };
+    use ink::storage::Mapping;
+
+    const STORAGE_VERSION: u32 = 1;
+    const BPS_DENOMINATOR: u64 = 10_000;
+
+    #[derive(Debug, PartialEq, Eq)]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub enum Error {
+        NotOwner,
+        NotPendingOwner,
+        AlreadyMigrated,
+        MissingRole,
+    }
+
+    pub type Result<T> = core::result::Result<T, Error>;
+    pub type TokenId = u128;
+
+    /// Price of a single market, tracked like the global price.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct TokenPrice {
+        pub price: u32,
+        pub reference_price: u32,
+        pub reference_block: BlockNumber,
+    }
+
+    #[ink(event)]
+    pub struct Upgraded {
+        code_hash: Hash,
+    }
+
+    #[ink(event)]
+    pub struct Migrated {
+        version: u32,
+    }
+
     #[ink(storage)]
     pub struct Oracle {
         price: u32,
+        reference_price: u32,
+        reference_block: BlockNumber,
+        ownership: Ownership,
+        version: u32,
+        access_control: AccessControl,
+        /// Markets with their own price. Any other market uses the global price.
+        token_prices: Mapping<TokenId, TokenPrice>,
     }
 
     impl Oracle {
         #[ink(constructor)]
         pub fn new(price: u32) -> Self {
-            Self { price }
+            let reference_block = Self::env().block_number();
+            let owner = Self::env().caller();
+            Self {
+                price,
+                reference_price: price,
+                reference_block,
+                ownership: Ownership::new(owner),
+                version: STORAGE_VERSION,
+                access_control: AccessControl::new(owner),
+                token_prices: Mapping::default(),
+            }
         }
         
+        /// Publishes a new price. Only a `FEEDER` can update the price.
         #[ink(message)]
-        pub fn change_price(&mut self, new_price: u32) {
+        pub fn change_price(&mut self, new_price: u32) -> Result<()> {
+            self.check_role(FEEDER, self.env().caller())?;
+
+            let block = self.env().block_number();
+
+            // the first update in a block starts a new window from the last price
+            if block != self.reference_block {
+                self.reference_price = self.price;
+                self.reference_block = block;
+            }
+
             self.price = new_price;
+            Ok(())
         }
 
         #[ink(message)]
         pub fn get_price(&mut self) -> u32{
             self.price
         }
+
+        /// Price move in basis points since the start of the current block, or 0 if
+        /// the price has not been updated in this block.
+        #[ink(message)]
+        pub fn get_price_move_bps(&self) -> u32 {
+            self.price_move_bps(self.price, self.reference_price, self.reference_block)
+        }
+
+        /// Publishes a new price for `token` alone. Only a `FEEDER` can update prices.
+        /// The first price of a token is measured against the global price it
+        /// followed until then.
+        #[ink(message)]
+        pub fn change_token_price(&mut self, token: TokenId, new_price: u32) -> Result<()> {
+            self.check_role(FEEDER, self.env().caller())?;
+
+            let block = self.env().block_number();
+            let mut token_price = self.token_prices.get(token).unwrap_or(TokenPrice {
+                price: self.price,
+                reference_price: if block == self.reference_block {
+                    self.reference_price
+                } else {
+                    self.price
+                },
+                reference_block: block,
+            });
+
+            if block != token_price.reference_block {
+                token_price.reference_price = token_price.price;
+                token_price.reference_block = block;
+            }
+
+            token_price.price = new_price;
+            self.token_prices.insert(token, &token_price);
+
+            Ok(())
+        }
+
+        /// Price of `token`, or the global price if it has none of its own.
+        #[ink(message)]
+        pub fn get_token_price(&self, token: TokenId) -> u32 {
+            self.token_prices
+                .get(token)
+                .map_or(self.price, |token_price| token_price.price)
+        }
+
+        /// `get_price_move_bps` for the price of `token`.
+        #[ink(message)]
+        pub fn get_token_price_move_bps(&self, token: TokenId) -> u32 {
+            match self.token_prices.get(token) {
+                Some(token_price) => self.price_move_bps(
+                    token_price.price,
+                    token_price.reference_price,
+                    token_price.reference_block,
+                ),
+                None => self.get_price_move_bps(),
+            }
+        }
+
+        /// Replaces the oracle code with `code_hash`, keeping the stored price.
+        #[ink(message)]
+        pub fn upgrade(&mut self, code_hash: Hash) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            self.env().set_code_hash(&code_hash).unwrap_or_else(|err| {
+                panic!("failed to set code hash {:?}: {:?}", code_hash, err)
+            });
+
+            self.env().emit_event(Upgraded { code_hash });
+
+            Ok(())
+        }
+
+        /// Migrates storage from an older code version after an `upgrade`.
+        #[ink(message)]
+        pub fn migrate(&mut self) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            if self.version >= STORAGE_VERSION {
+                return Err(Error::AlreadyMigrated);
+            }
+
+            self.version = STORAGE_VERSION;
+
+            self.env().emit_event(Migrated {
+                version: self.version,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_version(&self) -> u32 {
+            self.version
+        }
+
+        /// Proposes `new_owner` as the next owner. Nothing changes until
+        /// `new_owner` calls `accept_ownership`.
+        #[ink(message)]
+        pub fn transfer_ownership(&mut self, new_owner: AccountId) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            self.ownership.start_transfer(new_owner);
+
+            self.env().emit_event(OwnershipTransferStarted {
+                previous_owner: self.ownership.owner(),
+                new_owner,
+            });
+
+            Ok(())
+        }
+
+        /// Completes a transfer started by `transfer_ownership`. `ADMIN` moves from
+        /// the previous owner to the caller along with ownership.
+        #[ink(message)]
+        pub fn accept_ownership(&mut self) -> Result<()> {
+            let caller = self.env().caller();
+            let previous_owner = self.ownership.owner();
+
+            if !self.ownership.accept(caller) {
+                return Err(Error::NotPendingOwner);
+            }
+
+            if let Some(previous_owner) = previous_owner {
+                if self.access_control.revoke(ADMIN, previous_owner) {
+                    self.env().emit_event(RoleRevoked {
+                        role: ADMIN,
+                        account: previous_owner,
+                        sender: caller,
+                    });
+                }
+            }
+
+            if self.access_control.grant(ADMIN, caller) {
+                self.env().emit_event(RoleGranted {
+                    role: ADMIN,
+                    account: caller,
+                    sender: caller,
+                });
+            }
+
+            self.env().emit_event(OwnershipTransferred {
+                previous_owner,
+                new_owner: Some(caller),
+            });
+
+            Ok(())
+        }
+
+        /// Leaves the contract without an owner, which disables `upgrade` and
+        /// `migrate` for good. The owner's `ADMIN` goes with it, other admins
+        /// keep theirs.
+        #[ink(message)]
+        pub fn renounce_ownership(&mut self) -> Result<()> {
+            let caller = self.env().caller();
+            self.check_owner(caller)?;
+
+            let previous_owner = self.ownership.owner();
+            self.ownership.renounce();
+
+            if self.access_control.revoke(ADMIN, caller) {
+                self.env().emit_event(RoleRevoked {
+                    role: ADMIN,
+                    account: caller,
+                    sender: caller,
+                });
+            }
+
+            self.env().emit_event(OwnershipTransferred {
+                previous_owner,
+                new_owner: None,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn owner(&self) -> Option<AccountId> {
+            self.ownership.owner()
+        }
+
+        #[ink(message)]
+        pub fn pending_owner(&self) -> Option<AccountId> {
+            self.ownership.pending_owner()
+        }
+
+        fn price_move_bps(&self, price: u32, reference_price: u32, reference_block: BlockNumber) -> u32 {
+            if self.env().block_number() != reference_block || reference_price == 0 {
+                return 0;
+            }
+
+            let price_move = price.abs_diff(reference_price) as u64;
+            let move_bps = price_move * BPS_DENOMINATOR / reference_price as u64;
+
+            move_bps.min(u32::MAX as u64) as u32
+        }
+
+        fn check_owner(&self, account: AccountId) -> Result<()> {
+            if !self.ownership.is_owner(account) {
+                return Err(Error::NotOwner);
+            }
+            Ok(())
+        }
+
+        fn check_role(&self, role: RoleType, account: AccountId) -> Result<()> {
+            if !self.access_control.has_role(role, account) {
+                return Err(Error::MissingRole);
+            }
+            Ok(())
+        }
+    }
+
+    impl AccessControlled for Oracle {
+        #[ink(message)]
+        fn grant_role(&mut self, role: RoleType, account: AccountId) -> RoleResult {
+            self.access_control.grant_role(self.env().caller(), role, account)
+        }
+
+        #[ink(message)]
+        fn revoke_role(&mut self, role: RoleType, account: AccountId) -> RoleResult {
+            self.access_control.revoke_role(self.env().caller(), role, account)
+        }
+
+        #[ink(message)]
+        fn renounce_role(&mut self, role: RoleType) -> RoleResult {
+            self.access_control.renounce_role(self.env().caller(), role)
+        }
+
+        #[ink(message)]
+        fn has_role(&self, role: RoleType, account: AccountId) -> bool {
+            self.access_control.has_role(role, account)
+        }
     }
 
     #[cfg(test)]
     mod tests {
         use super::*;
+        use access_control::RoleError;
 
         #[ink::test]
         fn create_oracle_works() {
@@ -41,10 +345,186 @@ mod oracle {
             let new_price = 1200;
 
             let mut oracle = Oracle::new(oracle_price);
+            oracle.access_control.grant(FEEDER, ink::env::caller::<ink::env::DefaultEnvironment>());
             
-            oracle.change_price(1200);
+            assert_eq!(oracle.change_price(1200), Ok(()));
             assert_eq!(oracle.get_price(), new_price);
         }
+
+        #[ink::test]
+        fn price_move_works() {
+            let mut oracle = Oracle::new(1000);
+            oracle.access_control.grant(FEEDER, ink::env::caller::<ink::env::DefaultEnvironment>());
+            assert_eq!(oracle.get_price_move_bps(), 0);
+
+            ink::env::test::advance_block::<ink::env::DefaultEnvironment>();
+            assert_eq!(oracle.change_price(1100), Ok(()));
+            assert_eq!(oracle.get_price_move_bps(), 1000);
+
+            // moves within a block add up against the price the block started with
+            assert_eq!(oracle.change_price(800), Ok(()));
+            assert_eq!(oracle.get_price_move_bps(), 2000);
+
+            ink::env::test::advance_block::<ink::env::DefaultEnvironment>();
+            assert_eq!(oracle.get_price_move_bps(), 0);
+
+            assert_eq!(oracle.change_price(840), Ok(()));
+            assert_eq!(oracle.get_price_move_bps(), 500);
+        }
+
+        #[ink::test]
+        fn upgrade_only_owner() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut oracle = Oracle::new(1000);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(oracle.upgrade(Hash::from([0x42; 32])), Err(Error::NotOwner));
+            assert_eq!(oracle.migrate(), Err(Error::NotOwner));
+        }
+
+        #[ink::test]
+        fn token_price_works() {
+            let mut oracle = Oracle::new(1000);
+            let token = 1;
+            assert_eq!(oracle.change_token_price(token, 500), Err(Error::MissingRole));
+            oracle.access_control.grant(FEEDER, ink::env::caller::<ink::env::DefaultEnvironment>());
+
+            // markets without their own price follow the global one
+            assert_eq!(oracle.get_token_price(token), 1000);
+            assert_eq!(oracle.change_price(1200), Ok(()));
+            assert_eq!(oracle.get_token_price(token), 1200);
+            assert_eq!(oracle.get_token_price_move_bps(token), 2000);
+
+            // the first own price moves from the global price of the block start
+            assert_eq!(oracle.change_token_price(token + 1, 1100), Ok(()));
+            assert_eq!(oracle.get_token_price_move_bps(token + 1), 1000);
+
+            ink::env::test::advance_block::<ink::env::DefaultEnvironment>();
+            assert_eq!(oracle.change_token_price(token, 500), Ok(()));
+            assert_eq!(oracle.get_token_price(token), 500);
+            assert_eq!(oracle.get_token_price_move_bps(token), 5833);
+
+            ink::env::test::advance_block::<ink::env::DefaultEnvironment>();
+            assert_eq!(oracle.change_token_price(token, 550), Ok(()));
+            assert_eq!(oracle.get_token_price_move_bps(token), 1000);
+            assert_eq!(oracle.get_price(), 1200);
+            assert_eq!(oracle.get_price_move_bps(), 0);
+            assert_eq!(oracle.get_token_price_move_bps(token + 1), 0);
+        }
+
+        #[ink::test]
+        fn migrate_current_version_fails() {
+            let mut oracle = Oracle::new(1000);
+            assert_eq!(oracle.get_version(), STORAGE_VERSION);
+            assert_eq!(oracle.migrate(), Err(Error::AlreadyMigrated));
+        }
+
+        mod v2 {
+            use access_control::{AccessControl, Ownership};
+            use ink::storage::{Lazy, Mapping};
+
+            /// Oracle layout of a later version: the v1 fields in the same order
+            /// with a `Lazy` one appended.
+            #[ink::storage_item]
+            pub struct Oracle {
+                pub price: u32,
+                pub reference_price: u32,
+                pub reference_block: u32,
+                pub ownership: Ownership,
+                pub version: u32,
+                pub access_control: AccessControl,
+                pub token_prices: Mapping<u128, super::TokenPrice>,
+                pub fallback_price: Lazy<u32>,
+            }
+        }
+
+        #[ink::test]
+        fn storage_survives_upgrade() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let root_key = <Oracle as ink::storage::traits::StorageKey>::KEY;
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut oracle = Oracle::new(1000);
+            oracle.access_control.grant(FEEDER, ink::env::caller::<ink::env::DefaultEnvironment>());
+            assert_eq!(oracle.change_price(1200), Ok(()));
+            ink::env::set_contract_storage(&root_key, &oracle);
+
+            let upgraded = ink::env::get_contract_storage::<_, v2::Oracle>(&root_key)
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(upgraded.price, 1200);
+            assert_eq!(upgraded.ownership.owner(), Some(accounts.alice));
+            assert_eq!(upgraded.version, STORAGE_VERSION);
+            assert!(upgraded.access_control.has_role(FEEDER, accounts.alice));
+            assert_eq!(upgraded.fallback_price.get(), None);
+        }
+
+        #[ink::test]
+        fn roles_work() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut oracle = Oracle::new(1000);
+            assert!(oracle.has_role(ADMIN, accounts.alice));
+            assert!(!oracle.has_role(FEEDER, accounts.bob));
+
+            assert_eq!(oracle.grant_role(FEEDER, accounts.bob), Ok(()));
+            assert!(oracle.has_role(FEEDER, accounts.bob));
+            assert_eq!(oracle.revoke_role(FEEDER, accounts.bob), Ok(()));
+            assert!(!oracle.has_role(FEEDER, accounts.bob));
+
+            assert_eq!(oracle.grant_role(FEEDER, accounts.bob), Ok(()));
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(oracle.grant_role(ADMIN, accounts.bob), Err(RoleError::MissingRole));
+            assert_eq!(oracle.revoke_role(FEEDER, accounts.bob), Err(RoleError::MissingRole));
+            assert_eq!(oracle.renounce_role(FEEDER), Ok(()));
+            assert!(!oracle.has_role(FEEDER, accounts.bob));
+            assert_eq!(oracle.renounce_role(FEEDER), Err(RoleError::MissingRole));
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 4);
+        }
+
+        #[ink::test]
+        fn change_price_only_feeder() {
+            let mut oracle = Oracle::new(1000);
+
+            assert_eq!(oracle.change_price(1200), Err(Error::MissingRole));
+            assert_eq!(oracle.get_price(), 1000);
+        }
+
+        #[ink::test]
+        fn ownership_transfer_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut oracle = Oracle::new(1000);
+            assert_eq!(oracle.transfer_ownership(accounts.bob), Ok(()));
+            assert_eq!(oracle.owner(), Some(accounts.alice));
+            assert_eq!(oracle.pending_owner(), Some(accounts.bob));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.charlie);
+            assert_eq!(oracle.accept_ownership(), Err(Error::NotPendingOwner));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(oracle.accept_ownership(), Ok(()));
+            assert_eq!(oracle.owner(), Some(accounts.bob));
+            assert_eq!(oracle.pending_owner(), None);
+            assert!(!oracle.has_role(ADMIN, accounts.alice));
+            assert!(oracle.has_role(ADMIN, accounts.bob));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(oracle.transfer_ownership(accounts.alice), Err(Error::NotOwner));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(oracle.renounce_ownership(), Ok(()));
+            assert_eq!(oracle.owner(), None);
+            assert!(!oracle.has_role(ADMIN, accounts.bob));
+            assert_eq!(oracle.migrate(), Err(Error::NotOwner));
+        }
     }
 
 }
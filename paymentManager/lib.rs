@@ -1,23 +1,43 @@
 #![cfg_attr(not(feature = "std"), no_std, no_main)]
 
+pub use self::paymentManager::{PaymentManager, PaymentManagerRef};
+
 pub type PositionId = u128;
 pub type Result<T> = core::result::Result<T, Error>;
 pub type TokenId = u128;
 
+/// Replies of the manager messages carry its own error type, whose variants do
+/// not line up with ours, so they are decoded as such and wrapped.
+type ManagerResult<T> = core::result::Result<T, manager::Error>;
+
 #[derive(Debug, PartialEq, Eq)]
 #[ink::scale_derive(Encode, Decode, TypeInfo)]
 pub enum Error {
     Overflow,
     Underflow,
     NotFound,
+    CallFailed,
+    NotOwner,
+    NotPendingOwner,
+    AlreadyMigrated,
+    MissingRole,
+    UpdateNotReady,
+    Manager(manager::Error),
 }
 
 #[ink::contract]
 mod paymentManager {
     use super::*;
+    use access_control::{
+        AccessControl, AccessControlled, Ownership, OwnershipTransferStarted,
+        OwnershipTransferred, RoleGranted, RoleResult, RoleRevoked, RoleType, ADMIN, KEEPER,
+    };
     use ink::env::call::{build_call, ExecutionInput, Selector};
     use ink::env::DefaultEnvironment;
-    use ink::storage::Mapping;
+    use ink::prelude::vec::Vec;
+    use ink::storage::{Lazy, Mapping};
+
+    const STORAGE_VERSION: u32 = 1;
 
     #[ink::scale_derive(Encode, Decode, TypeInfo)]
     #[derive(Debug, PartialEq, Eq)]
@@ -38,6 +58,8 @@ mod paymentManager {
         leverage: u32,
         position_value: Balance,
         creation_time: u128,
+        reserved: Balance,
+        last_fee_time: u128,
     }
 
     #[ink(event)]
@@ -45,6 +67,7 @@ mod paymentManager {
         #[ink(topic)]
         from: Option<AccountId>,
         position_id: PositionId,
+        fee: Balance,
     }
 
     #[ink(event)]
@@ -54,58 +77,149 @@ mod paymentManager {
         position_id: PositionId,
     }
 
+    #[ink(event)]
+    pub struct PositionsBatchUpdated {
+        total: u32,
+        succeeded: u32,
+        failed: u32,
+    }
+
+    #[ink(event)]
+    pub struct Upgraded {
+        code_hash: Hash,
+    }
+
+    #[ink(event)]
+    pub struct Migrated {
+        version: u32,
+    }
+
+    /// Contract address this contract talks to that the admin can replace.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub enum AddressKind {
+        Manager,
+        Oracle,
+    }
+
+    /// Address change waiting for `address_update_delay` to pass.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct PendingAddressUpdate {
+        pub address: AccountId,
+        pub eta: Timestamp,
+    }
+
+    /// Change of `address_update_delay` waiting for the current delay to pass.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct PendingDelayUpdate {
+        pub delay: Timestamp,
+        pub eta: Timestamp,
+    }
+
+    #[ink(event)]
+    pub struct ManagerUpdated {
+        old: AccountId,
+        #[ink(topic)]
+        new: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct OracleUpdated {
+        old: AccountId,
+        #[ink(topic)]
+        new: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct AddressUpdateQueued {
+        kind: AddressKind,
+        #[ink(topic)]
+        address: AccountId,
+        eta: Timestamp,
+    }
+
+    #[ink(event)]
+    pub struct AddressUpdateCancelled {
+        kind: AddressKind,
+    }
+
+    #[ink(event)]
+    pub struct AddressUpdateDelayQueued {
+        delay: Timestamp,
+        eta: Timestamp,
+    }
+
+    #[ink(event)]
+    pub struct AddressUpdateDelayUpdated {
+        old: Timestamp,
+        new: Timestamp,
+    }
+
+    #[ink(event)]
+    pub struct AddressUpdateDelayCancelled {
+        delay: Timestamp,
+    }
+
     #[ink(storage)]
     pub struct PaymentManager {
         manager: AccountId,
         oracle: AccountId,
-        fee: Balance,
+        ownership: Ownership,
+        version: u32,
+        access_control: AccessControl,
+        address_update_delay: Lazy<Timestamp>,
+        pending_address_updates: Mapping<AddressKind, PendingAddressUpdate>,
+        pending_address_update_delay: Lazy<Option<PendingDelayUpdate>>,
     }
 
     impl PaymentManager {
         #[ink(constructor)]
-        pub fn new(manager_address: AccountId, oracle_address: AccountId, fee: Balance) -> Self {
+        pub fn new(manager_address: AccountId, oracle_address: AccountId) -> Self {
             let manager = manager_address;
             let oracle = oracle_address;
-            Self { manager, oracle, fee }
+            let owner = Self::env().caller();
+            Self {
+                manager,
+                oracle,
+                ownership: Ownership::new(owner),
+                version: STORAGE_VERSION,
+                access_control: AccessControl::new(owner),
+                address_update_delay: Lazy::new(),
+                pending_address_updates: Mapping::default(),
+                pending_address_update_delay: Lazy::new(),
+            }
         }
         
         #[ink(message)]
         pub fn update_position(&mut self, position_id: PositionId, user: AccountId) -> Result<()> {
-            let position_temp = build_call::<DefaultEnvironment>()
-                .call(self.manager)
-                .call_v1()
-                .gas_limit(0)
-                .exec_input(
-                    ExecutionInput::new(Selector::new(ink::selector_bytes!("get_position")))
-                        .push_arg(user)
-                        .push_arg(position_id),
-                )
-                .returns::<Result<Position>>()
-                .invoke();
+            self.check_role(KEEPER, self.env().caller())?;
 
-            let position = position_temp.unwrap();
-            let updated_amount = position.amount.checked_sub(self.fee).ok_or(Error::Underflow)?;
-            
-            let check: bool =
-                self.check_liquidation(position.amount, position.position_value, position.leverage, position.position_type);
+            self.maintain_position(position_id, user, &mut Vec::new())
+        }
 
-            if check {
-                self.liquidation(position_id, user);
-            } else {
-                self.collect_fee(updated_amount, position_id, user);
+        /// Maintains many positions in one call for keepers. The oracle price of each
+        /// market is read once for the whole batch and a failing position is skipped
+        /// instead of aborting the others; its error is reported at the same index of
+        /// the result.
+        #[ink(message)]
+        pub fn update_positions(&mut self, positions: Vec<(AccountId, PositionId)>) -> Vec<Result<()>> {
+            if self.check_role(KEEPER, self.env().caller()).is_err() {
+                return positions.iter().map(|_| Err(Error::MissingRole)).collect();
             }
 
-            self.env().emit_event(PositionUpdated {
-                from: Some(user),
-                position_id,
-            });
-
-            Ok(())
+            self.maintain_batch(positions)
         }
         
         #[ink(message)]
         pub fn liquidation(&mut self, position_id: PositionId, user: AccountId) -> Result<()> {
-            let position = build_call::<DefaultEnvironment>()
+            self.check_role(KEEPER, self.env().caller())?;
+
+            build_call::<DefaultEnvironment>()
                 .call(self.manager)
                 .call_v1()
                 .gas_limit(0)
@@ -114,34 +228,42 @@ mod paymentManager {
                         .push_arg(position_id)
                         .push_arg(user),
                 )
-                .returns::<bool>()
-                .invoke();
-
-            Ok(())
+                .returns::<ManagerResult<()>>()
+                .try_invoke()
+                .map_err(|_| Error::CallFailed)?
+                .map_err(|_| Error::CallFailed)?
+                .map_err(Error::Manager)
         }
 
+        /// Settles the maintenance fee accrued on the position since it was last
+        /// settled. The manager charges per second elapsed, so calling this more
+        /// often does not charge more.
         #[ink(message)]
-        pub fn collect_fee(&mut self, updated_amount: Balance, position_id: PositionId, user: AccountId) -> Result<()> {
-            // call manager to update position
-            let update_position = build_call::<DefaultEnvironment>()
+        pub fn collect_fee(&mut self, position_id: PositionId, user: AccountId) -> Result<Balance> {
+            self.check_role(KEEPER, self.env().caller())?;
+
+            let fee = build_call::<DefaultEnvironment>()
                 .call(self.manager)
                 .call_v1()
                 .gas_limit(0)
                 .exec_input(
-                    ExecutionInput::new(Selector::new(ink::selector_bytes!("update_position")))
-                        .push_arg(updated_amount)
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("settle_maintenance_fee")))
                         .push_arg(position_id)
                         .push_arg(user),
                 )
-                .returns::<bool>()
-                .invoke();
+                .returns::<ManagerResult<Balance>>()
+                .try_invoke()
+                .map_err(|_| Error::CallFailed)?
+                .map_err(|_| Error::CallFailed)?
+                .map_err(Error::Manager)?;
 
             self.env().emit_event(MaintenanceFeeCollected {
                 from: Some(user),
                 position_id,
+                fee,
             });
 
-            Ok(())
+            Ok(fee)
         }
         
         #[ink(message)]
@@ -152,29 +274,254 @@ mod paymentManager {
             leverage: u32,
             position_type: PositionType,
         ) -> bool {
-            let entry_value = position_value.wrapping_mul(leverage as u128);
-
             let current_price = self.get_price();
+            Self::is_liquidatable(amount, position_value, leverage, &position_type, current_price)
+        }
 
-            let real_amount_with_leverage = amount.wrapping_mul(leverage as u128);
-            let real_value = real_amount_with_leverage.wrapping_mul(current_price as u128);
+        /// Replaces the contract code, keeping storage.
+        #[ink(message)]
+        pub fn upgrade(&mut self, code_hash: Hash) -> Result<()> {
+            self.check_owner(self.env().caller())?;
 
-            match position_type {
-                PositionType::LONG => {
-                    if (position_value <= entry_value.checked_sub(real_value).unwrap()) {
-                        true
-                    } else {
-                        false
-                    }
-                },
-                PositionType::SHORT => {
-                    if (position_value <= real_value.checked_sub(entry_value).unwrap()) {
-                        true
-                    } else {
-                        false
-                    }
-                },
+            self.env().set_code_hash(&code_hash).unwrap_or_else(|err| {
+                panic!("failed to set code hash {:?}: {:?}", code_hash, err)
+            });
+
+            self.env().emit_event(Upgraded { code_hash });
+
+            Ok(())
+        }
+
+        /// Migrates storage from an older code version after an `upgrade`.
+        #[ink(message)]
+        pub fn migrate(&mut self) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            if self.version >= STORAGE_VERSION {
+                return Err(Error::AlreadyMigrated);
             }
+
+            self.version = STORAGE_VERSION;
+
+            self.env().emit_event(Migrated {
+                version: self.version,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_version(&self) -> u32 {
+            self.version
+        }
+
+        /// Proposes `new_owner` as the next owner. Nothing changes until
+        /// `new_owner` calls `accept_ownership`.
+        #[ink(message)]
+        pub fn transfer_ownership(&mut self, new_owner: AccountId) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            self.ownership.start_transfer(new_owner);
+
+            self.env().emit_event(OwnershipTransferStarted {
+                previous_owner: self.ownership.owner(),
+                new_owner,
+            });
+
+            Ok(())
+        }
+
+        /// Completes a transfer started by `transfer_ownership`. `ADMIN` moves from
+        /// the previous owner to the caller along with ownership.
+        #[ink(message)]
+        pub fn accept_ownership(&mut self) -> Result<()> {
+            let caller = self.env().caller();
+            let previous_owner = self.ownership.owner();
+
+            if !self.ownership.accept(caller) {
+                return Err(Error::NotPendingOwner);
+            }
+
+            if let Some(previous_owner) = previous_owner {
+                if self.access_control.revoke(ADMIN, previous_owner) {
+                    self.env().emit_event(RoleRevoked {
+                        role: ADMIN,
+                        account: previous_owner,
+                        sender: caller,
+                    });
+                }
+            }
+
+            if self.access_control.grant(ADMIN, caller) {
+                self.env().emit_event(RoleGranted {
+                    role: ADMIN,
+                    account: caller,
+                    sender: caller,
+                });
+            }
+
+            self.env().emit_event(OwnershipTransferred {
+                previous_owner,
+                new_owner: Some(caller),
+            });
+
+            Ok(())
+        }
+
+        /// Leaves the contract without an owner, which disables `upgrade` and
+        /// `migrate` for good. The owner's `ADMIN` goes with it, other admins
+        /// keep theirs.
+        #[ink(message)]
+        pub fn renounce_ownership(&mut self) -> Result<()> {
+            let caller = self.env().caller();
+            self.check_owner(caller)?;
+
+            let previous_owner = self.ownership.owner();
+            self.ownership.renounce();
+
+            if self.access_control.revoke(ADMIN, caller) {
+                self.env().emit_event(RoleRevoked {
+                    role: ADMIN,
+                    account: caller,
+                    sender: caller,
+                });
+            }
+
+            self.env().emit_event(OwnershipTransferred {
+                previous_owner,
+                new_owner: None,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn owner(&self) -> Option<AccountId> {
+            self.ownership.owner()
+        }
+
+        #[ink(message)]
+        pub fn pending_owner(&self) -> Option<AccountId> {
+            self.ownership.pending_owner()
+        }
+
+        #[ink(message)]
+        pub fn set_manager(&mut self, manager: AccountId) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+            self.request_address_update(AddressKind::Manager, manager)
+        }
+
+        #[ink(message)]
+        pub fn get_manager(&self) -> AccountId {
+            self.manager
+        }
+
+        #[ink(message)]
+        pub fn set_oracle(&mut self, oracle: AccountId) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+            self.request_address_update(AddressKind::Oracle, oracle)
+        }
+
+        #[ink(message)]
+        pub fn get_oracle(&self) -> AccountId {
+            self.oracle
+        }
+
+        /// Applies a queued address change once its delay has passed. Anyone can
+        /// call this.
+        #[ink(message)]
+        pub fn apply_address_update(&mut self, kind: AddressKind) -> Result<()> {
+            let update = self.pending_address_updates.get(kind).ok_or(Error::NotFound)?;
+
+            if self.env().block_timestamp() < update.eta {
+                return Err(Error::UpdateNotReady);
+            }
+
+            self.pending_address_updates.remove(kind);
+            self.update_address(kind, update.address);
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn cancel_address_update(&mut self, kind: AddressKind) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            if self.pending_address_updates.take(kind).is_none() {
+                return Err(Error::NotFound);
+            }
+
+            self.env().emit_event(AddressUpdateCancelled { kind });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_pending_address_update(&self, kind: AddressKind) -> Option<PendingAddressUpdate> {
+            self.pending_address_updates.get(kind)
+        }
+
+        /// Sets how long address changes wait before they can be applied. With 0
+        /// the setters take effect immediately. Once a delay is in place, changing
+        /// it waits for that delay too.
+        #[ink(message)]
+        pub fn set_address_update_delay(&mut self, delay: Timestamp) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            let current = self.get_address_update_delay();
+
+            if current == 0 {
+                self.update_address_update_delay(delay);
+                return Ok(());
+            }
+
+            let eta = self
+                .env()
+                .block_timestamp()
+                .checked_add(current)
+                .ok_or(Error::Overflow)?;
+            self.pending_address_update_delay.set(&Some(PendingDelayUpdate { delay, eta }));
+
+            self.env().emit_event(AddressUpdateDelayQueued { delay, eta });
+
+            Ok(())
+        }
+
+        /// Applies a queued delay change once the current delay has passed. Anyone
+        /// can call this.
+        #[ink(message)]
+        pub fn apply_address_update_delay(&mut self) -> Result<()> {
+            let update = self.get_pending_address_update_delay().ok_or(Error::NotFound)?;
+
+            if self.env().block_timestamp() < update.eta {
+                return Err(Error::UpdateNotReady);
+            }
+
+            self.update_address_update_delay(update.delay);
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn cancel_address_update_delay(&mut self) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            let update = self.get_pending_address_update_delay().ok_or(Error::NotFound)?;
+            self.pending_address_update_delay.set(&None);
+
+            self.env().emit_event(AddressUpdateDelayCancelled { delay: update.delay });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_address_update_delay(&self) -> Timestamp {
+            self.address_update_delay.get().unwrap_or_default()
+        }
+
+        #[ink(message)]
+        pub fn get_pending_address_update_delay(&self) -> Option<PendingDelayUpdate> {
+            self.pending_address_update_delay.get().flatten()
         }
 
         #[ink(message)]
@@ -190,17 +537,204 @@ mod paymentManager {
                 .invoke();
             price
         }
+
+        fn get_token_price(&self, token: TokenId) -> u32 {
+            build_call::<DefaultEnvironment>()
+                .call(self.oracle)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("get_token_price")))
+                        .push_arg(token),
+                )
+                .returns::<u32>()
+                .invoke()
+        }
+
+        fn maintain_batch(&mut self, positions: Vec<(AccountId, PositionId)>) -> Vec<Result<()>> {
+            let mut prices = Vec::new();
+            let results: Vec<Result<()>> = positions
+                .into_iter()
+                .map(|(user, position_id)| self.maintain_position(position_id, user, &mut prices))
+                .collect();
+
+            let total = results.len() as u32;
+            let succeeded = results.iter().filter(|result| result.is_ok()).count() as u32;
+
+            self.env().emit_event(PositionsBatchUpdated {
+                total,
+                succeeded,
+                failed: total.saturating_sub(succeeded),
+            });
+
+            results
+        }
+
+        /// `prices` caches the oracle price of every market seen so far.
+        fn maintain_position(
+            &mut self,
+            position_id: PositionId,
+            user: AccountId,
+            prices: &mut Vec<(TokenId, u32)>,
+        ) -> Result<()> {
+            let position = build_call::<DefaultEnvironment>()
+                .call(self.manager)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("get_position")))
+                        .push_arg(user)
+                        .push_arg(position_id),
+                )
+                .returns::<ManagerResult<Position>>()
+                .try_invoke()
+                .map_err(|_| Error::CallFailed)?
+                .map_err(|_| Error::CallFailed)?
+                .map_err(Error::Manager)?;
+
+            let current_price = match prices.iter().find(|(token, _)| *token == position.token) {
+                Some((_, price)) => *price,
+                None => {
+                    let price = self.get_token_price(position.token);
+                    prices.push((position.token, price));
+                    price
+                }
+            };
+
+            let check: bool = Self::is_liquidatable(
+                position.amount,
+                position.position_value,
+                position.leverage,
+                &position.position_type,
+                current_price,
+            );
+
+            if check {
+                self.liquidation(position_id, user)?;
+            } else {
+                self.collect_fee(position_id, user)?;
+            }
+
+            self.env().emit_event(PositionUpdated {
+                from: Some(user),
+                position_id,
+            });
+
+            Ok(())
+        }
+
+        fn is_liquidatable(
+            amount: Balance,
+            position_value: Balance,
+            leverage: u32,
+            position_type: &PositionType,
+            current_price: u32,
+        ) -> bool {
+            let entry_value = position_value.wrapping_mul(leverage as u128);
+
+            let real_amount_with_leverage = amount.wrapping_mul(leverage as u128);
+            let real_value = real_amount_with_leverage.wrapping_mul(current_price as u128);
+
+            // no loss at all means there is nothing to liquidate
+            match position_type {
+                PositionType::LONG => entry_value
+                    .checked_sub(real_value)
+                    .map_or(false, |loss| position_value <= loss),
+                PositionType::SHORT => real_value
+                    .checked_sub(entry_value)
+                    .map_or(false, |loss| position_value <= loss),
+            }
+        }
+
+        fn check_owner(&self, account: AccountId) -> Result<()> {
+            if !self.ownership.is_owner(account) {
+                return Err(Error::NotOwner);
+            }
+            Ok(())
+        }
+
+        fn check_role(&self, role: RoleType, account: AccountId) -> Result<()> {
+            if !self.access_control.has_role(role, account) {
+                return Err(Error::MissingRole);
+            }
+            Ok(())
+        }
+
+        fn request_address_update(&mut self, kind: AddressKind, address: AccountId) -> Result<()> {
+            let delay = self.get_address_update_delay();
+
+            if delay == 0 {
+                self.update_address(kind, address);
+                return Ok(());
+            }
+
+            let eta = self
+                .env()
+                .block_timestamp()
+                .checked_add(delay)
+                .ok_or(Error::Overflow)?;
+            self.pending_address_updates.insert(kind, &PendingAddressUpdate { address, eta });
+
+            self.env().emit_event(AddressUpdateQueued { kind, address, eta });
+
+            Ok(())
+        }
+
+        fn update_address_update_delay(&mut self, delay: Timestamp) {
+            let old = self.get_address_update_delay();
+            self.address_update_delay.set(&delay);
+            self.pending_address_update_delay.set(&None);
+
+            self.env().emit_event(AddressUpdateDelayUpdated { old, new: delay });
+        }
+
+        fn update_address(&mut self, kind: AddressKind, address: AccountId) {
+            match kind {
+                AddressKind::Manager => {
+                    let old = core::mem::replace(&mut self.manager, address);
+                    self.env().emit_event(ManagerUpdated { old, new: address });
+                }
+                AddressKind::Oracle => {
+                    let old = core::mem::replace(&mut self.oracle, address);
+                    self.env().emit_event(OracleUpdated { old, new: address });
+                }
+            }
+        }
+    }
+
+    impl AccessControlled for PaymentManager {
+        #[ink(message)]
+        fn grant_role(&mut self, role: RoleType, account: AccountId) -> RoleResult {
+            self.access_control.grant_role(self.env().caller(), role, account)
+        }
+
+        #[ink(message)]
+        fn revoke_role(&mut self, role: RoleType, account: AccountId) -> RoleResult {
+            self.access_control.revoke_role(self.env().caller(), role, account)
+        }
+
+        #[ink(message)]
+        fn renounce_role(&mut self, role: RoleType) -> RoleResult {
+            self.access_control.renounce_role(self.env().caller(), role)
+        }
+
+        #[ink(message)]
+        fn has_role(&self, role: RoleType, account: AccountId) -> bool {
+            self.access_control.has_role(role, account)
+        }
     }
 
     #[cfg(test)]
     mod tests {
         use super::*;
+        use access_control::RoleError;
 
         #[ink::test]
         pub fn contract_creation_works() {
             let manager_address = AccountId::from([0x1; 32]);
+            let oracle_address = AccountId::from([0x2; 32]);
 
-            let mut paymentManager = PaymentManager::new(manager_address);
+            let mut paymentManager = PaymentManager::new(manager_address, oracle_address);
 
             assert_eq!(paymentManager.manager, manager_address);
         }
@@ -208,11 +742,13 @@ mod paymentManager {
         #[ink::test]
         pub fn collect_fee_works() {
             let manager_address = AccountId::from([0x1; 32]);
+            let oracle_address = AccountId::from([0x2; 32]);
             let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
             let position_id = 0;
 
-            let mut paymentManager = PaymentManager::new(manager_address);
+            let mut paymentManager = PaymentManager::new(manager_address, oracle_address);
             ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            paymentManager.access_control.grant(KEEPER, accounts.alice);
 
             paymentManager.collect_fee(position_id, accounts.alice);
 
@@ -223,16 +759,207 @@ mod paymentManager {
         #[ink::test]
         pub fn update_position_works() {
             let manager_address = AccountId::from([0x1; 32]);
+            let oracle_address = AccountId::from([0x2; 32]);
             let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
             let position_id = 0;
 
-            let mut paymentManager = PaymentManager::new(manager_address);
+            let mut paymentManager = PaymentManager::new(manager_address, oracle_address);
             ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            paymentManager.access_control.grant(KEEPER, accounts.alice);
 
             paymentManager.update_position(position_id, accounts.alice);
 
             let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
             assert_eq!(emitted_events.len(), 2);
         }
+
+        #[ink::test]
+        pub fn is_liquidatable_works() {
+            // entry value 100 * 10 = 1000, collateral value 100
+            assert!(!PaymentManager::is_liquidatable(1, 100, 10, &PositionType::LONG, 100));
+            assert!(!PaymentManager::is_liquidatable(1, 100, 10, &PositionType::LONG, 91));
+            assert!(PaymentManager::is_liquidatable(1, 100, 10, &PositionType::LONG, 90));
+            assert!(!PaymentManager::is_liquidatable(1, 100, 10, &PositionType::LONG, 150));
+
+            assert!(!PaymentManager::is_liquidatable(1, 100, 10, &PositionType::SHORT, 100));
+            assert!(!PaymentManager::is_liquidatable(1, 100, 10, &PositionType::SHORT, 109));
+            assert!(PaymentManager::is_liquidatable(1, 100, 10, &PositionType::SHORT, 110));
+            assert!(!PaymentManager::is_liquidatable(1, 100, 10, &PositionType::SHORT, 50));
+        }
+
+        #[ink::test]
+        pub fn update_positions_empty_batch_works() {
+            let manager_address = AccountId::from([0x1; 32]);
+            let oracle_address = AccountId::from([0x2; 32]);
+
+            let mut paymentManager = PaymentManager::new(manager_address, oracle_address);
+
+            assert_eq!(paymentManager.maintain_batch(Vec::new()), Vec::new());
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 1);
+        }
+
+        #[ink::test]
+        pub fn upgrade_only_owner() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut paymentManager = PaymentManager::new(AccountId::from([0x1; 32]), AccountId::from([0x2; 32]));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(paymentManager.upgrade(Hash::from([0x42; 32])), Err(Error::NotOwner));
+            assert_eq!(paymentManager.migrate(), Err(Error::NotOwner));
+        }
+
+        #[ink::test]
+        pub fn migrate_current_version_fails() {
+            let mut paymentManager = PaymentManager::new(AccountId::from([0x1; 32]), AccountId::from([0x2; 32]));
+            assert_eq!(paymentManager.get_version(), STORAGE_VERSION);
+            assert_eq!(paymentManager.migrate(), Err(Error::AlreadyMigrated));
+        }
+
+        #[ink::test]
+        pub fn roles_work() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut paymentManager = PaymentManager::new(AccountId::from([0x1; 32]), AccountId::from([0x2; 32]));
+            assert!(paymentManager.has_role(ADMIN, accounts.alice));
+            assert!(!paymentManager.has_role(KEEPER, accounts.bob));
+
+            assert_eq!(paymentManager.grant_role(KEEPER, accounts.bob), Ok(()));
+            assert!(paymentManager.has_role(KEEPER, accounts.bob));
+            assert_eq!(paymentManager.revoke_role(KEEPER, accounts.bob), Ok(()));
+            assert!(!paymentManager.has_role(KEEPER, accounts.bob));
+
+            assert_eq!(paymentManager.grant_role(KEEPER, accounts.bob), Ok(()));
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(paymentManager.grant_role(ADMIN, accounts.bob), Err(RoleError::MissingRole));
+            assert_eq!(paymentManager.revoke_role(KEEPER, accounts.bob), Err(RoleError::MissingRole));
+            assert_eq!(paymentManager.renounce_role(KEEPER), Ok(()));
+            assert!(!paymentManager.has_role(KEEPER, accounts.bob));
+            assert_eq!(paymentManager.renounce_role(KEEPER), Err(RoleError::MissingRole));
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 4);
+        }
+
+        #[ink::test]
+        pub fn update_positions_only_keeper() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut paymentManager = PaymentManager::new(AccountId::from([0x1; 32]), AccountId::from([0x2; 32]));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(
+                paymentManager.update_positions(vec![(accounts.alice, 0), (accounts.alice, 1)]),
+                vec![Err(Error::MissingRole), Err(Error::MissingRole)]
+            );
+            assert_eq!(paymentManager.update_position(0, accounts.alice), Err(Error::MissingRole));
+            assert_eq!(paymentManager.collect_fee(0, accounts.alice), Err(Error::MissingRole));
+        }
+
+        #[ink::test]
+        pub fn ownership_transfer_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut paymentManager = PaymentManager::new(AccountId::from([0x1; 32]), AccountId::from([0x2; 32]));
+            assert_eq!(paymentManager.transfer_ownership(accounts.bob), Ok(()));
+            assert_eq!(paymentManager.owner(), Some(accounts.alice));
+            assert_eq!(paymentManager.pending_owner(), Some(accounts.bob));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.charlie);
+            assert_eq!(paymentManager.accept_ownership(), Err(Error::NotPendingOwner));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(paymentManager.accept_ownership(), Ok(()));
+            assert_eq!(paymentManager.owner(), Some(accounts.bob));
+            assert_eq!(paymentManager.pending_owner(), None);
+            assert!(!paymentManager.has_role(ADMIN, accounts.alice));
+            assert!(paymentManager.has_role(ADMIN, accounts.bob));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(paymentManager.transfer_ownership(accounts.alice), Err(Error::NotOwner));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(paymentManager.renounce_ownership(), Ok(()));
+            assert_eq!(paymentManager.owner(), None);
+            assert!(!paymentManager.has_role(ADMIN, accounts.bob));
+            assert_eq!(paymentManager.migrate(), Err(Error::NotOwner));
+        }
+
+        #[ink::test]
+        pub fn address_update_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let new_address = AccountId::from([0x9; 32]);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut paymentManager = PaymentManager::new(AccountId::from([0x1; 32]), AccountId::from([0x2; 32]));
+
+            assert_eq!(paymentManager.set_manager(new_address), Ok(()));
+            assert_eq!(paymentManager.get_manager(), new_address);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(paymentManager.set_manager(accounts.bob), Err(Error::MissingRole));
+            assert_eq!(paymentManager.set_address_update_delay(0), Err(Error::MissingRole));
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 1);
+        }
+
+        #[ink::test]
+        pub fn delayed_address_update_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let old_address = AccountId::from([0x8; 32]);
+            let new_address = AccountId::from([0x9; 32]);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut paymentManager = PaymentManager::new(AccountId::from([0x1; 32]), AccountId::from([0x2; 32]));
+            paymentManager.set_manager(old_address).unwrap();
+            paymentManager.set_address_update_delay(1000).unwrap();
+
+            assert_eq!(paymentManager.set_manager(new_address), Ok(()));
+            assert_eq!(paymentManager.get_manager(), old_address);
+            assert_eq!(
+                paymentManager.get_pending_address_update(AddressKind::Manager),
+                Some(PendingAddressUpdate { address: new_address, eta: 1000 })
+            );
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(paymentManager.apply_address_update(AddressKind::Manager), Err(Error::UpdateNotReady));
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(1000);
+            assert_eq!(paymentManager.apply_address_update(AddressKind::Manager), Ok(()));
+            assert_eq!(paymentManager.get_manager(), new_address);
+            assert_eq!(paymentManager.apply_address_update(AddressKind::Manager), Err(Error::NotFound));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(paymentManager.set_manager(old_address), Ok(()));
+            assert_eq!(paymentManager.cancel_address_update(AddressKind::Manager), Ok(()));
+            assert_eq!(paymentManager.get_pending_address_update(AddressKind::Manager), None);
+            assert_eq!(paymentManager.get_manager(), new_address);
+        }
+
+        #[ink::test]
+        pub fn address_update_delay_change_waits_for_delay() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut paymentManager = PaymentManager::new(AccountId::from([0x1; 32]), AccountId::from([0x2; 32]));
+            assert_eq!(paymentManager.set_address_update_delay(1000), Ok(()));
+            assert_eq!(paymentManager.set_address_update_delay(0), Ok(()));
+            assert_eq!(paymentManager.get_address_update_delay(), 1000);
+            assert_eq!(
+                paymentManager.get_pending_address_update_delay(),
+                Some(PendingDelayUpdate { delay: 0, eta: 1000 })
+            );
+            assert_eq!(paymentManager.apply_address_update_delay(), Err(Error::UpdateNotReady));
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(1000);
+            assert_eq!(paymentManager.apply_address_update_delay(), Ok(()));
+            assert_eq!(paymentManager.get_address_update_delay(), 0);
+            assert_eq!(paymentManager.get_pending_address_update_delay(), None);
+        }
     }
 }
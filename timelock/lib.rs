@@ -0,0 +1,371 @@
+#![cfg_attr(not(feature = "std"), no_std, no_main)]
+
+pub use self::timelock::{Timelock, TimelockRef};
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, PartialEq, Eq)]
+#[ink::scale_derive(Encode, Decode, TypeInfo)]
+pub enum Error {
+    Overflow,
+    NotFound,
+    MissingRole,
+    NotTimelock,
+    DelayTooShort,
+    AlreadyQueued,
+    NotReady,
+    CallFailed,
+}
+
+/// Delays admin calls to the other contracts. Calls are queued by an `ADMIN`, can be
+/// cancelled while they wait and can be executed by anyone once `min_delay` has
+/// passed. The factory makes it the owner and `ADMIN` of every contract it
+/// deploys, so that none of their parameters or code can change without notice.
+#[ink::contract]
+mod timelock {
+    use super::*;
+    use access_control::{AccessControl, AccessControlled, RoleResult, RoleType, ADMIN};
+    use ink::env::call::{build_call, ExecutionInput, Selector};
+    use ink::env::hash::{Blake2x256, HashOutput};
+    use ink::env::{CallFlags, DefaultEnvironment};
+    use ink::prelude::vec::Vec;
+    use ink::storage::{Lazy, Mapping};
+
+    /// A message call on `target`: `input` is the SCALE encoded argument list of
+    /// the message with the given `selector`.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct Call {
+        pub target: AccountId,
+        pub selector: [u8; 4],
+        pub input: Vec<u8>,
+    }
+
+    /// Already encoded arguments, written to the call input as they are.
+    struct CallInput<'a>(&'a [u8]);
+
+    impl<'a> ink::scale::Encode for CallInput<'a> {
+        fn encode_to<T: ink::scale::Output + ?Sized>(&self, dest: &mut T) {
+            dest.write(self.0);
+        }
+    }
+
+    /// Whatever the called message returned. Admin messages return a `Result`,
+    /// so only its discriminant is looked at, a leading 1 being `Err`. The rest of
+    /// the bytes is consumed and dropped since the full return type is unknown.
+    struct CallOutput {
+        failed: bool,
+    }
+
+    impl ink::scale::Decode for CallOutput {
+        fn decode<I: ink::scale::Input>(input: &mut I) -> core::result::Result<Self, ink::scale::Error> {
+            let remaining = input.remaining_len()?.unwrap_or_default();
+            let mut output = ink::prelude::vec![0; remaining];
+            input.read(&mut output)?;
+            Ok(CallOutput {
+                failed: output.first() == Some(&1),
+            })
+        }
+    }
+
+    #[ink(event)]
+    pub struct CallQueued {
+        #[ink(topic)]
+        id: Hash,
+        #[ink(topic)]
+        target: AccountId,
+        selector: [u8; 4],
+        eta: Timestamp,
+    }
+
+    #[ink(event)]
+    pub struct CallCancelled {
+        #[ink(topic)]
+        id: Hash,
+    }
+
+    #[ink(event)]
+    pub struct CallExecuted {
+        #[ink(topic)]
+        id: Hash,
+        #[ink(topic)]
+        target: AccountId,
+        selector: [u8; 4],
+    }
+
+    #[ink(event)]
+    pub struct MinDelayUpdated {
+        old: Timestamp,
+        new: Timestamp,
+    }
+
+    #[ink(storage)]
+    pub struct Timelock {
+        // kept out of the root cell so a call the timelock executes on itself
+        // is not overwritten when `execute` returns
+        min_delay: Lazy<Timestamp>,
+        etas: Mapping<Hash, Timestamp>,
+        access_control: AccessControl,
+    }
+
+    impl Timelock {
+        #[ink(constructor)]
+        pub fn new(min_delay: Timestamp) -> Self {
+            let admin = Self::env().caller();
+            let mut delay = Lazy::new();
+            delay.set(&min_delay);
+            Self {
+                min_delay: delay,
+                etas: Mapping::default(),
+                access_control: AccessControl::new(admin),
+            }
+        }
+
+        /// Queues `call` to become executable after `delay`, which must be at least
+        /// `min_delay`. `salt` tells apart otherwise identical calls. Returns the
+        /// operation id.
+        #[ink(message)]
+        pub fn queue(&mut self, call: Call, salt: [u8; 32], delay: Timestamp) -> Result<Hash> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            if delay < self.get_min_delay() {
+                return Err(Error::DelayTooShort);
+            }
+
+            let id = self.hash_call(call.clone(), salt);
+
+            if self.etas.contains(id) {
+                return Err(Error::AlreadyQueued);
+            }
+
+            let eta = self.env().block_timestamp().checked_add(delay).ok_or(Error::Overflow)?;
+            self.etas.insert(id, &eta);
+
+            self.env().emit_event(CallQueued {
+                id,
+                target: call.target,
+                selector: call.selector,
+                eta,
+            });
+
+            Ok(id)
+        }
+
+        #[ink(message)]
+        pub fn cancel(&mut self, id: Hash) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            if self.etas.take(id).is_none() {
+                return Err(Error::NotFound);
+            }
+
+            self.env().emit_event(CallCancelled { id });
+
+            Ok(())
+        }
+
+        /// Executes a queued call once its delay has passed. Anyone can call this.
+        /// A failing call reverts and the operation stays queued.
+        #[ink(message)]
+        pub fn execute(&mut self, call: Call, salt: [u8; 32]) -> Result<()> {
+            let id = self.hash_call(call.clone(), salt);
+            let eta = self.etas.get(id).ok_or(Error::NotFound)?;
+
+            if self.env().block_timestamp() < eta {
+                return Err(Error::NotReady);
+            }
+
+            self.etas.remove(id);
+
+            let output = build_call::<DefaultEnvironment>()
+                .call(call.target)
+                .call_v1()
+                .gas_limit(0)
+                .call_flags(CallFlags::ALLOW_REENTRY)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(call.selector))
+                        .push_arg(CallInput(&call.input)),
+                )
+                .returns::<CallOutput>()
+                .try_invoke()
+                .map_err(|_| Error::CallFailed)?
+                .map_err(|_| Error::CallFailed)?;
+
+            // the callee reverted with an `Err`, which does not fail the call itself
+            if output.failed {
+                return Err(Error::CallFailed);
+            }
+
+            self.env().emit_event(CallExecuted {
+                id,
+                target: call.target,
+                selector: call.selector,
+            });
+
+            Ok(())
+        }
+
+        /// Changes the minimum delay. Only the timelock itself can call this, so
+        /// the change has to wait out the current delay like any other.
+        #[ink(message)]
+        pub fn set_min_delay(&mut self, min_delay: Timestamp) -> Result<()> {
+            if self.env().caller() != self.env().account_id() {
+                return Err(Error::NotTimelock);
+            }
+
+            let old = self.get_min_delay();
+            self.min_delay.set(&min_delay);
+
+            self.env().emit_event(MinDelayUpdated { old, new: min_delay });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_min_delay(&self) -> Timestamp {
+            self.min_delay.get().unwrap_or_default()
+        }
+
+        /// Time after which the operation can be executed, if it is queued.
+        #[ink(message)]
+        pub fn get_eta(&self, id: Hash) -> Option<Timestamp> {
+            self.etas.get(id)
+        }
+
+        #[ink(message)]
+        pub fn hash_call(&self, call: Call, salt: [u8; 32]) -> Hash {
+            let mut output = <Blake2x256 as HashOutput>::Type::default();
+            ink::env::hash_encoded::<Blake2x256, _>(&(call, salt), &mut output);
+            Hash::from(output)
+        }
+
+        fn check_role(&self, role: RoleType, account: AccountId) -> Result<()> {
+            if !self.access_control.has_role(role, account) {
+                return Err(Error::MissingRole);
+            }
+            Ok(())
+        }
+    }
+
+    impl AccessControlled for Timelock {
+        #[ink(message)]
+        fn grant_role(&mut self, role: RoleType, account: AccountId) -> RoleResult {
+            self.access_control.grant_role(self.env().caller(), role, account)
+        }
+
+        #[ink(message)]
+        fn revoke_role(&mut self, role: RoleType, account: AccountId) -> RoleResult {
+            self.access_control.revoke_role(self.env().caller(), role, account)
+        }
+
+        #[ink(message)]
+        fn renounce_role(&mut self, role: RoleType) -> RoleResult {
+            self.access_control.renounce_role(self.env().caller(), role)
+        }
+
+        #[ink(message)]
+        fn has_role(&self, role: RoleType, account: AccountId) -> bool {
+            self.access_control.has_role(role, account)
+        }
+    }
+
+    // CROSS CONTRACT CALLS ARE NOT INCLUDED IN TESTS
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const DELAY: Timestamp = 1000;
+
+        fn set_fee_call() -> Call {
+            Call {
+                target: AccountId::from([0x1; 32]),
+                selector: ink::selector_bytes!("set_max_utilization_bps"),
+                input: ink::scale::Encode::encode(&9_000u32),
+            }
+        }
+
+        #[ink::test]
+        pub fn queue_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut timelock = Timelock::new(DELAY);
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(500);
+            let id = timelock.queue(set_fee_call(), [0; 32], DELAY).unwrap();
+
+            assert_eq!(id, timelock.hash_call(set_fee_call(), [0; 32]));
+            assert_eq!(timelock.get_eta(id), Some(1500));
+            let other_id = timelock.queue(set_fee_call(), [1; 32], DELAY).unwrap();
+            assert_ne!(other_id, id);
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 2);
+        }
+
+        #[ink::test]
+        pub fn queue_fails() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut timelock = Timelock::new(DELAY);
+
+            assert_eq!(timelock.queue(set_fee_call(), [0; 32], DELAY - 1), Err(Error::DelayTooShort));
+
+            timelock.queue(set_fee_call(), [0; 32], DELAY).unwrap();
+            assert_eq!(timelock.queue(set_fee_call(), [0; 32], DELAY), Err(Error::AlreadyQueued));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(timelock.queue(set_fee_call(), [1; 32], DELAY), Err(Error::MissingRole));
+        }
+
+        #[ink::test]
+        pub fn cancel_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut timelock = Timelock::new(DELAY);
+
+            let id = timelock.queue(set_fee_call(), [0; 32], DELAY).unwrap();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(timelock.cancel(id), Err(Error::MissingRole));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(timelock.cancel(id), Ok(()));
+            assert_eq!(timelock.get_eta(id), None);
+            assert_eq!(timelock.cancel(id), Err(Error::NotFound));
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(DELAY);
+            assert_eq!(timelock.execute(set_fee_call(), [0; 32]), Err(Error::NotFound));
+        }
+
+        #[ink::test]
+        pub fn execute_before_delay_fails() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut timelock = Timelock::new(DELAY);
+
+            let id = timelock.queue(set_fee_call(), [0; 32], DELAY).unwrap();
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(DELAY - 1);
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(timelock.execute(set_fee_call(), [0; 32]), Err(Error::NotReady));
+            assert_eq!(timelock.get_eta(id), Some(DELAY));
+        }
+
+        #[ink::test]
+        pub fn set_min_delay_only_timelock() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            ink::env::test::set_callee::<ink::env::DefaultEnvironment>(accounts.django);
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut timelock = Timelock::new(DELAY);
+
+            assert_eq!(timelock.set_min_delay(0), Err(Error::NotTimelock));
+            assert_eq!(timelock.get_min_delay(), DELAY);
+
+            let timelock_address = ink::env::account_id::<ink::env::DefaultEnvironment>();
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(timelock_address);
+            assert_eq!(timelock.set_min_delay(2 * DELAY), Ok(()));
+            assert_eq!(timelock.get_min_delay(), 2 * DELAY);
+        }
+    }
+}
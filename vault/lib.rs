@@ -1,8 +1,11 @@
 #![cfg_attr(not(feature = "std"), no_std, no_main)]
 
+pub use self::vault::{Vault, VaultRef};
+
 pub type TokenId = u128;
 pub type Result<T> = core::result::Result<T, Error>;
 pub type PositionId = u128;
+pub type WithdrawalId = u128;
 
 #[derive(Debug, PartialEq, Eq)]
 #[ink::scale_derive(Encode, Decode, TypeInfo)]
@@ -11,14 +14,64 @@ pub enum Error {
     Underflow,
     ZeroAmount,
     NonZeroAmount,
+    NotFound,
+    NotOwner,
+    NotPendingOwner,
+    InvalidParameter,
+    InsufficientBalance,
+    CooldownNotElapsed,
+    EpochWithdrawalLimitExceeded,
+    InsufficientLiquidity,
+    Insolvent,
+    AlreadyMigrated,
+    MissingRole,
+    UpdateNotReady,
+    LedgersNotEmpty,
 }
 
 #[ink::contract]
 mod vault {
     use super::*;
+    use access_control::{
+        AccessControl, AccessControlled, Ownership, OwnershipTransferStarted,
+        OwnershipTransferred, RoleGranted, RoleResult, RoleRevoked, RoleType,
+        ADMIN, MANAGER_CONTRACT,
+    };
     use ink::env::call::{build_call, ExecutionInput, Selector};
     use ink::env::DefaultEnvironment;
-    use ink::{contract_ref, storage::Mapping};
+    use ink::prelude::vec::Vec;
+    use ink::{
+        contract_ref,
+        storage::{Lazy, Mapping},
+    };
+
+    const STORAGE_VERSION: u32 = 1;
+    const BPS_DENOMINATOR: Balance = 10_000;
+    const DAY: Timestamp = 24 * 60 * 60 * 1000;
+    const DEFAULT_WITHDRAWAL_COOLDOWN: Timestamp = 7 * DAY;
+    const DEFAULT_EPOCH_DURATION: Timestamp = DAY;
+    const DEFAULT_MAX_EPOCH_WITHDRAWAL_BPS: u32 = 2_000;
+    const LP_INDEX_PRECISION: Balance = 1_000_000_000_000;
+
+    /// A pending LP withdrawal that can be claimed once `unlock_time` has passed.
+    /// `amount` was requested when the LP index stood at `lp_index` and moves with
+    /// the index until it is claimed.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct WithdrawalRequest {
+        id: WithdrawalId,
+        amount: Balance,
+        unlock_time: Timestamp,
+        lp_index: Balance,
+    }
+
+    /// Direction and size of the erc20 transfer an update of trader collateral requires.
+    #[derive(Debug, PartialEq, Eq)]
+    enum TokenFlow {
+        In(Balance),
+        Out(Balance),
+    }
 
     #[ink(event)]
     pub struct AddLiquidity {
@@ -27,6 +80,7 @@ mod vault {
         token: TokenId,
         #[ink(topic)]
         amount: Balance,
+        fee: Balance,
     }
 
     #[ink(event)]
@@ -36,6 +90,7 @@ mod vault {
         token: TokenId,
         #[ink(topic)]
         amount: Balance,
+        fee: Balance,
     }
 
     #[ink(event)]
@@ -45,52 +100,388 @@ mod vault {
         token: TokenId,
         #[ink(topic)]
         amount: Balance,
+        fee: Balance,
+    }
+
+    #[ink(event)]
+    pub struct LpDeposited {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        amount: Balance,
+    }
+
+    #[ink(event)]
+    pub struct WithdrawalRequested {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        request_id: WithdrawalId,
+        amount: Balance,
+        unlock_time: Timestamp,
+    }
+
+    #[ink(event)]
+    pub struct WithdrawalClaimed {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        request_id: WithdrawalId,
+        amount: Balance,
+    }
+
+    #[ink(event)]
+    pub struct FeeCollected {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        token: TokenId,
+        fee: Balance,
+    }
+
+    #[ink(event)]
+    pub struct RebateCredited {
+        #[ink(topic)]
+        referrer: AccountId,
+        amount: Balance,
+    }
+
+    #[ink(event)]
+    pub struct RebateClaimed {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        amount: Balance,
+    }
+
+    #[ink(event)]
+    pub struct InsuranceDeposited {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        amount: Balance,
+    }
+
+    #[ink(event)]
+    pub struct InsuranceUsed {
+        amount: Balance,
+        insurance_fund: Balance,
+    }
+
+    #[ink(event)]
+    pub struct ProfitPaid {
+        #[ink(topic)]
+        from: Option<AccountId>,
+        token: TokenId,
+        amount: Balance,
+    }
+
+    #[ink(event)]
+    pub struct LiquidityReserved {
+        amount: Balance,
+        reserved_liquidity: Balance,
+    }
+
+    #[ink(event)]
+    pub struct LiquidityReleased {
+        amount: Balance,
+        reserved_liquidity: Balance,
+    }
+
+    #[ink(event)]
+    pub struct Upgraded {
+        code_hash: Hash,
+    }
+
+    #[ink(event)]
+    pub struct Migrated {
+        version: u32,
+    }
+
+    /// Contract address this contract talks to that the admin can replace.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub enum AddressKind {
+        Distributor,
+        Erc20Contract,
+    }
+
+    /// Address change waiting for `address_update_delay` to pass.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct PendingAddressUpdate {
+        pub address: AccountId,
+        pub eta: Timestamp,
+    }
+
+    /// Change of `address_update_delay` waiting for the current delay to pass.
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub struct PendingDelayUpdate {
+        pub delay: Timestamp,
+        pub eta: Timestamp,
+    }
+
+    #[ink(event)]
+    pub struct DistributorUpdated {
+        old: AccountId,
+        #[ink(topic)]
+        new: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct Erc20ContractUpdated {
+        old: AccountId,
+        #[ink(topic)]
+        new: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct AddressUpdateQueued {
+        kind: AddressKind,
+        #[ink(topic)]
+        address: AccountId,
+        eta: Timestamp,
+    }
+
+    #[ink(event)]
+    pub struct AddressUpdateCancelled {
+        kind: AddressKind,
+    }
+
+    #[ink(event)]
+    pub struct AddressUpdateDelayQueued {
+        delay: Timestamp,
+        eta: Timestamp,
+    }
+
+    #[ink(event)]
+    pub struct AddressUpdateDelayUpdated {
+        old: Timestamp,
+        new: Timestamp,
+    }
+
+    #[ink(event)]
+    pub struct AddressUpdateDelayCancelled {
+        delay: Timestamp,
     }
 
     #[ink(storage)]
     pub struct Vault {
         contributors: Mapping<(AccountId, TokenId), Balance>,
         erc20contract: AccountId,
-        fee: Balance,
-        total_amount_deposit: Balance,
+        trader_collateral: Balance,
+        accrued_fees: Balance,
+        insurance_fund: Balance,
         distributor: AccountId,
+        ownership: Ownership,
+        lp_balances: Mapping<AccountId, Balance>,
+        total_lp_deposit: Balance,
+        withdrawal_requests: Mapping<AccountId, Vec<WithdrawalRequest>>,
+        next_withdrawal_id: WithdrawalId,
+        withdrawal_cooldown: Timestamp,
+        epoch_duration: Timestamp,
+        max_epoch_withdrawal_bps: u32,
+        current_epoch: u64,
+        epoch_start_lp_deposit: Balance,
+        epoch_withdrawn: Balance,
+        reserved_liquidity: Balance,
+        version: u32,
+        access_control: AccessControl,
+        address_update_delay: Lazy<Timestamp>,
+        pending_address_updates: Mapping<AddressKind, PendingAddressUpdate>,
+        /// Part of the collected fees owed to referrers, claimable by them and
+        /// never swept to the distributor.
+        referral_rebates: Mapping<AccountId, Balance>,
+        total_referral_rebates: Lazy<Balance>,
+        /// Growth of LP balances since deployment, scaled by `LP_INDEX_PRECISION`.
+        /// An LP balance is scaled by the change of the index since the LP's
+        /// checkpoint, so capital added to or taken from the LPs as a whole is
+        /// shared pro rata without touching every balance.
+        lp_index: Lazy<Balance>,
+        lp_index_checkpoints: Mapping<AccountId, Balance>,
+        pending_address_update_delay: Lazy<Option<PendingDelayUpdate>>,
     }
 
     impl Vault {
         #[ink(constructor)]
-        pub fn new(erc20_contract_address: AccountId, fee: Balance, distributor_address: AccountId) -> Self {
+        pub fn new(erc20_contract_address: AccountId, distributor_address: AccountId) -> Self {
             let contributors = Mapping::default();
             let erc20contract = erc20_contract_address;
-            let total_amount_deposit = 0;
             let distributor = distributor_address;
+            let owner = Self::env().caller();
             Self {
                 contributors,
                 erc20contract,
-                fee,
-                total_amount_deposit,
+                trader_collateral: 0,
+                accrued_fees: 0,
+                insurance_fund: 0,
                 distributor,
+                ownership: Ownership::new(owner),
+                lp_balances: Mapping::default(),
+                total_lp_deposit: 0,
+                withdrawal_requests: Mapping::default(),
+                next_withdrawal_id: 0,
+                withdrawal_cooldown: DEFAULT_WITHDRAWAL_COOLDOWN,
+                epoch_duration: DEFAULT_EPOCH_DURATION,
+                max_epoch_withdrawal_bps: DEFAULT_MAX_EPOCH_WITHDRAWAL_BPS,
+                current_epoch: 0,
+                epoch_start_lp_deposit: 0,
+                epoch_withdrawn: 0,
+                reserved_liquidity: 0,
+                version: STORAGE_VERSION,
+                access_control: AccessControl::new(owner),
+                address_update_delay: Lazy::new(),
+                pending_address_updates: Mapping::default(),
+                referral_rebates: Mapping::default(),
+                total_referral_rebates: Lazy::new(),
+                lp_index: Lazy::new(),
+                lp_index_checkpoints: Mapping::default(),
+                pending_address_update_delay: Lazy::new(),
+            }
+        }
+
+        /// Swaps the contract code for `code_hash` while keeping storage. The plain
+        /// fields of `Vault` are encoded together in the root cell, so new code
+        /// must leave them as they are and add fields as `Lazy` or `Mapping`
+        /// only. It must bump `STORAGE_VERSION` if it needs `migrate` to run.
+        #[ink(message)]
+        pub fn upgrade(&mut self, code_hash: Hash) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            self.env().set_code_hash(&code_hash).unwrap_or_else(|err| {
+                panic!("failed to set code hash {:?}: {:?}", code_hash, err)
+            });
+
+            self.env().emit_event(Upgraded { code_hash });
+
+            Ok(())
+        }
+
+        /// Runs the storage migrations from the stored `version` up to
+        /// `STORAGE_VERSION`. Called by the owner once after an upgrade.
+        #[ink(message)]
+        pub fn migrate(&mut self) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            if self.version >= STORAGE_VERSION {
+                return Err(Error::AlreadyMigrated);
+            }
+
+            self.version = STORAGE_VERSION;
+
+            self.env().emit_event(Migrated {
+                version: self.version,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_version(&self) -> u32 {
+            self.version
+        }
+
+        /// Proposes `new_owner` as the next owner. Nothing changes until
+        /// `new_owner` calls `accept_ownership`.
+        #[ink(message)]
+        pub fn transfer_ownership(&mut self, new_owner: AccountId) -> Result<()> {
+            self.check_owner(self.env().caller())?;
+
+            self.ownership.start_transfer(new_owner);
+
+            self.env().emit_event(OwnershipTransferStarted {
+                previous_owner: self.ownership.owner(),
+                new_owner,
+            });
+
+            Ok(())
+        }
+
+        /// Completes a transfer started by `transfer_ownership`. `ADMIN` moves from
+        /// the previous owner to the caller along with ownership.
+        #[ink(message)]
+        pub fn accept_ownership(&mut self) -> Result<()> {
+            let caller = self.env().caller();
+            let previous_owner = self.ownership.owner();
+
+            if !self.ownership.accept(caller) {
+                return Err(Error::NotPendingOwner);
+            }
+
+            if let Some(previous_owner) = previous_owner {
+                if self.access_control.revoke(ADMIN, previous_owner) {
+                    self.env().emit_event(RoleRevoked {
+                        role: ADMIN,
+                        account: previous_owner,
+                        sender: caller,
+                    });
+                }
+            }
+
+            if self.access_control.grant(ADMIN, caller) {
+                self.env().emit_event(RoleGranted {
+                    role: ADMIN,
+                    account: caller,
+                    sender: caller,
+                });
+            }
+
+            self.env().emit_event(OwnershipTransferred {
+                previous_owner,
+                new_owner: Some(caller),
+            });
+
+            Ok(())
+        }
+
+        /// Leaves the contract without an owner, which disables `upgrade` and
+        /// `migrate` for good. The owner's `ADMIN` goes with it, other admins
+        /// keep theirs.
+        #[ink(message)]
+        pub fn renounce_ownership(&mut self) -> Result<()> {
+            let caller = self.env().caller();
+            self.check_owner(caller)?;
+
+            let previous_owner = self.ownership.owner();
+            self.ownership.renounce();
+
+            if self.access_control.revoke(ADMIN, caller) {
+                self.env().emit_event(RoleRevoked {
+                    role: ADMIN,
+                    account: caller,
+                    sender: caller,
+                });
             }
+
+            self.env().emit_event(OwnershipTransferred {
+                previous_owner,
+                new_owner: None,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn owner(&self) -> Option<AccountId> {
+            self.ownership.owner()
         }
 
+        #[ink(message)]
+        pub fn pending_owner(&self) -> Option<AccountId> {
+            self.ownership.pending_owner()
+        }
+
+        /// Deposits `amount` of trader collateral and charges `fee` on top of it.
         #[ink(message)]
         pub fn add_liquidity(
             &mut self,
             token: TokenId,
             amount: Balance,
+            fee: Balance,
             user: AccountId,
         ) -> Result<()> {
-            let current_amount = self.contributors.get(&(user, token)).unwrap_or_default();
-
-            if current_amount > 0 {
-                return Err(Error::NonZeroAmount);
-            }
+            self.check_role(MANAGER_CONTRACT, self.env().caller())?;
 
-            self.contributors.insert((user, token), &amount);
-
-            let deposit_amount = amount.checked_add(self.fee).ok_or(Error::Overflow)?;
+            let deposit_amount = self.record_add_liquidity(token, amount, fee, user)?;
 
-            self.total_amount_deposit = self.total_amount_deposit.checked_add(deposit_amount).ok_or(Error::Overflow)?;
-            
             let deposit = build_call::<DefaultEnvironment>()
                 .call(self.erc20contract)
                 .call_v1()
@@ -108,6 +499,7 @@ mod vault {
                 from: Some(user),
                 token,
                 amount,
+                fee,
             });
 
             Ok(())
@@ -118,76 +510,64 @@ mod vault {
             &mut self,
             token: TokenId,
             new_amount: Balance,
+            fee: Balance,
             user: AccountId,
         ) -> Result<()> {
-            let amount = self.contributors.get(&(user, token)).unwrap_or_default();
-
-            if amount == 0 {
-                return Err(Error::ZeroAmount);
-            }
+            self.check_role(MANAGER_CONTRACT, self.env().caller())?;
 
-            let mut new_amount_final = 0;
-
-            if new_amount > amount {
-                new_amount_final = new_amount.checked_sub(amount).ok_or(Error::Underflow)?;
-                let new_amount_final_with_fee = new_amount_final.checked_add(self.fee).ok_or(Error::Overflow)?;
-
-                self.total_amount_deposit = self.total_amount_deposit.checked_add(new_amount_final_with_fee).ok_or(Error::Overflow)?;
-
-                let deposit = build_call::<DefaultEnvironment>()
-                    .call(self.erc20contract)
-                    .call_v1()
-                    .gas_limit(0)
-                    .exec_input(
-                        ExecutionInput::new(Selector::new(ink::selector_bytes!("transfer_from")))
-                            .push_arg(user)
-                            .push_arg(self.env().account_id())
-                            .push_arg(new_amount_final_with_fee),
-                    )
-                    .returns::<bool>()
-                    .invoke();
-            } else {
-                new_amount_final = amount.checked_sub(new_amount).ok_or(Error::Underflow)?;
-                self.total_amount_deposit = self.total_amount_deposit.checked_sub(new_amount_final).ok_or(Error::Underflow)?;
-                self.total_amount_deposit = self.total_amount_deposit.checked_add(self.fee).ok_or(Error::Overflow)?;
-
-                let withdraw = build_call::<DefaultEnvironment>()
-                    .call(self.erc20contract)
-                    .call_v1()
-                    .gas_limit(0)
-                    .exec_input(
-                        ExecutionInput::new(Selector::new(ink::selector_bytes!("transfer")))
-                            .push_arg(user)
-                            .push_arg(new_amount_final.checked_sub(self.fee).ok_or(Error::Underflow)?),
-                    )
-                    .returns::<bool>()
-                    .invoke();
-            }
+            let amount = self.contributors.get(&(user, token)).unwrap_or_default();
 
-            self.contributors.insert((user, token), &new_amount);
+            let new_amount_final = match self.record_update_liquidity(token, new_amount, fee, user)? {
+                TokenFlow::In(deposit_amount) => {
+                    let deposit = build_call::<DefaultEnvironment>()
+                        .call(self.erc20contract)
+                        .call_v1()
+                        .gas_limit(0)
+                        .exec_input(
+                            ExecutionInput::new(Selector::new(ink::selector_bytes!("transfer_from")))
+                                .push_arg(user)
+                                .push_arg(self.env().account_id())
+                                .push_arg(deposit_amount),
+                        )
+                        .returns::<bool>()
+                        .invoke();
+
+                    new_amount.checked_sub(amount).ok_or(Error::Underflow)?
+                }
+                TokenFlow::Out(withdraw_amount) => {
+                    let withdraw = build_call::<DefaultEnvironment>()
+                        .call(self.erc20contract)
+                        .call_v1()
+                        .gas_limit(0)
+                        .exec_input(
+                            ExecutionInput::new(Selector::new(ink::selector_bytes!("transfer")))
+                                .push_arg(user)
+                                .push_arg(withdraw_amount),
+                        )
+                        .returns::<bool>()
+                        .invoke();
+
+                    amount.checked_sub(new_amount).ok_or(Error::Underflow)?
+                }
+            };
 
             self.env().emit_event(UpdateLiquidity {
                 from: Some(user),
                 token,
                 amount: new_amount_final,
+                fee,
             });
 
             Ok(())
         }
 
         #[ink(message)]
-        pub fn remove_liquidity(&mut self, token: TokenId, user: AccountId) -> Result<()> {
-            let current_amount = self.contributors.get(&(user, token)).unwrap_or_default();
-
-            if current_amount == 0 {
-                return Err(Error::ZeroAmount);
-            }
-
-            let remove_amount = current_amount.checked_sub(self.fee).ok_or(Error::Underflow)?;
+        pub fn remove_liquidity(&mut self, token: TokenId, fee: Balance, user: AccountId) -> Result<()> {
+            self.check_role(MANAGER_CONTRACT, self.env().caller())?;
 
-            self.contributors.remove((user, token));
+            let current_amount = self.contributors.get(&(user, token)).unwrap_or_default();
 
-            self.total_amount_deposit = self.total_amount_deposit.checked_sub(remove_amount).ok_or(Error::Underflow)?;
+            let remove_amount = self.record_remove_liquidity(token, fee, user)?;
 
             let withdraw = build_call::<DefaultEnvironment>()
                 .call(self.erc20contract)
@@ -205,40 +585,82 @@ mod vault {
                 from: Some(user),
                 token,
                 amount: current_amount,
+                fee,
             });
 
             Ok(())
         }
 
+        /// Moves `fee` out of a trader's collateral into the fee ledger without any
+        /// token transfer, e.g. for maintenance fees.
         #[ink(message)]
-        pub fn liquidation(&mut self, token: TokenId, user: AccountId) -> Result<()> {
-            let current_amount = self.contributors.get(&(user, token)).unwrap_or_default();
+        pub fn collect_fee(&mut self, token: TokenId, fee: Balance, user: AccountId) -> Result<()> {
+            self.check_role(MANAGER_CONTRACT, self.env().caller())?;
+            self.record_fee_collection(token, fee, user)?;
 
-            if current_amount == 0 {
-                return Err(Error::ZeroAmount);
-            }
+            self.env().emit_event(FeeCollected {
+                from: Some(user),
+                token,
+                fee,
+            });
 
-            self.contributors.remove((user, token));
+            Ok(())
+        }
+
+        /// Moves `amount` of the accrued fees to the rebate ledger of `referrer`.
+        #[ink(message)]
+        pub fn credit_rebate(&mut self, referrer: AccountId, amount: Balance) -> Result<()> {
+            self.check_role(MANAGER_CONTRACT, self.env().caller())?;
+            self.record_rebate_credit(referrer, amount)?;
+
+            self.env().emit_event(RebateCredited { referrer, amount });
 
             Ok(())
         }
 
+        /// Pays out the referral rebates credited to the caller.
         #[ink(message)]
-        pub fn withdraw_distributor(&mut self) -> Result<()>{
-            //calculate remove amount
-            //contract call returns wrong amount or zero amount
-            let total_amount_in_vault: Balance = build_call::<DefaultEnvironment>()
+        pub fn claim_rebate(&mut self) -> Result<Balance> {
+            let caller = self.env().caller();
+            let amount = self.record_rebate_claim(caller)?;
+
+            let claim = build_call::<DefaultEnvironment>()
                 .call(self.erc20contract)
                 .call_v1()
                 .gas_limit(0)
                 .exec_input(
-                    ExecutionInput::new(Selector::new(ink::selector_bytes!("balance_of")))
-                        .push_arg(self.env().account_id())
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("transfer")))
+                        .push_arg(caller)
+                        .push_arg(amount),
                 )
-                .returns::<Balance>()
+                .returns::<bool>()
                 .invoke();
 
-            self.total_amount_deposit = self.total_amount_deposit.checked_sub(withdraw_amount).ok_or(Error::Underflow)?;
+            self.env().emit_event(RebateClaimed {
+                from: Some(caller),
+                amount,
+            });
+
+            Ok(amount)
+        }
+
+        /// Seizes the collateral of a liquidated position into the insurance fund.
+        #[ink(message)]
+        pub fn liquidation(&mut self, token: TokenId, user: AccountId) -> Result<()> {
+            self.check_role(MANAGER_CONTRACT, self.env().caller())?;
+            self.record_liquidation(token, user)
+        }
+
+        /// Sends the fees accrued since the last call to the distributor and returns
+        /// the amount sent. Trader collateral, LP capital and the insurance fund are
+        /// never touched.
+        #[ink(message)]
+        pub fn withdraw_distributor(&mut self) -> Result<Balance> {
+            let withdraw_amount = self.record_fee_withdrawal();
+
+            if withdraw_amount == 0 {
+                return Ok(0);
+            }
 
             let withdraw = build_call::<DefaultEnvironment>()
                 .call(self.erc20contract)
@@ -247,157 +669,1767 @@ mod vault {
                 .exec_input(
                     ExecutionInput::new(Selector::new(ink::selector_bytes!("transfer")))
                         .push_arg(self.distributor)
-                        //change self.fee na pravu vrednost
-                        .push_arg(total_amount_in_vault.checked_sub(self.total_amount_deposit).ok_or(Error::Underflow)?),
+                        .push_arg(withdraw_amount),
                 )
                 .returns::<bool>()
                 .invoke();
 
-            Ok(())
+            Ok(withdraw_amount)
         }
 
+        /// Tops up the insurance fund that absorbs bad debt from liquidations.
         #[ink(message)]
-        pub fn get_contributor_balance(&self, account: AccountId, token: TokenId) -> Balance {
-            self.contributors.get(&(account, token)).unwrap_or_default()
+        pub fn deposit_insurance(&mut self, amount: Balance) -> Result<()> {
+            if amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            let caller = self.env().caller();
+            self.insurance_fund = self.insurance_fund.checked_add(amount).ok_or(Error::Overflow)?;
+
+            let deposit = build_call::<DefaultEnvironment>()
+                .call(self.erc20contract)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("transfer_from")))
+                        .push_arg(caller)
+                        .push_arg(self.env().account_id())
+                        .push_arg(amount)
+                )
+                .returns::<bool>()
+                .invoke();
+
+            self.env().emit_event(InsuranceDeposited {
+                from: Some(caller),
+                amount,
+            });
+
+            Ok(())
         }
 
+        /// Covers bad debt left by a liquidation by moving `amount` from the insurance
+        /// fund back to LP capital.
         #[ink(message)]
-        pub fn get_total_amount_deposit(&self) -> Balance {
-            self.total_amount_deposit
+        pub fn use_insurance_fund(&mut self, amount: Balance) -> Result<()> {
+            self.check_role(MANAGER_CONTRACT, self.env().caller())?;
+            self.record_insurance_use(amount)?;
+
+            self.env().emit_event(InsuranceUsed {
+                amount,
+                insurance_fund: self.insurance_fund,
+            });
+
+            Ok(())
         }
-    }
+
+        /// Moves a trader's realized profit from LP capital to the trader's collateral,
+        /// where it is paid out with the collateral when the position is reduced or
+        /// closed. The loss is shared by the LPs pro rata.
+        #[ink(message)]
+        pub fn pay_profit(&mut self, token: TokenId, amount: Balance, user: AccountId) -> Result<()> {
+            self.check_role(MANAGER_CONTRACT, self.env().caller())?;
+            self.record_profit_payment(token, amount, user)?;
+
+            self.env().emit_event(ProfitPaid {
+                from: Some(user),
+                token,
+                amount,
+            });
+
+            Ok(())
+        }
+
+        /// Compares the vault's erc20 balance against the sum of its ledgers and
+        /// returns the surplus, or `Error::Insolvent` if the ledgers are not covered.
+        #[ink(message)]
+        pub fn check_solvency(&self) -> Result<Balance> {
+            let token_balance: Balance = build_call::<DefaultEnvironment>()
+                .call(self.erc20contract)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("balance_of")))
+                        .push_arg(self.env().account_id())
+                )
+                .returns::<Balance>()
+                .invoke();
+
+            self.solvency(token_balance)
+        }
+
+        /// Deposits LP capital into the vault. LP capital is kept on its own ledger,
+        /// so it is never swept to the distributor as fees.
+        #[ink(message)]
+        pub fn deposit_lp(&mut self, amount: Balance) -> Result<()> {
+            if amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            let caller = self.env().caller();
+            self.record_lp_deposit(caller, amount)?;
+
+            let deposit = build_call::<DefaultEnvironment>()
+                .call(self.erc20contract)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("transfer_from")))
+                        .push_arg(caller)
+                        .push_arg(self.env().account_id())
+                        .push_arg(amount)
+                )
+                .returns::<bool>()
+                .invoke();
+
+            self.env().emit_event(LpDeposited {
+                from: Some(caller),
+                amount,
+            });
+
+            Ok(())
+        }
+
+        /// Queues a withdrawal of LP capital. The amount stops counting towards the
+        /// caller's LP balance immediately but stays in the vault until claimed and
+        /// keeps sharing LP gains and losses until then.
+        #[ink(message)]
+        pub fn request_withdrawal(&mut self, amount: Balance) -> Result<WithdrawalId> {
+            if amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            let caller = self.env().caller();
+            let lp_balance = self.settle_lp_balance(caller)?;
+
+            if amount > lp_balance {
+                return Err(Error::InsufficientBalance);
+            }
+
+            self.lp_balances.insert(caller, &lp_balance.checked_sub(amount).ok_or(Error::Underflow)?);
+
+            let request_id = self.next_withdrawal_id;
+            self.next_withdrawal_id = self.next_withdrawal_id.checked_add(1).ok_or(Error::Overflow)?;
+
+            let unlock_time = self
+                .env()
+                .block_timestamp()
+                .checked_add(self.withdrawal_cooldown)
+                .ok_or(Error::Overflow)?;
+
+            let mut requests = self.withdrawal_requests.get(caller).unwrap_or_default();
+            requests.push(WithdrawalRequest {
+                id: request_id,
+                amount,
+                unlock_time,
+                lp_index: self.lp_index(),
+            });
+            self.withdrawal_requests.insert(caller, &requests);
+
+            self.env().emit_event(WithdrawalRequested {
+                from: Some(caller),
+                request_id,
+                amount,
+                unlock_time,
+            });
+
+            Ok(request_id)
+        }
+
+        /// Pays out a queued withdrawal once its cooldown has elapsed, as long as the
+        /// total claimed in the current epoch stays within `max_epoch_withdrawal_bps`
+        /// of the LP capital held at the start of the epoch.
+        #[ink(message)]
+        pub fn claim_withdrawal(&mut self, request_id: WithdrawalId) -> Result<()> {
+            let caller = self.env().caller();
+            let request = self.record_withdrawal_claim(caller, request_id)?;
+
+            let withdraw = build_call::<DefaultEnvironment>()
+                .call(self.erc20contract)
+                .call_v1()
+                .gas_limit(0)
+                .exec_input(
+                    ExecutionInput::new(Selector::new(ink::selector_bytes!("transfer")))
+                        .push_arg(caller)
+                        .push_arg(request.amount),
+                )
+                .returns::<bool>()
+                .invoke();
+
+            self.env().emit_event(WithdrawalClaimed {
+                from: Some(caller),
+                request_id,
+                amount: request.amount,
+            });
+
+            Ok(())
+        }
+
+        /// Locks LP capital to back the maximum payout of an open position.
+        #[ink(message)]
+        pub fn reserve_liquidity(&mut self, amount: Balance) -> Result<()> {
+            self.check_role(MANAGER_CONTRACT, self.env().caller())?;
+
+            if amount > self.get_available_liquidity() {
+                return Err(Error::InsufficientLiquidity);
+            }
+
+            self.reserved_liquidity = self.reserved_liquidity.checked_add(amount).ok_or(Error::Overflow)?;
+
+            self.env().emit_event(LiquidityReserved {
+                amount,
+                reserved_liquidity: self.reserved_liquidity,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn release_liquidity(&mut self, amount: Balance) -> Result<()> {
+            self.check_role(MANAGER_CONTRACT, self.env().caller())?;
+            self.reserved_liquidity = self.reserved_liquidity.checked_sub(amount).ok_or(Error::Underflow)?;
+
+            self.env().emit_event(LiquidityReleased {
+                amount,
+                reserved_liquidity: self.reserved_liquidity,
+            });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_reserved_liquidity(&self) -> Balance {
+            self.reserved_liquidity
+        }
+
+        /// LP capital that is not reserved for open positions.
+        #[ink(message)]
+        pub fn get_available_liquidity(&self) -> Balance {
+            self.total_lp_deposit.saturating_sub(self.reserved_liquidity)
+        }
+
+        #[ink(message)]
+        pub fn set_withdrawal_cooldown(&mut self, cooldown: Timestamp) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+            self.withdrawal_cooldown = cooldown;
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn set_epoch_duration(&mut self, duration: Timestamp) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            if duration == 0 {
+                return Err(Error::InvalidParameter);
+            }
+
+            self.epoch_duration = duration;
+            self.roll_epoch();
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn set_max_epoch_withdrawal_bps(&mut self, bps: u32) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            if bps as Balance > BPS_DENOMINATOR {
+                return Err(Error::InvalidParameter);
+            }
+
+            self.max_epoch_withdrawal_bps = bps;
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn set_distributor(&mut self, distributor: AccountId) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+            self.request_address_update(AddressKind::Distributor, distributor)
+        }
+
+        #[ink(message)]
+        pub fn get_distributor(&self) -> AccountId {
+            self.distributor
+        }
+
+        /// Replaces the token the vault holds. Only possible while every ledger is
+        /// empty, both when the change is requested and when it is applied, as
+        /// nothing held in the old token moves over.
+        #[ink(message)]
+        pub fn set_erc20_contract(&mut self, erc20contract: AccountId) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+            self.check_ledgers_empty()?;
+            self.request_address_update(AddressKind::Erc20Contract, erc20contract)
+        }
+
+        #[ink(message)]
+        pub fn get_erc20_contract(&self) -> AccountId {
+            self.erc20contract
+        }
+
+        /// Applies a queued address change once its delay has passed. Anyone can
+        /// call this.
+        #[ink(message)]
+        pub fn apply_address_update(&mut self, kind: AddressKind) -> Result<()> {
+            let update = self.pending_address_updates.get(kind).ok_or(Error::NotFound)?;
+
+            if self.env().block_timestamp() < update.eta {
+                return Err(Error::UpdateNotReady);
+            }
+
+            if kind == AddressKind::Erc20Contract {
+                self.check_ledgers_empty()?;
+            }
+
+            self.pending_address_updates.remove(kind);
+            self.update_address(kind, update.address);
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn cancel_address_update(&mut self, kind: AddressKind) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            if self.pending_address_updates.take(kind).is_none() {
+                return Err(Error::NotFound);
+            }
+
+            self.env().emit_event(AddressUpdateCancelled { kind });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_pending_address_update(&self, kind: AddressKind) -> Option<PendingAddressUpdate> {
+            self.pending_address_updates.get(kind)
+        }
+
+        /// Sets how long address changes wait before they can be applied. With 0
+        /// the setters take effect immediately. While a delay is set, a new one
+        /// is queued behind it like an address change, so lowering the delay
+        /// can't be used to skip it.
+        #[ink(message)]
+        pub fn set_address_update_delay(&mut self, delay: Timestamp) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            let current = self.get_address_update_delay();
+
+            if current == 0 {
+                self.update_address_update_delay(delay);
+                return Ok(());
+            }
+
+            let eta = self
+                .env()
+                .block_timestamp()
+                .checked_add(current)
+                .ok_or(Error::Overflow)?;
+            self.pending_address_update_delay.set(&Some(PendingDelayUpdate { delay, eta }));
+
+            self.env().emit_event(AddressUpdateDelayQueued { delay, eta });
+
+            Ok(())
+        }
+
+        /// Applies a queued delay change once the current delay has passed. Anyone
+        /// can call this.
+        #[ink(message)]
+        pub fn apply_address_update_delay(&mut self) -> Result<()> {
+            let update = self.get_pending_address_update_delay().ok_or(Error::NotFound)?;
+
+            if self.env().block_timestamp() < update.eta {
+                return Err(Error::UpdateNotReady);
+            }
+
+            self.update_address_update_delay(update.delay);
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn cancel_address_update_delay(&mut self) -> Result<()> {
+            self.check_role(ADMIN, self.env().caller())?;
+
+            let update = self.get_pending_address_update_delay().ok_or(Error::NotFound)?;
+            self.pending_address_update_delay.set(&None);
+
+            self.env().emit_event(AddressUpdateDelayCancelled { delay: update.delay });
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_address_update_delay(&self) -> Timestamp {
+            self.address_update_delay.get().unwrap_or_default()
+        }
+
+        #[ink(message)]
+        pub fn get_pending_address_update_delay(&self) -> Option<PendingDelayUpdate> {
+            self.pending_address_update_delay.get().flatten()
+        }
+
+        /// Queued withdrawals of `account` with their amounts at the current index.
+        #[ink(message)]
+        pub fn get_pending_withdrawals(&self, account: AccountId) -> Vec<WithdrawalRequest> {
+            self.withdrawal_requests
+                .get(account)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|mut request| {
+                    request.amount = self.withdrawal_amount(&request).unwrap_or_default();
+                    request
+                })
+                .collect()
+        }
+
+        #[ink(message)]
+        pub fn get_lp_balance(&self, account: AccountId) -> Balance {
+            self.lp_balance(account).unwrap_or_default()
+        }
+
+        #[ink(message)]
+        pub fn get_total_lp_deposit(&self) -> Balance {
+            self.total_lp_deposit
+        }
+
+        #[ink(message)]
+        pub fn get_withdrawal_cooldown(&self) -> Timestamp {
+            self.withdrawal_cooldown
+        }
+
+        #[ink(message)]
+        pub fn get_max_epoch_withdrawal_bps(&self) -> u32 {
+            self.max_epoch_withdrawal_bps
+        }
+
+        #[ink(message)]
+        pub fn get_contributor_balance(&self, account: AccountId, token: TokenId) -> Balance {
+            self.contributors.get(&(account, token)).unwrap_or_default()
+        }
+
+        /// Trader collateral plus LP capital, i.e. everything deposited into the
+        /// vault that is not a fee or insurance.
+        #[ink(message)]
+        pub fn get_total_amount_deposit(&self) -> Balance {
+            self.trader_collateral.saturating_add(self.total_lp_deposit)
+        }
+
+        #[ink(message)]
+        pub fn get_trader_collateral(&self) -> Balance {
+            self.trader_collateral
+        }
+
+        #[ink(message)]
+        pub fn get_accrued_fees(&self) -> Balance {
+            self.accrued_fees
+        }
+
+        #[ink(message)]
+        pub fn get_insurance_fund(&self) -> Balance {
+            self.insurance_fund
+        }
+
+        #[ink(message)]
+        pub fn get_referral_rebate(&self, account: AccountId) -> Balance {
+            self.referral_rebates.get(account).unwrap_or_default()
+        }
+
+        #[ink(message)]
+        pub fn get_total_referral_rebates(&self) -> Balance {
+            self.total_referral_rebates.get().unwrap_or_default()
+        }
+
+        /// Sum of every ledger the vault's token balance has to cover.
+        #[ink(message)]
+        pub fn get_total_liabilities(&self) -> Balance {
+            self.trader_collateral
+                .saturating_add(self.accrued_fees)
+                .saturating_add(self.total_lp_deposit)
+                .saturating_add(self.insurance_fund)
+                .saturating_add(self.get_total_referral_rebates())
+        }
+
+        fn solvency(&self, token_balance: Balance) -> Result<Balance> {
+            token_balance
+                .checked_sub(self.get_total_liabilities())
+                .ok_or(Error::Insolvent)
+        }
+
+        fn record_add_liquidity(&mut self, token: TokenId, amount: Balance, fee: Balance, user: AccountId) -> Result<Balance> {
+            let current_amount = self.contributors.get(&(user, token)).unwrap_or_default();
+
+            if current_amount > 0 {
+                return Err(Error::NonZeroAmount);
+            }
+
+            self.contributors.insert((user, token), &amount);
+
+            self.trader_collateral = self.trader_collateral.checked_add(amount).ok_or(Error::Overflow)?;
+            self.accrued_fees = self.accrued_fees.checked_add(fee).ok_or(Error::Overflow)?;
+
+            amount.checked_add(fee).ok_or(Error::Overflow)
+        }
+
+        fn record_update_liquidity(&mut self, token: TokenId, new_amount: Balance, fee: Balance, user: AccountId) -> Result<TokenFlow> {
+            let amount = self.contributors.get(&(user, token)).unwrap_or_default();
+
+            if amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            let (trader_collateral, flow) = if new_amount > amount {
+                let difference = new_amount.checked_sub(amount).ok_or(Error::Underflow)?;
+                (
+                    self.trader_collateral.checked_add(difference).ok_or(Error::Overflow)?,
+                    TokenFlow::In(difference.checked_add(fee).ok_or(Error::Overflow)?),
+                )
+            } else {
+                let difference = amount.checked_sub(new_amount).ok_or(Error::Underflow)?;
+                (
+                    self.trader_collateral.checked_sub(difference).ok_or(Error::Underflow)?,
+                    TokenFlow::Out(difference.checked_sub(fee).ok_or(Error::Underflow)?),
+                )
+            };
+
+            self.contributors.insert((user, token), &new_amount);
+            self.trader_collateral = trader_collateral;
+            self.accrued_fees = self.accrued_fees.checked_add(fee).ok_or(Error::Overflow)?;
+
+            Ok(flow)
+        }
+
+        fn record_remove_liquidity(&mut self, token: TokenId, fee: Balance, user: AccountId) -> Result<Balance> {
+            let current_amount = self.contributors.get(&(user, token)).unwrap_or_default();
+
+            if current_amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            let remove_amount = current_amount.checked_sub(fee).ok_or(Error::Underflow)?;
+
+            self.contributors.remove((user, token));
+
+            self.trader_collateral = self.trader_collateral.checked_sub(current_amount).ok_or(Error::Underflow)?;
+            self.accrued_fees = self.accrued_fees.checked_add(fee).ok_or(Error::Overflow)?;
+
+            Ok(remove_amount)
+        }
+
+        fn record_fee_collection(&mut self, token: TokenId, fee: Balance, user: AccountId) -> Result<()> {
+            let current_amount = self.contributors.get(&(user, token)).unwrap_or_default();
+
+            if current_amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            let new_amount = current_amount.checked_sub(fee).ok_or(Error::Underflow)?;
+
+            self.contributors.insert((user, token), &new_amount);
+
+            self.trader_collateral = self.trader_collateral.checked_sub(fee).ok_or(Error::Underflow)?;
+            self.accrued_fees = self.accrued_fees.checked_add(fee).ok_or(Error::Overflow)?;
+
+            Ok(())
+        }
+
+        fn record_rebate_credit(&mut self, referrer: AccountId, amount: Balance) -> Result<()> {
+            if amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            self.accrued_fees = self.accrued_fees.checked_sub(amount).ok_or(Error::InsufficientBalance)?;
+            self.total_referral_rebates
+                .set(&self.get_total_referral_rebates().checked_add(amount).ok_or(Error::Overflow)?);
+
+            let rebate = self.referral_rebates.get(referrer).unwrap_or_default();
+            self.referral_rebates.insert(referrer, &rebate.checked_add(amount).ok_or(Error::Overflow)?);
+
+            Ok(())
+        }
+
+        fn record_rebate_claim(&mut self, account: AccountId) -> Result<Balance> {
+            let amount = self.referral_rebates.take(account).unwrap_or_default();
+
+            if amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            self.total_referral_rebates
+                .set(&self.get_total_referral_rebates().checked_sub(amount).ok_or(Error::Underflow)?);
+
+            Ok(amount)
+        }
+
+        fn record_liquidation(&mut self, token: TokenId, user: AccountId) -> Result<()> {
+            let current_amount = self.contributors.get(&(user, token)).unwrap_or_default();
+
+            if current_amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            self.contributors.remove((user, token));
+
+            self.trader_collateral = self.trader_collateral.checked_sub(current_amount).ok_or(Error::Underflow)?;
+            self.insurance_fund = self.insurance_fund.checked_add(current_amount).ok_or(Error::Overflow)?;
+
+            Ok(())
+        }
+
+        fn record_insurance_use(&mut self, amount: Balance) -> Result<()> {
+            if amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            let insurance_fund = self.insurance_fund.checked_sub(amount).ok_or(Error::InsufficientBalance)?;
+            self.rebase_lp_capital(self.total_lp_deposit.checked_add(amount).ok_or(Error::Overflow)?)?;
+            self.insurance_fund = insurance_fund;
+
+            Ok(())
+        }
+
+        fn record_profit_payment(&mut self, token: TokenId, amount: Balance, user: AccountId) -> Result<()> {
+            if amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            if amount > self.get_available_liquidity() {
+                return Err(Error::InsufficientLiquidity);
+            }
+
+            let current_amount = self.contributors.get(&(user, token)).unwrap_or_default();
+
+            if current_amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            self.rebase_lp_capital(
+                self.total_lp_deposit
+                    .checked_sub(amount)
+                    .ok_or(Error::InsufficientLiquidity)?,
+            )?;
+
+            self.contributors.insert((user, token), &current_amount.checked_add(amount).ok_or(Error::Overflow)?);
+            self.trader_collateral = self.trader_collateral.checked_add(amount).ok_or(Error::Overflow)?;
+
+            Ok(())
+        }
+
+        fn lp_index(&self) -> Balance {
+            self.lp_index.get().unwrap_or(LP_INDEX_PRECISION)
+        }
+
+        /// LP balance of `account` with the index growth since its checkpoint applied.
+        fn lp_balance(&self, account: AccountId) -> Result<Balance> {
+            let checkpoint = self.lp_index_checkpoints.get(account).unwrap_or(LP_INDEX_PRECISION);
+
+            self.lp_balances
+                .get(account)
+                .unwrap_or_default()
+                .checked_mul(self.lp_index())
+                .ok_or(Error::Overflow)?
+                .checked_div(checkpoint)
+                .ok_or(Error::Underflow)
+        }
+
+        /// Writes the indexed LP balance of `account` back to storage and moves its
+        /// checkpoint to the current index. Must run before the balance changes.
+        fn settle_lp_balance(&mut self, account: AccountId) -> Result<Balance> {
+            let lp_balance = self.lp_balance(account)?;
+
+            self.lp_balances.insert(account, &lp_balance);
+            self.lp_index_checkpoints.insert(account, &self.lp_index());
+
+            Ok(lp_balance)
+        }
+
+        /// Amount a queued withdrawal pays out at the current index.
+        fn withdrawal_amount(&self, request: &WithdrawalRequest) -> Result<Balance> {
+            request
+                .amount
+                .checked_mul(self.lp_index())
+                .ok_or(Error::Overflow)?
+                .checked_div(request.lp_index)
+                .ok_or(Error::Underflow)
+        }
+
+        /// Grows or shrinks the LP capital to `new_total`, scaling every LP balance
+        /// and queued withdrawal by the same factor.
+        fn rebase_lp_capital(&mut self, new_total: Balance) -> Result<()> {
+            if self.total_lp_deposit == 0 {
+                return Err(Error::InsufficientLiquidity);
+            }
+
+            let lp_index = self
+                .lp_index()
+                .checked_mul(new_total)
+                .ok_or(Error::Overflow)?
+                .checked_div(self.total_lp_deposit)
+                .ok_or(Error::Underflow)?;
+
+            // a zero index could never be divided by again
+            if lp_index == 0 {
+                return Err(Error::InsufficientLiquidity);
+            }
+
+            self.lp_index.set(&lp_index);
+            self.total_lp_deposit = new_total;
+
+            Ok(())
+        }
+
+        fn record_fee_withdrawal(&mut self) -> Balance {
+            let withdraw_amount = self.accrued_fees;
+            self.accrued_fees = 0;
+            withdraw_amount
+        }
+
+        fn record_lp_deposit(&mut self, account: AccountId, amount: Balance) -> Result<()> {
+            self.roll_epoch();
+
+            let lp_balance = self.settle_lp_balance(account)?;
+            self.lp_balances.insert(account, &lp_balance.checked_add(amount).ok_or(Error::Overflow)?);
+
+            self.total_lp_deposit = self.total_lp_deposit.checked_add(amount).ok_or(Error::Overflow)?;
+
+            // An epoch that started with no LP capital has no snapshot to cap
+            // withdrawals against, so the first deposit seeds it.
+            if self.epoch_start_lp_deposit == 0 {
+                self.epoch_start_lp_deposit = self.total_lp_deposit;
+            }
+
+            Ok(())
+        }
+
+        fn record_withdrawal_claim(&mut self, account: AccountId, request_id: WithdrawalId) -> Result<WithdrawalRequest> {
+            let mut requests = self.withdrawal_requests.get(account).unwrap_or_default();
+
+            let index = requests
+                .iter()
+                .position(|request| request.id == request_id)
+                .ok_or(Error::NotFound)?;
+            let mut request = requests.remove(index);
+
+            if self.env().block_timestamp() < request.unlock_time {
+                return Err(Error::CooldownNotElapsed);
+            }
+
+            request.amount = self.withdrawal_amount(&request)?;
+
+            self.roll_epoch();
+
+            let epoch_withdrawn = self.epoch_withdrawn.checked_add(request.amount).ok_or(Error::Overflow)?;
+
+            if epoch_withdrawn > self.epoch_withdrawal_limit()? {
+                return Err(Error::EpochWithdrawalLimitExceeded);
+            }
+
+            if request.amount > self.get_available_liquidity() {
+                return Err(Error::InsufficientLiquidity);
+            }
+
+            self.epoch_withdrawn = epoch_withdrawn;
+
+            if requests.is_empty() {
+                self.withdrawal_requests.remove(account);
+            } else {
+                self.withdrawal_requests.insert(account, &requests);
+            }
+
+            self.total_lp_deposit = self.total_lp_deposit.checked_sub(request.amount).ok_or(Error::Underflow)?;
+
+            Ok(request)
+        }
+
+        /// Starts a new withdrawal epoch if the current one has ended, snapshotting
+        /// the LP capital the per-epoch limit is measured against.
+        fn roll_epoch(&mut self) {
+            let epoch = self.env().block_timestamp() / self.epoch_duration;
+
+            if epoch != self.current_epoch {
+                self.current_epoch = epoch;
+                self.epoch_start_lp_deposit = self.total_lp_deposit;
+                self.epoch_withdrawn = 0;
+            }
+        }
+
+        fn epoch_withdrawal_limit(&self) -> Result<Balance> {
+            self.epoch_start_lp_deposit
+                .checked_mul(self.max_epoch_withdrawal_bps as Balance)
+                .ok_or(Error::Overflow)?
+                .checked_div(BPS_DENOMINATOR)
+                .ok_or(Error::Underflow)
+        }
+
+        fn check_owner(&self, account: AccountId) -> Result<()> {
+            if !self.ownership.is_owner(account) {
+                return Err(Error::NotOwner);
+            }
+            Ok(())
+        }
+
+        fn check_role(&self, role: RoleType, account: AccountId) -> Result<()> {
+            if !self.access_control.has_role(role, account) {
+                return Err(Error::MissingRole);
+            }
+            Ok(())
+        }
+
+        fn request_address_update(&mut self, kind: AddressKind, address: AccountId) -> Result<()> {
+            let delay = self.get_address_update_delay();
+
+            if delay == 0 {
+                self.update_address(kind, address);
+                return Ok(());
+            }
+
+            let eta = self
+                .env()
+                .block_timestamp()
+                .checked_add(delay)
+                .ok_or(Error::Overflow)?;
+            self.pending_address_updates.insert(kind, &PendingAddressUpdate { address, eta });
+
+            self.env().emit_event(AddressUpdateQueued { kind, address, eta });
+
+            Ok(())
+        }
+
+        fn update_address_update_delay(&mut self, delay: Timestamp) {
+            let old = self.get_address_update_delay();
+            self.address_update_delay.set(&delay);
+            self.pending_address_update_delay.set(&None);
+
+            self.env().emit_event(AddressUpdateDelayUpdated { old, new: delay });
+        }
+
+        /// Fails unless the vault owes nothing to anyone in its current token.
+        fn check_ledgers_empty(&self) -> Result<()> {
+            if self.get_total_liabilities() != 0 {
+                return Err(Error::LedgersNotEmpty);
+            }
+            Ok(())
+        }
+
+        fn update_address(&mut self, kind: AddressKind, address: AccountId) {
+            match kind {
+                AddressKind::Distributor => {
+                    let old = core::mem::replace(&mut self.distributor, address);
+                    self.env().emit_event(DistributorUpdated { old, new: address });
+                }
+                AddressKind::Erc20Contract => {
+                    let old = core::mem::replace(&mut self.erc20contract, address);
+                    self.env().emit_event(Erc20ContractUpdated { old, new: address });
+                }
+            }
+        }
+    }
+
+    impl AccessControlled for Vault {
+        #[ink(message)]
+        fn grant_role(&mut self, role: RoleType, account: AccountId) -> RoleResult {
+            self.access_control.grant_role(self.env().caller(), role, account)
+        }
+
+        #[ink(message)]
+        fn revoke_role(&mut self, role: RoleType, account: AccountId) -> RoleResult {
+            self.access_control.revoke_role(self.env().caller(), role, account)
+        }
+
+        #[ink(message)]
+        fn renounce_role(&mut self, role: RoleType) -> RoleResult {
+            self.access_control.renounce_role(self.env().caller(), role)
+        }
+
+        #[ink(message)]
+        fn has_role(&self, role: RoleType, account: AccountId) -> bool {
+            self.access_control.has_role(role, account)
+        }
+    }
 
     // CROSS CONTRACT CALLS ARE NOT INCLUDED IN TESTS
     // COMMENT CROSS CONTRACT CALLS BEFORE TESTING
     #[cfg(test)]
     mod tests {
         use super::*;
+        use access_control::RoleError;
+
+        fn grant_manager_role(vault: &mut Vault) {
+            let caller = ink::env::caller::<ink::env::DefaultEnvironment>();
+            vault.access_control.grant(MANAGER_CONTRACT, caller);
+        }
+
+        #[ink::test]
+        pub fn add_liquidity_works() {
+            let erc20 = AccountId::from([0x0; 32]);
+            let fee = 10;
+            let token = 123;
+            let amount = 100;
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            grant_manager_role(&mut vault);
+            assert_eq!(vault.add_liquidity(token, amount, fee, accounts.alice), Ok(()));
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 1);
+
+            assert_eq!(vault.get_contributor_balance(accounts.alice, token), amount);
+        }
+
+        #[ink::test]
+        pub fn add_liquidity_fails() {
+            let erc20 = AccountId::from([0x0; 32]);
+            let fee = 10;
+            let token = 123;
+            let amount = 100;
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            grant_manager_role(&mut vault);
+            assert_eq!(vault.add_liquidity(token, amount, fee, accounts.alice), Ok(()));
+
+            assert_eq!(
+                vault.add_liquidity(token, amount, fee, accounts.alice),
+                Err(Error::NonZeroAmount)
+            );
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 1);
+        }
+
+        #[ink::test]
+        pub fn update_liquidity_works() {
+            let erc20 = AccountId::from([0x0; 32]);
+            let fee = 10;
+            let token = 123;
+            let amount = 100;
+            let new_amount = 120;
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            grant_manager_role(&mut vault);
+            vault.add_liquidity(token, amount, fee, accounts.alice);
+            vault.update_liquidity(token, new_amount, fee, accounts.alice);
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 2);
+
+            assert_eq!(
+                vault.get_contributor_balance(accounts.alice, 123),
+                new_amount
+            );
+        }
+
+        #[ink::test]
+        pub fn update_liquidity_zero_amount_fails() {
+            let erc20 = AccountId::from([0x0; 32]);
+            let fee = 10;
+            let token = 123;
+            let amount = 100;
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            grant_manager_role(&mut vault);
+            assert_eq!(
+                vault.update_liquidity(token, amount, fee, accounts.alice),
+                Err(Error::ZeroAmount)
+            );
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 0);
+        }
+
+        #[ink::test]
+        pub fn remove_liquidity_fails() {
+            let erc20 = AccountId::from([0x0; 32]);
+            let fee = 10;
+            let token = 123;
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            grant_manager_role(&mut vault);
+            assert_eq!(
+                vault.remove_liquidity(token, fee, accounts.alice),
+                Err(Error::ZeroAmount)
+            );
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 0);
+        }
 
         #[ink::test]
-        pub fn add_liquidity_works() {
+        pub fn remove_liquidity_works() {
             let erc20 = AccountId::from([0x0; 32]);
             let fee = 10;
             let token = 123;
-            let amount = 100;
-            let mut vault = Vault::new(erc20, fee);
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
             let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
 
             ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
-            assert_eq!(vault.add_liquidity(token, amount, accounts.alice), Ok(()));
+            grant_manager_role(&mut vault);
+            assert_eq!(vault.add_liquidity(token, 100, fee, accounts.alice), Ok(()));
+            assert_eq!(vault.remove_liquidity(token, fee, accounts.alice), Ok(()));
 
             let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
-            assert_eq!(emitted_events.len(), 1);
+            assert_eq!(emitted_events.len(), 2);
 
-            assert_eq!(vault.get_contributor_balance(accounts.alice, token), amount);
+            assert_eq!(vault.get_contributor_balance(accounts.alice, 123), 0);
         }
 
         #[ink::test]
-        pub fn add_liquidity_fails() {
-            let erc20 = AccountId::from([0x0; 32]);
+        pub fn contract_creation_works() {
             let fee = 10;
-            let token = 123;
-            let amount = 100;
-            let mut vault = Vault::new(erc20, fee);
+            let erc20 = AccountId::from([0x0; 32]);
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
+
+            assert_eq!(vault.erc20contract, erc20);
+            assert_eq!(vault.distributor, distributor);
+        }
+
+        #[ink::test]
+        pub fn request_withdrawal_works() {
+            let erc20 = AccountId::from([0x0; 32]);
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
             let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
 
+            vault.lp_balances.insert(accounts.alice, &1000);
+            vault.total_lp_deposit = 1000;
+
             ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
-            assert_eq!(vault.add_liquidity(token, amount, accounts.alice), Ok(()));
+            assert_eq!(vault.request_withdrawal(400), Ok(0));
 
-            assert_eq!(
-                vault.add_liquidity(token, amount, accounts.alice),
-                Err(Error::NonZeroAmount)
-            );
+            let pending = vault.get_pending_withdrawals(accounts.alice);
+            assert_eq!(pending.len(), 1);
+            assert_eq!(pending[0].amount, 400);
+            assert_eq!(pending[0].unlock_time, DEFAULT_WITHDRAWAL_COOLDOWN);
+
+            assert_eq!(vault.get_lp_balance(accounts.alice), 600);
+            assert_eq!(vault.get_total_lp_deposit(), 1000);
 
             let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
             assert_eq!(emitted_events.len(), 1);
         }
 
         #[ink::test]
-        pub fn update_liquidity_works() {
+        pub fn request_withdrawal_fails() {
             let erc20 = AccountId::from([0x0; 32]);
-            let fee = 10;
-            let token = 123;
-            let amount = 100;
-            let new_amount = 120;
-            let mut vault = Vault::new(erc20, fee);
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
             let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
 
+            vault.lp_balances.insert(accounts.alice, &100);
+
             ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
-            vault.add_liquidity(token, amount, accounts.alice);
-            vault.update_liquidity(token, new_amount, accounts.alice);
+            assert_eq!(vault.request_withdrawal(0), Err(Error::ZeroAmount));
+            assert_eq!(vault.request_withdrawal(101), Err(Error::InsufficientBalance));
+            assert!(vault.get_pending_withdrawals(accounts.alice).is_empty());
+        }
 
-            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
-            assert_eq!(emitted_events.len(), 2);
+        #[ink::test]
+        pub fn claim_withdrawal_before_cooldown_fails() {
+            let erc20 = AccountId::from([0x0; 32]);
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            vault.lp_balances.insert(accounts.alice, &1000);
+            vault.total_lp_deposit = 1000;
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let request_id = vault.request_withdrawal(100).unwrap();
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(DEFAULT_WITHDRAWAL_COOLDOWN - 1);
+            assert_eq!(vault.claim_withdrawal(request_id), Err(Error::CooldownNotElapsed));
+            assert_eq!(vault.claim_withdrawal(request_id + 1), Err(Error::NotFound));
+        }
+
+        #[ink::test]
+        pub fn claim_withdrawal_over_epoch_limit_fails() {
+            let erc20 = AccountId::from([0x0; 32]);
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
 
+            vault.lp_balances.insert(accounts.alice, &1000);
+            vault.total_lp_deposit = 1000;
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let request_id = vault.request_withdrawal(400).unwrap();
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(DEFAULT_WITHDRAWAL_COOLDOWN);
             assert_eq!(
-                vault.get_contributor_balance(accounts.alice, 123),
-                new_amount
+                vault.claim_withdrawal(request_id),
+                Err(Error::EpochWithdrawalLimitExceeded)
             );
+            assert_eq!(vault.epoch_withdrawal_limit(), Ok(200));
+            assert_eq!(vault.get_pending_withdrawals(accounts.alice).len(), 1);
         }
 
         #[ink::test]
-        pub fn update_liquidity_zero_amount_fails() {
+        pub fn claim_withdrawal_in_first_epoch_works() {
             let erc20 = AccountId::from([0x0; 32]);
-            let fee = 10;
-            let token = 123;
-            let amount = 100;
-            let mut vault = Vault::new(erc20, fee);
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            vault.withdrawal_cooldown = 0;
+            vault.record_lp_deposit(accounts.alice, 1000).unwrap();
+            assert_eq!(vault.epoch_withdrawal_limit(), Ok(200));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let request_id = vault.request_withdrawal(100).unwrap();
+
+            let request = vault.record_withdrawal_claim(accounts.alice, request_id).unwrap();
+            assert_eq!(request.amount, 100);
+            assert_eq!(vault.get_total_lp_deposit(), 900);
+        }
+
+        #[ink::test]
+        pub fn reserve_liquidity_works() {
+            let erc20 = AccountId::from([0x0; 32]);
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
+            grant_manager_role(&mut vault);
+
+            vault.total_lp_deposit = 1000;
+
+            assert_eq!(vault.reserve_liquidity(600), Ok(()));
+            assert_eq!(vault.get_reserved_liquidity(), 600);
+            assert_eq!(vault.get_available_liquidity(), 400);
+
+            assert_eq!(vault.reserve_liquidity(401), Err(Error::InsufficientLiquidity));
+
+            assert_eq!(vault.release_liquidity(200), Ok(()));
+            assert_eq!(vault.get_reserved_liquidity(), 400);
+            assert_eq!(vault.get_available_liquidity(), 600);
+
+            assert_eq!(vault.release_liquidity(401), Err(Error::Underflow));
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 2);
+        }
+
+        #[ink::test]
+        pub fn claim_withdrawal_of_reserved_liquidity_fails() {
+            let erc20 = AccountId::from([0x0; 32]);
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
+            grant_manager_role(&mut vault);
             let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
 
+            vault.lp_balances.insert(accounts.alice, &1000);
+            vault.total_lp_deposit = 1000;
+            vault.max_epoch_withdrawal_bps = 10_000;
+            vault.reserve_liquidity(900).unwrap();
+
             ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let request_id = vault.request_withdrawal(200).unwrap();
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(DEFAULT_WITHDRAWAL_COOLDOWN);
+            assert_eq!(vault.claim_withdrawal(request_id), Err(Error::InsufficientLiquidity));
+        }
+
+        fn apply_flow(token_balance: Balance, flow: TokenFlow) -> Balance {
+            match flow {
+                TokenFlow::In(amount) => token_balance + amount,
+                TokenFlow::Out(amount) => token_balance - amount,
+            }
+        }
+
+        fn assert_solvent(vault: &Vault, token_balance: Balance) {
+            assert_eq!(vault.solvency(token_balance), Ok(0));
             assert_eq!(
-                vault.update_liquidity(token, amount, accounts.alice),
-                Err(Error::ZeroAmount)
+                vault.get_total_liabilities(),
+                vault.trader_collateral
+                    + vault.accrued_fees
+                    + vault.total_lp_deposit
+                    + vault.insurance_fund
+                    + vault.get_total_referral_rebates()
             );
+        }
 
-            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
-            assert_eq!(emitted_events.len(), 0);
+        #[ink::test]
+        pub fn solvency_holds_through_position_lifecycle() {
+            let erc20 = AccountId::from([0x0; 32]);
+            let distributor = AccountId::from([0x2; 32]);
+            let fee = 10;
+            let mut vault = Vault::new(erc20, distributor);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let token = 123;
+            let mut token_balance = 0;
+
+            token_balance += vault.record_add_liquidity(token, 100, fee, accounts.alice).unwrap();
+            assert_solvent(&vault, token_balance);
+
+            let flow = vault.record_update_liquidity(token, 150, fee, accounts.alice).unwrap();
+            assert_eq!(flow, TokenFlow::In(60));
+            token_balance = apply_flow(token_balance, flow);
+            assert_solvent(&vault, token_balance);
+
+            let flow = vault.record_update_liquidity(token, 120, fee, accounts.alice).unwrap();
+            assert_eq!(flow, TokenFlow::Out(20));
+            token_balance = apply_flow(token_balance, flow);
+            assert_solvent(&vault, token_balance);
+
+            token_balance -= vault.record_remove_liquidity(token, fee, accounts.alice).unwrap();
+            assert_solvent(&vault, token_balance);
+
+            assert_eq!(vault.get_trader_collateral(), 0);
+            assert_eq!(vault.get_accrued_fees(), 40);
+            assert_eq!(token_balance, 40);
+
+            token_balance -= vault.record_fee_withdrawal();
+            assert_solvent(&vault, token_balance);
+            assert_eq!(token_balance, 0);
         }
 
         #[ink::test]
-        pub fn remove_liquidity_fails() {
+        pub fn solvency_holds_with_lp_capital_and_liquidations() {
             let erc20 = AccountId::from([0x0; 32]);
+            let distributor = AccountId::from([0x2; 32]);
             let fee = 10;
+            let mut vault = Vault::new(erc20, distributor);
+            grant_manager_role(&mut vault);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
             let token = 123;
-            let mut vault = Vault::new(erc20, fee);
+            let mut token_balance = 0;
+
+            vault.max_epoch_withdrawal_bps = 10_000;
+
+            vault.record_lp_deposit(accounts.bob, 1000).unwrap();
+            token_balance += 1000;
+            assert_solvent(&vault, token_balance);
+
+            token_balance += vault.record_add_liquidity(token, 100, fee, accounts.alice).unwrap();
+            token_balance += vault.record_add_liquidity(token, 50, fee, accounts.charlie).unwrap();
+            assert_solvent(&vault, token_balance);
+
+            vault.record_liquidation(token, accounts.charlie).unwrap();
+            assert_solvent(&vault, token_balance);
+            assert_eq!(vault.get_insurance_fund(), 50);
+
+            assert_eq!(vault.use_insurance_fund(60), Err(Error::InsufficientBalance));
+            vault.use_insurance_fund(20).unwrap();
+            assert_solvent(&vault, token_balance);
+            assert_eq!(vault.get_insurance_fund(), 30);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            let request_id = vault.request_withdrawal(300).unwrap();
+            assert_solvent(&vault, token_balance);
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(DEFAULT_WITHDRAWAL_COOLDOWN);
+            token_balance -= vault.record_withdrawal_claim(accounts.bob, request_id).unwrap().amount;
+            assert_solvent(&vault, token_balance);
+
+            token_balance -= vault.record_fee_withdrawal();
+            assert_solvent(&vault, token_balance);
+
+            token_balance -= vault.record_remove_liquidity(token, fee, accounts.alice).unwrap();
+            assert_solvent(&vault, token_balance);
+
+            assert_eq!(token_balance, vault.get_total_lp_deposit() + vault.get_insurance_fund() + vault.get_accrued_fees());
+            assert_eq!(vault.get_total_lp_deposit(), 720);
+            // the insurance payout went to the only LP and can be withdrawn
+            assert_eq!(vault.get_lp_balance(accounts.bob), 720);
+        }
+
+        #[ink::test]
+        pub fn profit_payment_is_shared_by_lps() {
+            let erc20 = AccountId::from([0x0; 32]);
+            let distributor = AccountId::from([0x2; 32]);
+            let fee = 10;
+            let mut vault = Vault::new(erc20, distributor);
             let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let token = 123;
 
-            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            vault.record_lp_deposit(accounts.alice, 1000).unwrap();
+            vault.record_lp_deposit(accounts.bob, 3000).unwrap();
+            let mut token_balance = 4000;
+            token_balance += vault.record_add_liquidity(token, 100, fee, accounts.charlie).unwrap();
+
+            assert_eq!(vault.record_profit_payment(token, 0, accounts.charlie), Err(Error::ZeroAmount));
+            assert_eq!(vault.record_profit_payment(token, 100, accounts.django), Err(Error::ZeroAmount));
             assert_eq!(
-                vault.remove_liquidity(token, accounts.alice),
-                Err(Error::ZeroAmount)
+                vault.record_profit_payment(token, 4001, accounts.charlie),
+                Err(Error::InsufficientLiquidity)
             );
 
-            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
-            assert_eq!(emitted_events.len(), 0);
+            vault.record_profit_payment(token, 400, accounts.charlie).unwrap();
+            assert_solvent(&vault, token_balance);
+            assert_eq!(vault.get_contributor_balance(accounts.charlie, token), 500);
+            assert_eq!(vault.get_lp_balance(accounts.alice), 900);
+            assert_eq!(vault.get_lp_balance(accounts.bob), 2700);
+            assert_eq!(vault.get_total_lp_deposit(), 3600);
+
+            token_balance -= vault.record_remove_liquidity(token, fee, accounts.charlie).unwrap();
+            assert_solvent(&vault, token_balance);
         }
 
         #[ink::test]
-        pub fn remove_liquidity_works() {
+        pub fn queued_withdrawal_shares_losses() {
             let erc20 = AccountId::from([0x0; 32]);
-            let fee = 10;
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let token = 123;
+
+            vault.withdrawal_cooldown = 0;
+            vault.max_epoch_withdrawal_bps = 10_000;
+            vault.record_lp_deposit(accounts.alice, 1000).unwrap();
+            vault.record_lp_deposit(accounts.bob, 3000).unwrap();
+            let mut token_balance = 4000;
+            token_balance += vault.record_add_liquidity(token, 100, 0, accounts.charlie).unwrap();
+
+            // queueing the withdrawal right before a trader profit is paid does not
+            // leave the loss to the other LPs
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            let request_id = vault.request_withdrawal(3000).unwrap();
+            vault.record_profit_payment(token, 400, accounts.charlie).unwrap();
+            assert_eq!(vault.get_lp_balance(accounts.alice), 900);
+            assert_eq!(vault.get_pending_withdrawals(accounts.bob)[0].amount, 2700);
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(DEFAULT_EPOCH_DURATION);
+            let request = vault.record_withdrawal_claim(accounts.bob, request_id).unwrap();
+            assert_eq!(request.amount, 2700);
+            token_balance -= request.amount;
+            assert_eq!(vault.get_total_lp_deposit(), 900);
+            assert_solvent(&vault, token_balance);
+        }
+
+        #[ink::test]
+        pub fn insurance_use_is_shared_by_lps() {
+            let erc20 = AccountId::from([0x0; 32]);
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            assert_eq!(vault.record_insurance_use(0), Err(Error::ZeroAmount));
+
+            vault.insurance_fund = 300;
+            assert_eq!(vault.record_insurance_use(100), Err(Error::InsufficientLiquidity));
+
+            vault.record_lp_deposit(accounts.alice, 1000).unwrap();
+            vault.record_lp_deposit(accounts.bob, 2000).unwrap();
+
+            // capital queued for withdrawal shares in the payout until it is claimed
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            vault.request_withdrawal(1000).unwrap();
+
+            vault.record_insurance_use(300).unwrap();
+            assert_eq!(vault.get_lp_balance(accounts.alice), 1100);
+            assert_eq!(vault.get_lp_balance(accounts.bob), 1100);
+            assert_eq!(vault.get_pending_withdrawals(accounts.bob)[0].amount, 1100);
+            assert_eq!(vault.get_total_lp_deposit(), 3300);
+
+            // a later deposit does not share in earlier payouts
+            vault.record_lp_deposit(accounts.charlie, 1000).unwrap();
+            assert_eq!(vault.get_lp_balance(accounts.charlie), 1000);
+            assert_eq!(vault.get_lp_balance(accounts.alice), 1100);
+        }
+
+        #[ink::test]
+        pub fn referral_rebates_work() {
+            let erc20 = AccountId::from([0x0; 32]);
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
+            grant_manager_role(&mut vault);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
             let token = 123;
-            let mut vault = Vault::new(erc20, fee);
+
+            let mut token_balance = vault.record_add_liquidity(token, 100, 10, accounts.alice).unwrap();
+
+            assert_eq!(vault.credit_rebate(accounts.bob, 0), Err(Error::ZeroAmount));
+            assert_eq!(vault.credit_rebate(accounts.bob, 11), Err(Error::InsufficientBalance));
+            assert_eq!(vault.credit_rebate(accounts.bob, 4), Ok(()));
+            assert_eq!(vault.get_accrued_fees(), 6);
+            assert_eq!(vault.get_referral_rebate(accounts.bob), 4);
+            assert_eq!(vault.get_total_referral_rebates(), 4);
+            assert_solvent(&vault, token_balance);
+
+            // rebates are not swept to the distributor with the fees
+            token_balance -= vault.record_fee_withdrawal();
+            assert_eq!(vault.get_total_referral_rebates(), 4);
+            assert_solvent(&vault, token_balance);
+
+            token_balance -= vault.record_rebate_claim(accounts.bob).unwrap();
+            assert_eq!(vault.record_rebate_claim(accounts.bob), Err(Error::ZeroAmount));
+            assert_eq!(vault.get_total_referral_rebates(), 0);
+            assert_solvent(&vault, token_balance);
+            assert_eq!(token_balance, 100);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(vault.credit_rebate(accounts.bob, 1), Err(Error::MissingRole));
+            assert_eq!(vault.claim_rebate(), Err(Error::ZeroAmount));
+        }
+
+        #[ink::test]
+        pub fn solvency_holds_for_interleaved_operations() {
+            let erc20 = AccountId::from([0x0; 32]);
+            let distributor = AccountId::from([0x2; 32]);
+            let fee = 3;
+            let mut vault = Vault::new(erc20, distributor);
             let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let users = [accounts.alice, accounts.bob, accounts.charlie, accounts.django];
+            let mut token_balance = 0;
+
+            for round in 0..20u128 {
+                let user = users[(round % 4) as usize];
+                let token = round % 3;
+
+                match round % 5 {
+                    0 | 1 => {
+                        if let Ok(deposit_amount) = vault.record_add_liquidity(token, 10 + round, fee, user) {
+                            token_balance += deposit_amount;
+                        }
+                    }
+                    2 => {
+                        if let Ok(flow) = vault.record_update_liquidity(token, 5 + round * 2, fee, user) {
+                            token_balance = apply_flow(token_balance, flow);
+                        }
+                    }
+                    3 => {
+                        if let Ok(remove_amount) = vault.record_remove_liquidity(token, fee, user) {
+                            token_balance -= remove_amount;
+                        }
+                    }
+                    _ => {
+                        let _ = vault.record_liquidation(token, user);
+                        token_balance -= vault.record_fee_withdrawal();
+                    }
+                }
+
+                assert_solvent(&vault, token_balance);
+            }
+        }
 
-            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
-            assert_eq!(vault.add_liquidity(token, 100, accounts.alice), Ok(()));
-            assert_eq!(vault.remove_liquidity(token, accounts.alice), Ok(()));
+        #[ink::test]
+        pub fn solvency_holds_when_collecting_fees() {
+            let erc20 = AccountId::from([0x0; 32]);
+            let distributor = AccountId::from([0x2; 32]);
+            let mut vault = Vault::new(erc20, distributor);
+            grant_manager_role(&mut vault);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let token = 123;
 
-            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
-            assert_eq!(emitted_events.len(), 2);
+            let token_balance = vault.record_add_liquidity(token, 100, 5, accounts.alice).unwrap();
 
-            assert_eq!(vault.get_contributor_balance(accounts.alice, 123), 0);
+            assert_eq!(vault.collect_fee(token, 7, accounts.alice), Ok(()));
+            assert_solvent(&vault, token_balance);
+
+            assert_eq!(vault.get_contributor_balance(accounts.alice, token), 93);
+            assert_eq!(vault.get_trader_collateral(), 93);
+            assert_eq!(vault.get_accrued_fees(), 12);
+
+            assert_eq!(vault.collect_fee(token, 94, accounts.alice), Err(Error::Underflow));
+            assert_eq!(vault.collect_fee(token, 1, accounts.bob), Err(Error::ZeroAmount));
         }
 
         #[ink::test]
-        pub fn contract_creation_works() {
+        pub fn solvency_detects_missing_tokens() {
+            let erc20 = AccountId::from([0x0; 32]);
+            let distributor = AccountId::from([0x2; 32]);
             let fee = 10;
+            let mut vault = Vault::new(erc20, distributor);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            let token_balance = vault.record_add_liquidity(123, 100, fee, accounts.alice).unwrap();
+
+            assert_eq!(vault.solvency(token_balance + 5), Ok(5));
+            assert_eq!(vault.solvency(token_balance - 1), Err(Error::Insolvent));
+        }
+
+        #[ink::test]
+        pub fn withdrawal_settings_only_owner() {
             let erc20 = AccountId::from([0x0; 32]);
-            let mut vault = Vault::new(erc20, fee);
+            let distributor = AccountId::from([0x2; 32]);
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
 
-            assert_eq!(vault.erc20contract, erc20);
-            assert_eq!(vault.fee, fee);
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut vault = Vault::new(erc20, distributor);
+
+            assert_eq!(vault.set_withdrawal_cooldown(1000), Ok(()));
+            assert_eq!(vault.get_withdrawal_cooldown(), 1000);
+            assert_eq!(vault.set_max_epoch_withdrawal_bps(10_001), Err(Error::InvalidParameter));
+            assert_eq!(vault.set_epoch_duration(0), Err(Error::InvalidParameter));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(vault.set_withdrawal_cooldown(0), Err(Error::MissingRole));
+            assert_eq!(vault.set_max_epoch_withdrawal_bps(5_000), Err(Error::MissingRole));
+            assert_eq!(vault.reserve_liquidity(0), Err(Error::MissingRole));
+            assert_eq!(vault.liquidation(123, accounts.alice), Err(Error::MissingRole));
+        }
+
+        #[ink::test]
+        pub fn upgrade_only_owner() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut vault = Vault::new(AccountId::from([0x0; 32]), AccountId::from([0x2; 32]));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(vault.upgrade(Hash::from([0x42; 32])), Err(Error::NotOwner));
+            assert_eq!(vault.migrate(), Err(Error::NotOwner));
+        }
+
+        #[ink::test]
+        pub fn migrate_current_version_fails() {
+            let mut vault = Vault::new(AccountId::from([0x0; 32]), AccountId::from([0x2; 32]));
+            assert_eq!(vault.get_version(), STORAGE_VERSION);
+            assert_eq!(vault.migrate(), Err(Error::AlreadyMigrated));
+        }
+
+        mod v2 {
+            use access_control::{AccessControl, Ownership};
+            use ink::prelude::vec::Vec;
+            use ink::primitives::AccountId;
+            use ink::storage::{Lazy, Mapping};
+
+            use super::{
+                AddressKind, PendingAddressUpdate, PendingDelayUpdate, TokenId, WithdrawalId,
+                WithdrawalRequest,
+            };
+
+            type Balance = u128;
+            type Timestamp = u64;
+
+            /// Vault layout of a later version: the v1 fields in the same order
+            /// with a `Lazy` one appended.
+            #[ink::storage_item]
+            pub struct Vault {
+                pub contributors: Mapping<(AccountId, TokenId), Balance>,
+                pub erc20contract: AccountId,
+                pub trader_collateral: Balance,
+                pub accrued_fees: Balance,
+                pub insurance_fund: Balance,
+                pub distributor: AccountId,
+                pub ownership: Ownership,
+                pub lp_balances: Mapping<AccountId, Balance>,
+                pub total_lp_deposit: Balance,
+                pub withdrawal_requests: Mapping<AccountId, Vec<WithdrawalRequest>>,
+                pub next_withdrawal_id: WithdrawalId,
+                pub withdrawal_cooldown: Timestamp,
+                pub epoch_duration: Timestamp,
+                pub max_epoch_withdrawal_bps: u32,
+                pub current_epoch: u64,
+                pub epoch_start_lp_deposit: Balance,
+                pub epoch_withdrawn: Balance,
+                pub reserved_liquidity: Balance,
+                pub version: u32,
+                pub access_control: AccessControl,
+                pub address_update_delay: Lazy<Timestamp>,
+                pub pending_address_updates: Mapping<AddressKind, PendingAddressUpdate>,
+                pub referral_rebates: Mapping<AccountId, Balance>,
+                pub total_referral_rebates: Lazy<Balance>,
+                pub lp_index: Lazy<Balance>,
+                pub lp_index_checkpoints: Mapping<AccountId, Balance>,
+                pub pending_address_update_delay: Lazy<Option<PendingDelayUpdate>>,
+                pub max_lp_deposit: Lazy<Balance>,
+            }
+        }
+
+        #[ink::test]
+        pub fn storage_survives_upgrade() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let root_key = <Vault as ink::storage::traits::StorageKey>::KEY;
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut vault = Vault::new(AccountId::from([0x0; 32]), AccountId::from([0x2; 32]));
+            assert_eq!(vault.record_lp_deposit(accounts.bob, 1000), Ok(()));
+            assert_eq!(vault.record_add_liquidity(1, 300, 10, accounts.charlie), Ok(310));
+            assert_eq!(vault.record_rebate_credit(accounts.django, 4), Ok(()));
+            ink::env::set_contract_storage(&root_key, &vault);
+
+            let upgraded = ink::env::get_contract_storage::<_, v2::Vault>(&root_key)
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(upgraded.erc20contract, AccountId::from([0x0; 32]));
+            assert_eq!(upgraded.distributor, AccountId::from([0x2; 32]));
+            assert_eq!(upgraded.trader_collateral, 300);
+            assert_eq!(upgraded.accrued_fees, 6);
+            assert_eq!(upgraded.total_lp_deposit, 1000);
+            assert_eq!(upgraded.lp_balances.get(accounts.bob), Some(1000));
+            assert_eq!(upgraded.contributors.get((accounts.charlie, 1)), Some(300));
+            assert_eq!(upgraded.referral_rebates.get(accounts.django), Some(4));
+            assert_eq!(upgraded.total_referral_rebates.get(), Some(4));
+            assert_eq!(upgraded.ownership.owner(), Some(accounts.alice));
+            assert_eq!(upgraded.version, STORAGE_VERSION);
+            assert!(upgraded.access_control.has_role(ADMIN, accounts.alice));
+            assert_eq!(upgraded.max_lp_deposit.get(), None);
+        }
+
+        #[ink::test]
+        pub fn roles_work() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut vault = Vault::new(AccountId::from([0x0; 32]), AccountId::from([0x2; 32]));
+            assert!(vault.has_role(ADMIN, accounts.alice));
+            assert!(!vault.has_role(MANAGER_CONTRACT, accounts.bob));
+
+            assert_eq!(vault.grant_role(MANAGER_CONTRACT, accounts.bob), Ok(()));
+            assert!(vault.has_role(MANAGER_CONTRACT, accounts.bob));
+            assert_eq!(vault.revoke_role(MANAGER_CONTRACT, accounts.bob), Ok(()));
+            assert!(!vault.has_role(MANAGER_CONTRACT, accounts.bob));
+
+            assert_eq!(vault.grant_role(MANAGER_CONTRACT, accounts.bob), Ok(()));
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(vault.grant_role(ADMIN, accounts.bob), Err(RoleError::MissingRole));
+            assert_eq!(vault.revoke_role(MANAGER_CONTRACT, accounts.bob), Err(RoleError::MissingRole));
+            assert_eq!(vault.renounce_role(MANAGER_CONTRACT), Ok(()));
+            assert!(!vault.has_role(MANAGER_CONTRACT, accounts.bob));
+            assert_eq!(vault.renounce_role(MANAGER_CONTRACT), Err(RoleError::MissingRole));
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 4);
+        }
+
+        #[ink::test]
+        pub fn ownership_transfer_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut vault = Vault::new(AccountId::from([0x0; 32]), AccountId::from([0x2; 32]));
+            assert_eq!(vault.transfer_ownership(accounts.bob), Ok(()));
+            assert_eq!(vault.owner(), Some(accounts.alice));
+            assert_eq!(vault.pending_owner(), Some(accounts.bob));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.charlie);
+            assert_eq!(vault.accept_ownership(), Err(Error::NotPendingOwner));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(vault.accept_ownership(), Ok(()));
+            assert_eq!(vault.owner(), Some(accounts.bob));
+            assert_eq!(vault.pending_owner(), None);
+            assert!(!vault.has_role(ADMIN, accounts.alice));
+            assert!(vault.has_role(ADMIN, accounts.bob));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(vault.transfer_ownership(accounts.alice), Err(Error::NotOwner));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(vault.renounce_ownership(), Ok(()));
+            assert_eq!(vault.owner(), None);
+            assert!(!vault.has_role(ADMIN, accounts.bob));
+            assert_eq!(vault.migrate(), Err(Error::NotOwner));
+        }
+
+        #[ink::test]
+        pub fn address_update_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let new_address = AccountId::from([0x9; 32]);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut vault = Vault::new(AccountId::from([0x0; 32]), AccountId::from([0x2; 32]));
+
+            assert_eq!(vault.set_distributor(new_address), Ok(()));
+            assert_eq!(vault.get_distributor(), new_address);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(vault.set_distributor(accounts.bob), Err(Error::MissingRole));
+            assert_eq!(vault.set_address_update_delay(0), Err(Error::MissingRole));
+
+            let emitted_events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            assert_eq!(emitted_events.len(), 1);
+        }
+
+        #[ink::test]
+        pub fn delayed_address_update_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let old_address = AccountId::from([0x8; 32]);
+            let new_address = AccountId::from([0x9; 32]);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut vault = Vault::new(AccountId::from([0x0; 32]), AccountId::from([0x2; 32]));
+            vault.set_distributor(old_address).unwrap();
+            vault.set_address_update_delay(1000).unwrap();
+
+            assert_eq!(vault.set_distributor(new_address), Ok(()));
+            assert_eq!(vault.get_distributor(), old_address);
+            assert_eq!(
+                vault.get_pending_address_update(AddressKind::Distributor),
+                Some(PendingAddressUpdate { address: new_address, eta: 1000 })
+            );
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(vault.apply_address_update(AddressKind::Distributor), Err(Error::UpdateNotReady));
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(1000);
+            assert_eq!(vault.apply_address_update(AddressKind::Distributor), Ok(()));
+            assert_eq!(vault.get_distributor(), new_address);
+            assert_eq!(vault.apply_address_update(AddressKind::Distributor), Err(Error::NotFound));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(vault.set_distributor(old_address), Ok(()));
+            assert_eq!(vault.cancel_address_update(AddressKind::Distributor), Ok(()));
+            assert_eq!(vault.get_pending_address_update(AddressKind::Distributor), None);
+            assert_eq!(vault.get_distributor(), new_address);
+        }
+
+        #[ink::test]
+        pub fn address_update_delay_change_waits_for_delay() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut vault = Vault::new(AccountId::from([0x0; 32]), AccountId::from([0x2; 32]));
+            assert_eq!(vault.set_address_update_delay(1000), Ok(()));
+            assert_eq!(vault.get_address_update_delay(), 1000);
+
+            assert_eq!(vault.set_address_update_delay(0), Ok(()));
+            assert_eq!(vault.get_address_update_delay(), 1000);
+            assert_eq!(
+                vault.get_pending_address_update_delay(),
+                Some(PendingDelayUpdate { delay: 0, eta: 1000 })
+            );
+            assert_eq!(vault.apply_address_update_delay(), Err(Error::UpdateNotReady));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(vault.set_address_update_delay(0), Err(Error::MissingRole));
+            assert_eq!(vault.cancel_address_update_delay(), Err(Error::MissingRole));
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(1000);
+            assert_eq!(vault.apply_address_update_delay(), Ok(()));
+            assert_eq!(vault.get_address_update_delay(), 0);
+            assert_eq!(vault.get_pending_address_update_delay(), None);
+            assert_eq!(vault.apply_address_update_delay(), Err(Error::NotFound));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(vault.set_address_update_delay(2000), Ok(()));
+            assert_eq!(vault.set_address_update_delay(0), Ok(()));
+            assert_eq!(vault.cancel_address_update_delay(), Ok(()));
+            assert_eq!(vault.get_pending_address_update_delay(), None);
+            assert_eq!(vault.get_address_update_delay(), 2000);
+        }
+
+        #[ink::test]
+        pub fn erc20_contract_update_needs_empty_ledgers() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let new_token = AccountId::from([0x9; 32]);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            let mut vault = Vault::new(AccountId::from([0x0; 32]), AccountId::from([0x2; 32]));
+            vault.set_address_update_delay(1000).unwrap();
+
+            assert_eq!(vault.set_erc20_contract(new_token), Ok(()));
+            assert_eq!(vault.record_lp_deposit(accounts.bob, 100), Ok(()));
+
+            ink::env::test::set_block_timestamp::<ink::env::DefaultEnvironment>(1000);
+            assert_eq!(vault.apply_address_update(AddressKind::Erc20Contract), Err(Error::LedgersNotEmpty));
+            assert_eq!(vault.set_erc20_contract(new_token), Err(Error::LedgersNotEmpty));
+            assert_eq!(vault.get_erc20_contract(), AccountId::from([0x0; 32]));
         }
     }
 }